@@ -0,0 +1,20 @@
+//! Decodes confluent framed protobuf messages to JSON with schemas from a schema registry.
+//!
+//! `Decoder` is the entry point for decoding single values or messages, `decode_kafka_stream`
+//! for async code consuming topics. Everything the API needs is exported here.
+
+/// Readers of the sources, sinks and the HTTP service the `proto2json` binary is made of. Public
+/// for the binary only, it changes with it.
+#[doc(hidden)]
+pub mod parse;
+
+pub use parse::confluent::Schema;
+pub use parse::decoder::{DecodedValue, Decoder};
+pub use parse::envelope::Envelope;
+pub use parse::error::{Error, ErrorClass, Result};
+pub use parse::kafka::{KafkaOptions, Start};
+pub use parse::metrics::Metrics;
+pub use parse::msg::{Msg, ParsedKey};
+pub use parse::redact::Redaction;
+pub use parse::source::{AsyncRegistry, SchemaSource};
+pub use parse::stream::{decode_kafka_stream, DecodeStream, Record, StreamOptions};
@@ -1,17 +1,42 @@
 extern crate core;
 
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::CommandArgs;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use clap::Parser;
+use itertools::Itertools;
 use tracing;
-use tracing::warn;
+use tracing::{info, warn};
 
-use crate::parse::error::*;
-use crate::parse::kafka;
-use crate::parse::proto2json::Proto2Json;
-
-mod parse;
+use proto2json::parse;
+use proto2json::parse::census::Census;
+use proto2json::parse::checkpoint::{Checkpoint, Position, Tracked};
+use proto2json::parse::confluent::{get_schemas_http, latest_schema};
+use proto2json::parse::dlq::{self, DeadLetter, Failures};
+use proto2json::parse::envelope::Envelope;
+use proto2json::parse::error::*;
+use proto2json::parse::file::{
+    open_inputs, read_files, LengthPrefix, LineEncoding, LinesParser, PrefixedParser,
+};
+use proto2json::parse::filter::Filter;
+use proto2json::parse::kafka;
+use proto2json::parse::kafka::ConsumerError;
+use proto2json::parse::metrics::{self, Metrics};
+use proto2json::parse::msg::Msg;
+use proto2json::parse::pipeline;
+use proto2json::parse::proto2json::Proto2Json;
+use proto2json::parse::redact::Redaction;
+use proto2json::parse::sink;
+use proto2json::parse::sink::Sink;
+use proto2json::parse::source;
+use proto2json::parse::source::SchemaSource;
 
 fn setup_tracing(level: tracing_subscriber::filter::LevelFilter) {
     let t = tracing_subscriber::fmt::time::Uptime::default();
@@ -37,6 +62,32 @@ struct Verbosity {
 pub enum Source {
     Kafka,
     Kcat,
+    /// kcat's JSON envelope, as printed with `kcat -J`
+    KcatJson,
+    /// kafka `.log` segment files, topic and partition are taken from the directory name
+    Segment,
+    /// every input file is one message, directories are expanded to the files they contain
+    Files,
+    /// one base64 encoded message per line
+    Base64,
+    /// one hex encoded message per line
+    Hex,
+    /// messages prefixed with their length as unsigned varint
+    Varint,
+    /// messages prefixed with their length as 4 byte big endian integer
+    Length,
+    /// kafka produce requests and fetch responses in pcap or pcapng captures
+    Pcap,
+    /// dead letters written with --on-error dlq, to retry the records that failed
+    DeadLetters,
+}
+
+#[derive(Debug, Clone, clap::ValueEnum)]
+pub enum Output {
+    /// decoded values only
+    Value,
+    /// decoded values with their kafka coordinates, key, headers and schema
+    Envelope,
 }
 
 #[derive(Debug, Clone, clap::Args)]
@@ -46,8 +97,31 @@ struct BrokerArgs {
     brokers: String,
 }
 
+#[derive(Debug, Clone, clap::ValueEnum)]
+pub enum Format {
+    Table,
+    Json,
+}
+
 #[derive(Debug, Clone, clap::Args)]
-struct DumpJsonArgs {
+struct ListTopicsArgs {
+    #[command(flatten)]
+    brokers_args: BrokerArgs,
+
+    /// url to confluent's schemas endpoint, used to show `<topic>-key` and `<topic>-value` subjects
+    #[arg(long, required = false)]
+    schemas_url: Option<String>,
+
+    /// number of last records per partition to read to find out which schema ids are in use
+    #[arg(long, default_value_t = 0)]
+    sample: usize,
+
+    #[arg(short, long, default_value = "table")]
+    format: Format,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct SchemaArgs {
     /// include paths for .proto files
     #[arg(short = 'I', long = "include")]
     include: Option<Vec<String>>,
@@ -58,17 +132,6 @@ struct DumpJsonArgs {
     #[arg(long, required = false)]
     schemas_url: Option<String>,
 
-    /// selects whether messages should be from kafka or kcat
-    #[arg(short = 'm', long, required = true)]
-    source: Source,
-
-    #[command(flatten)]
-    brokers_args: BrokerArgs,
-
-    /// topic csv list
-    #[arg(short, long, required = false)]
-    topics: String,
-
     /// path to store .proto files  (the protobuf library needs them to be on disk)
     ///
     /// if unset, current directory will be used
@@ -81,6 +144,353 @@ struct DumpJsonArgs {
     #[arg(long, required = false)]
     schemas_path: Option<String>,
 
+    /// directory of schemas snapshotted from a registry, every .json file below it holding
+    /// a schema or a list of them. Looked up after the url and path.
+    #[arg(long)]
+    schemas_dir: Option<PathBuf>,
+
+    /// FileDescriptorSet written by `protoc --include_imports --descriptor_set_out`, whose files
+    /// are given schema ids with --descriptor-set-id. Looked up after all other schemas.
+    #[arg(long)]
+    descriptor_set: Option<PathBuf>,
+
+    /// gives schema id ID to file FILE of the descriptor set, can be specified multiple times
+    #[arg(long, value_parser = parse_schema_file_mapping, requires = "descriptor_set")]
+    descriptor_set_id: Vec<(i32, String)>,
+
+    /// JSON field path to redact, such as `customer.email`, optionally followed by `=mask`,
+    /// `=hash` or `=drop` (mask by default). Can be given several times.
+    #[arg(long)]
+    redact: Vec<String>,
+
+    /// boolean custom field option marking fields to redact, such as `pii` for `(pii) = true`,
+    /// optionally followed by `=mask`, `=hash` or `=drop`. Can be given several times.
+    #[arg(long)]
+    redact_option: Vec<String>,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct KafkaArgs {
+    /// timeout of metadata and watermark requests, in milliseconds
+    #[arg(long, default_value_t = 30000)]
+    request_timeout_ms: u64,
+
+    /// consecutive empty polls after which the topics are assumed to be fully read
+    #[arg(long, default_value_t = 10)]
+    max_idle_polls: usize,
+
+    /// consecutive transient broker errors tolerated before giving up
+    #[arg(long, default_value_t = 10)]
+    max_error_retries: usize,
+
+    /// upper bound of the delay between retries, in milliseconds
+    #[arg(long, default_value_t = 30000)]
+    max_backoff_ms: u64,
+}
+
+impl From<&KafkaArgs> for kafka::KafkaOptions {
+    fn from(args: &KafkaArgs) -> Self {
+        kafka::KafkaOptions {
+            request_timeout: Duration::from_millis(args.request_timeout_ms),
+            max_idle_polls: args.max_idle_polls,
+            max_error_retries: args.max_error_retries,
+            max_backoff: Duration::from_millis(args.max_backoff_ms),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, clap::ValueEnum)]
+pub enum SinkKind {
+    Stdout,
+    /// files laid out as <output-dir>/<topic>/<partition>/<yyyy-mm-dd>/<first offset>.jsonl
+    Files,
+    /// records produced to kafka, to `<topic><sink-topic-suffix>` unless mapped with --sink-topic
+    Kafka,
+}
+
+#[derive(Debug, Clone, clap::ValueEnum)]
+pub enum OnError {
+    /// log the failure and go on
+    Skip,
+    /// stop at the failed record, --skip continues after it
+    Fail,
+    /// write the record and its error to the dead-letter sink and go on
+    Dlq,
+}
+
+#[derive(Debug, Clone, clap::ValueEnum)]
+pub enum FileCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct SinkArgs {
+    /// where decoded messages are written
+    #[arg(long, default_value = "stdout")]
+    sink: SinkKind,
+
+    /// directory written by the files sink, current directory if unset
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// compression of the files written by the files sink
+    #[arg(long, default_value = "none")]
+    compression: FileCompression,
+
+    /// start a new file once this many uncompressed bytes were written to one
+    #[arg(long)]
+    rotate_bytes: Option<u64>,
+
+    /// start a new file once one has been open for this many seconds
+    #[arg(long)]
+    rotate_secs: Option<u64>,
+
+    /// broker csv list of the kafka sink, --brokers if unset
+    #[arg(long)]
+    sink_brokers: Option<String>,
+
+    /// produces records of topic FROM to topic TO, can be specified multiple times
+    #[arg(long, value_parser = parse_topic_mapping)]
+    sink_topic: Vec<(String, String)>,
+
+    /// appended to the names of topics that aren't mapped with --sink-topic
+    #[arg(long, default_value = ".json")]
+    sink_topic_suffix: String,
+
+    /// transactional id of the kafka sink in group mode, random if unset
+    #[arg(long)]
+    transactional_id: Option<String>,
+
+    /// number of messages per transaction of the kafka sink in group mode, or between commits of
+    /// the group's offsets with the other sinks
+    #[arg(long, default_value_t = 1000)]
+    transaction_size: usize,
+
+    /// directory dead letters are written to with --on-error dlq, laid out like the files sink
+    #[arg(long, default_value = "dead-letters")]
+    dlq_dir: PathBuf,
+
+    /// produces dead letters to `<topic><dlq-topic-suffix>` on the kafka sink's brokers rather
+    /// than writing them to --dlq-dir
+    #[arg(long)]
+    dlq_topic_suffix: Option<String>,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct DumpJsonArgs {
+    #[command(flatten)]
+    schema_args: SchemaArgs,
+
+    /// selects where messages are read from
+    #[arg(short = 'm', long, required = true)]
+    source: Source,
+
+    /// broker csv list (required when in kafka mode)
+    #[arg(short, long, required = false)]
+    brokers: Option<String>,
+
+    #[command(flatten)]
+    kafka_args: KafkaArgs,
+
+    /// topic csv list (required when in kafka mode)
+    #[arg(short, long, required = false)]
+    topics: Option<String>,
+
+    /// input files or directories, stdin is read if unset and the source reads streams
+    #[arg(long, required = false)]
+    input: Vec<String>,
+
+    /// what is printed for every decoded message, one JSON object per line
+    #[arg(long, default_value = "value")]
+    output: Output,
+
+    /// only print messages matching this expression, such as `value.id == "X" and partition in [0, 1]`.
+    /// Paths start at the envelope fields, the decoded message is `value`.
+    #[arg(long = "where")]
+    condition: Option<String>,
+
+    /// comma separated paths printed instead of the whole message, each optionally named with `name=path`
+    #[arg(long)]
+    select: Option<String>,
+
+    /// what happens to records that fail to decode, skip for kafka sources and fail otherwise
+    /// if unset. Failures are summarized by error and schema id at the end.
+    #[arg(long)]
+    on_error: Option<OnError>,
+
+    /// topic given to messages whose source doesn't record one
+    #[arg(long, default_value = "")]
+    topic_name: String,
+
+    /// format string given to kcat with -f, fields of unknown length must be followed by a delimiter
+    #[arg(long, default_value = parse::kcat::DEFAULT_FORMAT)]
+    kcat_format: String,
+
+    /// broker ports whose traffic is decoded in pcap mode
+    #[arg(long, value_delimiter = ',', default_value = "9092")]
+    kafka_ports: Vec<u16>,
+
+    /// consumer group to read kafka topics with, from its committed offsets on and without end.
+    /// The kafka sink commits the group's offsets in its transactions, the other sinks once the
+    /// messages before them are flushed.
+    #[arg(long)]
+    group_id: Option<String>,
+
+    /// address to serve prometheus metrics on while decoding, such as `0.0.0.0:9464`, with
+    /// consumer lag in group mode
+    #[arg(long)]
+    metrics_listen: Option<SocketAddr>,
+
+    /// number of threads decoding kafka messages, more than one switches to the async consumer
+    #[arg(long, default_value_t = 1)]
+    workers: usize,
+
+    /// maximum number of messages waiting in each decoding queue
+    #[arg(long, default_value_t = 1024)]
+    queue_size: usize,
+
+    /// file recording the number of records processed and, for streamed sources, the byte
+    /// position in the input; not supported with `-m kafka`, see `--group-id`
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+
+    /// continue after the last record recorded in the checkpoint file
+    #[arg(long, requires = "checkpoint")]
+    resume: bool,
+
+    /// number of records between checkpoint updates
+    #[arg(long, default_value_t = 10000)]
+    checkpoint_interval: u64,
+
+    /// skip records numbered below this, records are numbered from 0 at the start of the input
+    #[arg(long, default_value_t = 0)]
+    skip: u64,
+
+    /// stop after processing this many records
+    #[arg(long)]
+    limit: Option<u64>,
+
+    #[command(flatten)]
+    sink_args: SinkArgs,
+
+    #[command(flatten)]
+    verbosity: Verbosity,
+}
+
+fn parse_partition_mapping(s: &str) -> std::result::Result<(i32, i32), String> {
+    let (from, to) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected FROM=TO, got {}", s))?;
+    let from = from.parse::<i32>().map_err(|e| e.to_string())?;
+    let to = to.parse::<i32>().map_err(|e| e.to_string())?;
+    Ok((from, to))
+}
+
+fn parse_schema_file_mapping(s: &str) -> std::result::Result<(i32, String), String> {
+    let (id, file) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected ID=FILE, got {}", s))?;
+    let id = id.parse::<i32>().map_err(|e| e.to_string())?;
+    Ok((id, file.to_string()))
+}
+
+fn parse_topic_mapping(s: &str) -> std::result::Result<(String, String), String> {
+    let (from, to) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected FROM=TO, got {}", s))?;
+    Ok((from.to_string(), to.to_string()))
+}
+
+fn parse_rate(s: &str) -> std::result::Result<f64, String> {
+    let rate = s.parse::<f64>().map_err(|e| e.to_string())?;
+    if rate > 0.0 {
+        Ok(rate)
+    } else {
+        Err(format!(
+            "expected a positive number of records per second, got {}",
+            s
+        ))
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct ProduceArgs {
+    #[command(flatten)]
+    schema_args: SchemaArgs,
+
+    #[command(flatten)]
+    brokers_args: BrokerArgs,
+
+    /// file with envelopes, one JSON object per line (stdin if unset)
+    #[arg(long, required = false)]
+    input: Option<String>,
+
+    /// topic to write to, defaults to the topic of each envelope
+    #[arg(long, required = false)]
+    topic: Option<String>,
+
+    /// write to the partition recorded in the envelope instead of letting the partitioner choose
+    #[arg(long)]
+    keep_partitions: bool,
+
+    /// moves records of partition FROM to partition TO, can be specified multiple times
+    #[arg(long, value_parser = parse_partition_mapping)]
+    partition_map: Vec<(i32, i32)>,
+
+    /// maximum number of records per second
+    #[arg(long, required = false, value_parser = parse_rate)]
+    rate: Option<f64>,
+
+    /// schema id to encode values with when the envelope has none
+    ///
+    /// values without a schema id are sent as plain JSON
+    #[arg(long, required = false)]
+    schema_id: Option<i32>,
+
+    /// message type to encode values as when the envelope has none, defaults to the first one in the schema
+    #[arg(long, required = false)]
+    message_type: Option<String>,
+
+    #[command(flatten)]
+    verbosity: Verbosity,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct CensusArgs {
+    #[command(flatten)]
+    dump_json_args: DumpJsonArgs,
+
+    /// stop after this many messages
+    #[arg(long, required = false)]
+    max_messages: Option<usize>,
+
+    #[arg(short, long, default_value = "table")]
+    format: Format,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+struct ServeArgs {
+    #[command(flatten)]
+    schema_args: SchemaArgs,
+
+    /// address the HTTP server listens on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+
+    /// broker csv list topics are tailed from, tails aren't served if unset
+    #[arg(short, long)]
+    brokers: Option<String>,
+
+    /// tails followed at the same time, further ones are answered with 503
+    #[arg(long, default_value_t = 16)]
+    max_tails: usize,
+
+    #[command(flatten)]
+    kafka_args: KafkaArgs,
+
     #[command(flatten)]
     verbosity: Verbosity,
 }
@@ -88,8 +498,14 @@ struct DumpJsonArgs {
 #[derive(Debug, Clone, clap::Parser)]
 enum Command {
     // #[clap(name = "list-topics")]
-    ListTopics(BrokerArgs),
+    ListTopics(ListTopicsArgs),
     ProtoToJson(DumpJsonArgs),
+    /// count messages per schema id, subject version, message type and decode error
+    Census(CensusArgs),
+    /// re-encode envelopes and write them to kafka
+    Produce(ProduceArgs),
+    /// decode and encode messages over HTTP
+    Serve(ServeArgs),
 }
 
 #[derive(Debug, Clone, clap::Parser)]
@@ -106,56 +522,648 @@ struct Arguments {
 
 fn main() {
     let args = Arguments2::parse();
-    match args.cmd {
-        Command::ListTopics(args) => kafka::list_topics(args.brokers.as_str())
-            .for_each(|t| t.1.into_iter().for_each(|id| println!("{} {}", t.0, id))),
-        Command::ProtoToJson(args) => dump_json(args).unwrap(),
+    let result = match args.cmd {
+        Command::ListTopics(args) => list_topics(args),
+        Command::ProtoToJson(args) => dump_json(args),
+        Command::Census(args) => census(args),
+        Command::Produce(args) => produce(args),
+        Command::Serve(args) => serve(args),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", report(&e));
+        std::process::exit(e.class().exit_code());
     }
 }
 
-fn dump_json(args: DumpJsonArgs) -> Result<()> {
-    let verbosity_level = match args.verbosity.verbose {
+#[derive(Debug, serde::Serialize)]
+struct SubjectInfo {
+    subject: String,
+    version: usize,
+    schema_id: i32,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct TopicListing {
+    #[serde(flatten)]
+    topic: kafka::TopicInfo,
+    count: i64,
+    subjects: Vec<SubjectInfo>,
+}
+
+fn list_topics(args: ListTopicsArgs) -> Result<()> {
+    let brokers = args.brokers_args.brokers.as_str();
+    let schemas = match args.schemas_url {
+        Some(url) => get_schemas_http(url)?,
+        None => vec![],
+    };
+    let mut topics = kafka::list_topics(brokers)?.collect::<Vec<_>>();
+    kafka::sample_schema_ids(brokers, &mut topics, args.sample)?;
+    let listing = topics
+        .into_iter()
+        .map(|topic| {
+            let subjects = ["key", "value"]
+                .iter()
+                .filter_map(|suffix| latest_schema(&schemas, &format!("{}-{}", topic.name, suffix)))
+                .map(|s| SubjectInfo {
+                    subject: s.subject.clone(),
+                    version: s.version,
+                    schema_id: s.id,
+                })
+                .collect();
+            TopicListing {
+                count: topic.count(),
+                topic,
+                subjects,
+            }
+        })
+        .collect::<Vec<_>>();
+    match args.format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(&listing)?),
+        Format::Table => {
+            println!(
+                "{:<40} {:>9} {:>12} {:>12} {:>12}  SAMPLED_SCHEMA_IDS",
+                "TOPIC", "PARTITION", "LOW", "HIGH", "COUNT"
+            );
+            for t in &listing {
+                for p in &t.topic.partitions {
+                    let ids = p
+                        .sampled_schema_ids
+                        .iter()
+                        .map(|(id, n)| format!("{}({})", id, n))
+                        .join(",");
+                    println!(
+                        "{:<40} {:>9} {:>12} {:>12} {:>12}  {}",
+                        t.topic.name, p.partition, p.lo, p.hi, p.count, ids
+                    );
+                }
+            }
+            if !schemas.is_empty() {
+                println!();
+                println!(
+                    "{:<40} {:<50} {:>8} {:>10}",
+                    "TOPIC", "SUBJECT", "VERSION", "SCHEMA_ID"
+                );
+                for t in &listing {
+                    for s in &t.subjects {
+                        println!(
+                            "{:<40} {:<50} {:>8} {:>10}",
+                            t.topic.name, s.subject, s.version, s.schema_id
+                        );
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn setup_verbosity(verbosity: &Verbosity) {
+    let verbosity_level = match verbosity.verbose {
         0 => tracing_subscriber::filter::LevelFilter::WARN,
         1 => tracing_subscriber::filter::LevelFilter::INFO,
         _ => tracing_subscriber::filter::LevelFilter::TRACE,
     };
     setup_tracing(verbosity_level);
-    let mut p = Proto2Json::load(
-        args.schemas_proto_path,
-        args.include,
-        args.schemas_url,
-        args.schemas_path,
-    )?;
-    match args.source {
+}
+
+/// Registry first, then files, then the descriptor set
+fn schema_source(args: &SchemaArgs) -> Result<Arc<dyn SchemaSource>> {
+    let mut sources: Vec<Box<dyn SchemaSource>> = vec![];
+    if let Some(url) = &args.schemas_url {
+        let registry = source::Registry::new(url);
+        if let Err(e) = registry.preload() {
+            warn!(
+                "could not get schemas via HTTP, looking them up one by one: {:?}",
+                e
+            );
+        }
+        sources.push(Box::new(registry));
+    }
+    if let Some(path) = &args.schemas_path {
+        let schemas = source::read_schema_file(path.as_ref())
+            .with_config_context(|| "loading --schemas-path".to_string())?;
+        sources.push(Box::new(schemas));
+    }
+    if let Some(dir) = &args.schemas_dir {
+        let schemas = source::read_snapshot_dir(dir)
+            .with_config_context(|| "loading --schemas-dir".to_string())?;
+        sources.push(Box::new(schemas));
+    }
+    if let Some(path) = &args.descriptor_set {
+        let descriptors = source::DescriptorSet::load(path, &args.descriptor_set_id)
+            .with_config_context(|| "loading --descriptor-set".to_string())?;
+        sources.push(Box::new(descriptors));
+    }
+    Ok(Arc::new(source::Chain::new(sources)))
+}
+
+fn load_proto2json(args: &SchemaArgs) -> Result<Proto2Json> {
+    let mut p = Proto2Json::new(
+        schema_source(args)?,
+        args.schemas_proto_path.clone(),
+        args.include.clone().unwrap_or_default(),
+    );
+    p.set_redaction(Redaction::new(&args.redact, &args.redact_option)?);
+    Ok(p)
+}
+
+fn kafka_brokers_and_topics(args: &DumpJsonArgs) -> Result<(&str, Vec<&str>)> {
+    let brokers = args
+        .brokers
+        .as_deref()
+        .ok_or(Error::NeedAtLeastOneBrokerHostname)?;
+    let topics = args
+        .topics
+        .as_deref()
+        .ok_or(Error::NeedAtLeastOneTopic)?
+        .split(',')
+        .collect::<Vec<_>>();
+    Ok((brokers, topics))
+}
+
+/// Inputs of streamed sources, read from the checkpoint's position on
+fn tracked_inputs(
+    args: &DumpJsonArgs,
+    start: &Checkpoint,
+    position: &mut Option<Position>,
+) -> Result<Tracked<Box<dyn BufRead>>> {
+    let offset = start.position.unwrap_or(0);
+    let (r, p) = Tracked::new(open_inputs(&args.input, offset)?, offset);
+    *position = Some(p);
+    Ok(r)
+}
+
+type Messages = Box<dyn Iterator<Item = Result<Msg>>>;
+
+/// Messages following the `start` checkpoint, and the position in the input for streamed sources
+fn read_messages(args: &DumpJsonArgs, start: &Checkpoint) -> Result<(Messages, Option<Position>)> {
+    let topic = args.topic_name.as_str();
+    let mut position = None;
+    let it: Box<dyn Iterator<Item = Result<Msg>>> = match args.source {
         Source::Kafka => {
-            let brokers = args.brokers_args.brokers;
-            let topics = args.topics;
-            let rd = kafka::read_kafka(
-                brokers.as_str(),
-                &topics.split(",").into_iter().collect::<Vec<_>>(),
+            let (brokers, topics) = kafka_brokers_and_topics(args)?;
+            Box::new(
+                kafka::read_kafka(brokers, &topics, &(&args.kafka_args).into())
+                    .with_context(|| format!("consuming {} from {}", topics.join(","), brokers))?,
+            )
+        }
+        Source::Kcat => Box::new(parse::kcat::Parser::with_format(
+            tracked_inputs(args, start, &mut position)?,
+            parse::kcat::Format::new(&args.kcat_format)?,
+        )),
+        Source::KcatJson => Box::new(parse::kcat_json::Parser::new(tracked_inputs(
+            args,
+            start,
+            &mut position,
+        )?)),
+        Source::Segment => Box::new(parse::segment::read_segments(&args.input)?),
+        Source::Files => Box::new(read_files(&args.input, topic)?),
+        Source::Base64 => Box::new(LinesParser::new(
+            tracked_inputs(args, start, &mut position)?,
+            LineEncoding::Base64,
+            topic,
+        )),
+        Source::Hex => Box::new(LinesParser::new(
+            tracked_inputs(args, start, &mut position)?,
+            LineEncoding::Hex,
+            topic,
+        )),
+        Source::Varint => Box::new(PrefixedParser::new(
+            tracked_inputs(args, start, &mut position)?,
+            LengthPrefix::Varint,
+            topic,
+        )),
+        Source::Length => Box::new(PrefixedParser::new(
+            tracked_inputs(args, start, &mut position)?,
+            LengthPrefix::U32,
+            topic,
+        )),
+        Source::Pcap => parse::pcap::read_captures(&args.input, &args.kafka_ports)?,
+        Source::DeadLetters => Box::new(dlq::Parser::new(tracked_inputs(
+            args,
+            start,
+            &mut position,
+        )?)),
+    };
+    // sources that can't seek are read up to the checkpoint again
+    let it = match (&position, start.position) {
+        (Some(_), Some(_)) => it,
+        _ => Box::new(it.skip(start.records as usize)),
+    };
+    if topic.is_empty() {
+        return Ok((it, position));
+    }
+    let topic = topic.to_string();
+    let it = Box::new(it.map(move |msg| {
+        msg.map(|mut msg| {
+            if msg.topic.is_empty() {
+                msg.topic = topic.clone();
+            }
+            msg
+        })
+    }));
+    Ok((it, position))
+}
+
+/// Numbers records from `first` and applies --skip and --limit. Idle polls aren't records,
+/// they carry the number of the record that follows.
+fn select_records<T>(
+    it: impl Iterator<Item = Result<T>>,
+    first: u64,
+    args: &DumpJsonArgs,
+) -> impl Iterator<Item = (u64, Result<T>)> {
+    let skip = args.skip;
+    let end = args
+        .limit
+        .map_or(u64::MAX, |l| first.max(skip).saturating_add(l));
+    it.scan(first, |next, item| {
+        let n = *next;
+        if !matches!(item, Err(Error::Consumer(ConsumerError::Idle))) {
+            *next += 1;
+        }
+        Some((n, item))
+    })
+    .skip_while(move |(n, _)| *n < skip)
+    .take_while(move |(n, _)| *n < end)
+}
+
+fn census(args: CensusArgs) -> Result<()> {
+    setup_verbosity(&args.dump_json_args.verbosity);
+    let mut p = load_proto2json(&args.dump_json_args.schema_args)?;
+    let mut census = Census::default();
+    let max_messages = args.max_messages.unwrap_or(usize::MAX);
+    let mut result = Ok(());
+    let (messages, _) = read_messages(&args.dump_json_args, &Checkpoint::default())?;
+    let messages = select_records(messages, 0, &args.dump_json_args).map(|(_, msg)| msg);
+    for msg in messages.take(max_messages) {
+        match msg {
+            Ok(msg) => census.add(&mut p, &msg),
+            Err(Error::Consumer(e)) if !e.is_fatal() => warn!("{:?}", e),
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    // a partial census is still worth printing
+    match args.format {
+        Format::Table => census.print_table(),
+        Format::Json => println!("{}", census.to_json()?),
+    }
+    result
+}
+
+/// Output lines of every message, envelopes are only built when they are printed or filtered on
+fn decoder(args: &DumpJsonArgs) -> Result<pipeline::Decode> {
+    let filter = Filter::new(args.condition.as_deref(), args.select.as_deref())?;
+    let envelope = matches!(args.output, Output::Envelope);
+    if filter.is_empty() {
+        return Ok(match envelope {
+            false => Arc::new(Proto2Json::proto2json),
+            true => Arc::new(Proto2Json::proto2envelopes),
+        });
+    }
+    Ok(Arc::new(move |p: &mut Proto2Json, msg: &Msg| {
+        let mut out = vec![];
+        for e in p.envelopes(msg)? {
+            if let Some(record) = filter.apply(e.into_json()?, envelope) {
+                out.push(record.to_string());
+            }
+        }
+        Ok(out)
+    }))
+}
+
+fn dump_json(args: DumpJsonArgs) -> Result<()> {
+    setup_verbosity(&args.verbosity);
+    let mut p = load_proto2json(&args.schema_args)?;
+    let metrics = match args.metrics_listen {
+        Some(addr) => {
+            let metrics = Arc::new(Metrics::default());
+            p.set_metrics(metrics.clone());
+            metrics::serve_in_background(metrics.clone(), addr)?;
+            Some(metrics)
+        }
+        None => None,
+    };
+    let mut start = Checkpoint {
+        source: format!("{:?}", args.source),
+        inputs: args.input.clone(),
+        ..Default::default()
+    };
+    if args.checkpoint.is_some() && matches!(args.source, Source::Kafka) {
+        return Err(Error::CheckpointUnsupported);
+    }
+    if let (true, Some(path)) = (args.resume, &args.checkpoint) {
+        if let Some(saved) = Checkpoint::load(path)? {
+            if saved.source != start.source || saved.inputs != start.inputs {
+                return Err(Error::CheckpointMismatch);
+            }
+            info!(
+                records = saved.records,
+                position = saved.position,
+                "resuming"
             );
-            for msg in rd {
-                match p.proto2json(&msg) {
-                    Ok(out) => out.into_iter().for_each(|s| println!("{}", s)),
-                    Err(e) => {
+            start = saved;
+        }
+    }
+    let decode = decoder(&args)?;
+    let mut position = None;
+    let mut group = None;
+    let group_mode = matches!(args.source, Source::Kafka) && args.group_id.is_some();
+    if group_mode && args.workers > 1 {
+        warn!("--workers is ignored with --group-id, messages are decoded in order");
+    }
+    let decoded: Box<dyn Iterator<Item = (u64, Result<pipeline::Decoded>)>> =
+        if matches!(args.source, Source::Kafka) && args.workers > 1 && !group_mode {
+            let (brokers, topics) = kafka_brokers_and_topics(&args)?;
+            let pipeline = pipeline::decode_kafka(
+                &p,
+                decode,
+                brokers,
+                &topics,
+                &(&args.kafka_args).into(),
+                args.workers,
+                args.queue_size,
+            )?;
+            Box::new(select_records(pipeline, 0, &args))
+        } else {
+            let messages: Box<dyn Iterator<Item = Result<Msg>>> = match &args.group_id {
+                Some(group_id) if group_mode => {
+                    let (brokers, topics) = kafka_brokers_and_topics(&args)?;
+                    let (it, consumer) = kafka::read_kafka_group(
+                        brokers,
+                        &topics,
+                        group_id,
+                        &(&args.kafka_args).into(),
+                        metrics.clone(),
+                    )
+                    .with_context(|| format!("joining group {} on {}", group_id, brokers))?;
+                    group = Some(consumer);
+                    Box::new(it)
+                }
+                _ => {
+                    let (messages, p2) = read_messages(&args, &start)?;
+                    position = p2;
+                    messages
+                }
+            };
+            // skipped records are not decoded
+            Box::new(
+                select_records(messages, start.records, &args).map(move |(n, msg)| {
+                    let decoded = msg.map(|msg| {
+                        let out = decode(&mut p, &msg);
+                        (msg, out)
+                    });
+                    (n, decoded)
+                }),
+            )
+        };
+    let mut progress = start;
+    let on_error = match (&args.on_error, &args.source) {
+        (Some(on_error), _) => on_error.clone(),
+        (None, Source::Kafka) => OnError::Skip,
+        (None, _) => OnError::Fail,
+    };
+    let dead_letters = match on_error {
+        OnError::Dlq => Some(open_dead_letters(&args)?),
+        _ => None,
+    };
+    let mut sink = sink::WithDeadLetters::new(open_sink(&args, group)?, dead_letters);
+    let mut failures = Failures::default();
+    let save = |sink: &mut dyn Sink, progress: &Checkpoint| -> Result<()> {
+        match &args.checkpoint {
+            Some(path) => {
+                sink.flush()?;
+                progress.save(path)
+            }
+            None => Ok(()),
+        }
+    };
+    // completes what was written before an error, so that a resumed run continues after it
+    let finish = |sink: &mut dyn Sink, progress: &Checkpoint, failures: &Failures| -> Result<()> {
+        sink.close()?;
+        failures.print_summary();
+        save(sink, progress)
+    };
+    for (n, decoded) in decoded {
+        let (msg, out) = match decoded {
+            Ok(decoded) => decoded,
+            Err(Error::Consumer(ConsumerError::Idle)) => {
+                sink.flush()?;
+                continue;
+            }
+            Err(Error::Consumer(e)) if !e.is_fatal() => {
+                warn!("{:?}", e);
+                continue;
+            }
+            Err(e) => {
+                finish(&mut sink, &progress, &failures)?;
+                return Err(e);
+            }
+        };
+        if let Some(metrics) = &metrics {
+            metrics.consumed(&msg);
+            match &out {
+                Ok(_) => metrics.decoded(&msg),
+                Err(e) => metrics.failed(&msg, e),
+            }
+        }
+        match out {
+            Ok(out) => {
+                let size = metrics::output_size(&out);
+                sink.write(&msg, out)?;
+                if let Some(metrics) = &metrics {
+                    metrics.written(size);
+                }
+            }
+            Err(e) => {
+                failures.add(&msg, &e);
+                match on_error {
+                    OnError::Skip => warn!(
+                        topic = msg.topic,
+                        partition = msg.partition,
+                        offset = msg.offset,
+                        "failed to parse message: {}",
+                        report(&e)
+                    ),
+                    OnError::Dlq => {
+                        let letter = serde_json::to_string(&DeadLetter::new(&msg, &e))?;
+                        sink.write_dead_letter(&msg, letter)?;
+                    }
+                    OnError::Fail => {
+                        finish(&mut sink, &progress, &failures)?;
                         warn!(
-                            topic = msg.topic,
-                            partition = msg.partition,
-                            offset = msg.offset,
-                            "failed to parse message: {:?}",
-                            e
-                        )
+                            record = n,
+                            "failed to parse message, --skip {} continues after it",
+                            n + 1
+                        );
+                        return Err(e).with_context(|| match msg.topic.as_str() {
+                            "" => format!("decoding record {}", n),
+                            topic => format!(
+                                "decoding record {} of {}, partition {} offset {}",
+                                n, topic, msg.partition, msg.offset
+                            ),
+                        });
                     }
                 }
             }
         }
-        Source::Kcat => {
-            for msg in parse::kcat::Parser::new(std::io::stdin()) {
-                p.proto2json(&msg)?
-                    .into_iter()
-                    .for_each(|s| println!("{}", s));
+        progress.records = n + 1;
+        progress.position = position.as_ref().map(Position::get);
+        if progress
+            .records
+            .is_multiple_of(args.checkpoint_interval.max(1))
+        {
+            save(&mut sink, &progress)?;
+        }
+    }
+    finish(&mut sink, &progress, &failures)
+}
+
+/// Dead letters are JSON lines written with the failed record's coordinates, key and headers
+fn open_dead_letters(args: &DumpJsonArgs) -> Result<Box<dyn Sink>> {
+    let sink_args = &args.sink_args;
+    Ok(match &sink_args.dlq_topic_suffix {
+        Some(suffix) => {
+            let brokers = sink_args
+                .sink_brokers
+                .as_deref()
+                .or(args.brokers.as_deref())
+                .ok_or(Error::NeedAtLeastOneBrokerHostname)?;
+            Box::new(sink::Kafka::new(
+                kafka::Producer::new(brokers)?,
+                sink::TopicMap::new(&[], suffix),
+            ))
+        }
+        None => Box::new(sink::Files::new(
+            &sink_args.dlq_dir,
+            sink::Compression::None,
+            sink::Rotation::default(),
+        )),
+    })
+}
+
+/// The kafka sink commits the offsets of `group`, if any, in its transactions, the other sinks
+/// after flushing
+fn open_sink(args: &DumpJsonArgs, group: Option<kafka::GroupConsumer>) -> Result<Box<dyn Sink>> {
+    let sink_args = &args.sink_args;
+    let sink: Box<dyn Sink> = match (&sink_args.sink, &sink_args.output_dir) {
+        (SinkKind::Stdout, _) => Box::new(sink::Stdout),
+        (SinkKind::Files, dir) => {
+            let rotation = sink::Rotation {
+                max_bytes: sink_args.rotate_bytes,
+                max_age: sink_args.rotate_secs.map(Duration::from_secs),
+            };
+            let dir = dir.clone().unwrap_or_else(|| PathBuf::from("."));
+            let compression = match sink_args.compression {
+                FileCompression::None => sink::Compression::None,
+                FileCompression::Gzip => sink::Compression::Gzip,
+                FileCompression::Zstd => sink::Compression::Zstd,
+            };
+            Box::new(sink::Files::new(&dir, compression, rotation))
+        }
+        (SinkKind::Kafka, _) => {
+            let brokers = sink_args
+                .sink_brokers
+                .as_deref()
+                .or(args.brokers.as_deref())
+                .ok_or(Error::NeedAtLeastOneBrokerHostname)?;
+            let topics = sink::TopicMap::new(&sink_args.sink_topic, &sink_args.sink_topic_suffix);
+            match group {
+                Some(group) => {
+                    let transactional_id = match &sink_args.transactional_id {
+                        Some(id) => id.clone(),
+                        None => format!("proto2json-{}", uuid::Uuid::new_v4()),
+                    };
+                    let producer = kafka::Producer::transactional(
+                        brokers,
+                        &transactional_id,
+                        &(&args.kafka_args).into(),
+                    )?;
+                    return Ok(Box::new(sink::Kafka::transactional(
+                        producer,
+                        topics,
+                        group,
+                        sink_args.transaction_size,
+                    )));
+                }
+                None => Box::new(sink::Kafka::new(kafka::Producer::new(brokers)?, topics)),
             }
         }
     };
+    Ok(match group {
+        Some(group) => Box::new(sink::GroupCommit::new(
+            sink,
+            group,
+            sink_args.transaction_size,
+        )),
+        None => sink,
+    })
+}
+
+fn serve(args: ServeArgs) -> Result<()> {
+    setup_verbosity(&args.verbosity);
+    let mut p = load_proto2json(&args.schema_args)?;
+    p.set_metrics(Arc::new(Metrics::default()));
+    let tail = args.brokers.map(|brokers| parse::http::Tail {
+        brokers,
+        options: (&args.kafka_args).into(),
+        max_tails: args.max_tails,
+    });
+    parse::http::serve(p, args.listen, tail)
+}
+
+fn produce(args: ProduceArgs) -> Result<()> {
+    setup_verbosity(&args.verbosity);
+    let mut p = load_proto2json(&args.schema_args)?;
+    let producer = kafka::Producer::new(args.brokers_args.brokers.as_str())?;
+    let input: Box<dyn BufRead> = match &args.input {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(BufReader::new(std::io::stdin())),
+    };
+    let partition_map = args
+        .partition_map
+        .iter()
+        .copied()
+        .collect::<HashMap<_, _>>();
+    let start = Instant::now();
+    let mut sent = 0usize;
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let envelope: Envelope = serde_json::from_str(&line)?;
+        let payload = match envelope.schema_id.or(args.schema_id) {
+            Some(schema_id) => p.encode(
+                schema_id,
+                envelope
+                    .message_type
+                    .as_deref()
+                    .or(args.message_type.as_deref()),
+                &envelope.value,
+            )?,
+            None => envelope.value.to_string().into_bytes(),
+        };
+        let partition = i32::try_from(envelope.partition)?;
+        let partition = match partition_map.get(&partition) {
+            Some(to) => Some(*to),
+            None if args.keep_partitions => Some(partition),
+            None => None,
+        };
+        let topic = args.topic.as_deref().unwrap_or(envelope.topic.as_str());
+        producer.send(topic, partition, &envelope.key, &envelope.headers, &payload)?;
+        sent += 1;
+        if let Some(rate) = args.rate {
+            let due = start + Duration::from_secs_f64(sent as f64 / rate);
+            let now = Instant::now();
+            if due > now {
+                std::thread::sleep(due - now);
+            }
+        }
+    }
+    producer.flush()?;
+    info!(sent = sent, "produced");
     Ok(())
 }
@@ -0,0 +1,249 @@
+use crate::parse::confluent::peek_schema_id;
+use crate::parse::error::*;
+use crate::parse::msg::Msg;
+use crate::parse::proto2json::{Decoded, Proto2Json};
+use crate::parse::protobuf::ProtobufError;
+use itertools::Itertools;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+pub struct CensusKey {
+    pub topic: String,
+    pub schema_id: Option<i32>,
+    pub subject: Option<String>,
+    pub version: Option<usize>,
+    /// the message type a record decoded into, or the types it fits equally well joined by `|`
+    pub message_type: Option<String>,
+    /// the record fits more than one message type equally well
+    pub ambiguous: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Seen {
+    pub partition: i64,
+    pub offset: i64,
+    pub ts: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CensusRow {
+    #[serde(flatten)]
+    pub key: CensusKey,
+    pub count: usize,
+    pub first: Seen,
+    pub last: Seen,
+}
+
+#[derive(Default)]
+pub struct Census {
+    rows: BTreeMap<CensusKey, CensusRow>,
+}
+
+impl Census {
+    /// Decodes `msg` and counts it under the message type it decoded into, under all the types
+    /// it fits equally well if it is ambiguous, or under the error kind if decoding failed
+    pub fn add(&mut self, p: &mut Proto2Json, msg: &Msg) {
+        let schema_id = peek_schema_id(&msg.msg);
+        let (subject, version) = schema_id
+            .and_then(|id| p.schema(id))
+            .map(|s| (Some(s.subject.clone()), Some(s.version)))
+            .unwrap_or((None, None));
+        let key = CensusKey {
+            topic: msg.topic.clone(),
+            schema_id,
+            subject,
+            version,
+            message_type: None,
+            ambiguous: false,
+            error: None,
+        };
+        match p.decode(msg) {
+            Ok(Decoded::Json(_)) => self.count(
+                CensusKey {
+                    message_type: Some("json".to_string()),
+                    ..key
+                },
+                msg,
+            ),
+            Ok(Decoded::Protobuf { messages, .. }) => {
+                let types = messages
+                    .iter()
+                    .map(|m| m.descriptor_dyn().full_name().to_string())
+                    .join("|");
+                self.count(
+                    CensusKey {
+                        message_type: Some(types),
+                        ..key
+                    },
+                    msg,
+                )
+            }
+            // counted once under the types it fits equally well, not as a failure
+            Err(e) => match e.root() {
+                Error::Protobuf(ProtobufError::AmbiguousMessageType(_, names)) => self.count(
+                    CensusKey {
+                        message_type: Some(names.iter().sorted().join("|")),
+                        ambiguous: true,
+                        ..key
+                    },
+                    msg,
+                ),
+                _ => self.count(
+                    CensusKey {
+                        error: Some(e.kind().to_string()),
+                        ..key
+                    },
+                    msg,
+                ),
+            },
+        }
+    }
+
+    fn count(&mut self, key: CensusKey, msg: &Msg) {
+        let seen = Seen {
+            partition: msg.partition,
+            offset: msg.offset,
+            ts: msg.ts,
+        };
+        let row = self.rows.entry(key.clone()).or_insert_with(|| CensusRow {
+            key,
+            count: 0,
+            first: seen.clone(),
+            last: seen.clone(),
+        });
+        row.count += 1;
+        if seen.ts < row.first.ts {
+            row.first = seen.clone();
+        }
+        if seen.ts >= row.last.ts {
+            row.last = seen;
+        }
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &CensusRow> {
+        self.rows.values()
+    }
+
+    pub fn print_table(&self) {
+        println!(
+            "{:<30} {:>9} {:<30} {:>7} {:<40} {:<9} {:<30} {:>10} {:>20} {:>14} {:>20} {:>14}",
+            "TOPIC",
+            "SCHEMA_ID",
+            "SUBJECT",
+            "VERSION",
+            "MESSAGE_TYPE",
+            "AMBIGUOUS",
+            "ERROR",
+            "COUNT",
+            "FIRST",
+            "FIRST_TS",
+            "LAST",
+            "LAST_TS"
+        );
+        let opt = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+        for r in self.rows() {
+            let k = &r.key;
+            println!(
+                "{:<30} {:>9} {:<30} {:>7} {:<40} {:<9} {:<30} {:>10} {:>20} {:>14} {:>20} {:>14}",
+                k.topic,
+                opt(k.schema_id.map(|v| v.to_string())),
+                opt(k.subject.clone()),
+                opt(k.version.map(|v| v.to_string())),
+                opt(k.message_type.clone()),
+                if k.ambiguous { "yes" } else { "-" },
+                opt(k.error.clone()),
+                r.count,
+                format!("{}:{}", r.first.partition, r.first.offset),
+                r.first.ts,
+                format!("{}:{}", r.last.partition, r.last.offset),
+                r.last.ts
+            );
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(
+            &self.rows().collect::<Vec<_>>(),
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::census::Census;
+    use crate::parse::confluent::{write_confluent, Schema};
+    use crate::parse::msg::{Msg, ParsedKey};
+    use crate::parse::proto2json::Proto2Json;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_add() {
+        let dir = std::env::temp_dir().join(format!("census-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let schemas = vec![Schema {
+            id: 7,
+            version: 2,
+            schema_type: "PROTOBUF".to_string(),
+            subject: "orders-value".to_string(),
+            references: None,
+            schema: "syntax = \"proto3\";\npackage acme;\n\
+                     message Order { string id = 1; }\n\
+                     message Ping { string id = 1; int64 sent = 4; }\n\
+                     message Pong { string id = 1; int64 sent = 4; }\n"
+                .to_string(),
+        }];
+        let mut p = Proto2Json::new(Arc::new(schemas), Some(dir.display().to_string()), vec![]);
+        let msg = |offset: i64, value: Vec<u8>| Msg {
+            topic: "orders".to_string(),
+            partition: 0,
+            offset,
+            ts: offset * 1000,
+            key: ParsedKey::None,
+            key_len: 0,
+            msg_len: value.len(),
+            msg: value,
+            headers: vec![],
+        };
+        let mut census = Census::default();
+        census.add(
+            &mut p,
+            &msg(0, write_confluent(7, &[0], &[0x0a, 1, b'x']).unwrap()),
+        );
+        census.add(&mut p, &msg(1, write_confluent(7, &[0], &[]).unwrap()));
+        // fits Ping and Pong as well as each other
+        let sent = write_confluent(7, &[0], &[0x0a, 1, b'x', 0x20, 1]).unwrap();
+        census.add(&mut p, &msg(2, sent.clone()));
+        census.add(&mut p, &msg(3, sent));
+        census.add(&mut p, &msg(4, b"[1]".to_vec()));
+        census.add(&mut p, &msg(5, write_confluent(8, &[0], &[]).unwrap()));
+
+        let rows = census
+            .rows()
+            .map(|r| {
+                let k = &r.key;
+                (
+                    k.message_type.clone(),
+                    k.ambiguous,
+                    k.error.clone(),
+                    r.count,
+                )
+            })
+            .collect::<Vec<_>>();
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(
+            rows,
+            vec![
+                (some("json"), false, None, 1),
+                (some("acme.Order"), false, None, 2),
+                (some("acme.Ping|acme.Pong"), true, None, 2),
+                (None, false, some("SchemaNotFound"), 1),
+            ]
+        );
+        let order = census.rows().nth(1).unwrap();
+        assert_eq!(order.key.subject.as_deref(), Some("orders-value"));
+        assert_eq!(order.key.version, Some(2));
+        assert_eq!((order.first.offset, order.last.offset), (0, 1));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
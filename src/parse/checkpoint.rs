@@ -0,0 +1,124 @@
+use crate::parse::error::*;
+use std::cell::Cell;
+use std::io::{BufRead, Read};
+use std::path::Path;
+use std::rc::Rc;
+use tracing::debug;
+
+/// How far a run got through its input. `position` is the byte offset following the last
+/// processed record, it is only known for sources read as a stream.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Checkpoint {
+    pub source: String,
+    pub inputs: Vec<String>,
+    pub records: u64,
+    pub position: Option<u64>,
+}
+
+impl Checkpoint {
+    /// None if there is no checkpoint yet
+    pub fn load(path: &Path) -> Result<Option<Checkpoint>> {
+        match std::fs::read(path) {
+            Ok(data) => {
+                Ok(Some(serde_json::from_slice(&data).with_context(|| {
+                    format!("reading checkpoint {}", path.display())
+                })?))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("reading checkpoint {}", path.display())),
+        }
+    }
+
+    /// Replaces the checkpoint file, a crash while saving leaves the previous one intact
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self)?)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .with_context(|| format!("saving checkpoint {}", path.display()))?;
+        debug!(
+            records = self.records,
+            position = self.position,
+            "checkpoint"
+        );
+        Ok(())
+    }
+}
+
+/// Number of bytes consumed from a `Tracked` reader, shared with whoever reads from it
+#[derive(Debug, Clone, Default)]
+pub struct Position(Rc<Cell<u64>>);
+
+impl Position {
+    pub fn get(&self) -> u64 {
+        self.0.get()
+    }
+
+    fn add(&self, n: usize) {
+        self.0.set(self.0.get() + n as u64)
+    }
+}
+
+/// Counts the bytes parsers consume, as opposed to the bytes buffered ahead of them
+pub struct Tracked<R> {
+    r: R,
+    position: Position,
+}
+
+impl<R: BufRead> Tracked<R> {
+    pub fn new(r: R, start: u64) -> (Self, Position) {
+        let position = Position::default();
+        position.0.set(start);
+        (
+            Tracked {
+                r,
+                position: position.clone(),
+            },
+            position,
+        )
+    }
+}
+
+impl<R: BufRead> Read for Tracked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.r.read(buf)?;
+        self.position.add(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Tracked<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.r.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.r.consume(amt);
+        self.position.add(amt);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::checkpoint::Tracked;
+    use crate::parse::file::{LineEncoding, LinesParser};
+    use std::io::BufReader;
+
+    #[test]
+    fn test_tracked() {
+        let input = "00\n\n0102\n03\n";
+        let (r, position) = Tracked::new(BufReader::with_capacity(4, input.as_bytes()), 0);
+        let mut parser = LinesParser::new(r, LineEncoding::Hex, "t");
+        parser.next().unwrap().unwrap();
+        assert_eq!(position.get(), 3);
+        parser.next().unwrap().unwrap();
+        assert_eq!(position.get(), 9);
+
+        let (r, position) = Tracked::new(&input.as_bytes()[9..], 9);
+        let msgs = LinesParser::new(r, LineEncoding::Hex, "t")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(msgs[0].msg, &[3]);
+        assert_eq!(position.get(), input.len() as u64);
+    }
+}
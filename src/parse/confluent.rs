@@ -6,6 +6,8 @@ use tracing::trace;
 
 pub struct ConfluentMsg {
     pub schema_id: i32,
+    /// position of the message type in the schema, empty for its first type
+    pub message_indexes: Vec<i32>,
     pub value: Vec<u8>,
 }
 
@@ -14,17 +16,47 @@ pub fn parse_confluent(value: &[u8]) -> Result<ConfluentMsg> {
 
     let magic_byte = rdr.read_u8()?;
     trace!("magic_byte={:?}", magic_byte);
-    assert_eq!(magic_byte, 0);
+    if magic_byte != 0 {
+        return Err(Error::InvalidMagicByte(magic_byte));
+    }
 
     let schema_id = rdr.read_i32::<BigEndian>()?;
     trace!("schema_id={:?}", schema_id);
 
-    // confluent java parser throws these away
-    let message_idx = parse_message_indexes(&mut rdr)?;
-    trace!("message_idx={:?}", message_idx);
+    let message_indexes = parse_message_indexes(&mut rdr)?;
+    trace!("message_indexes={:?}", message_indexes);
 
     let value = rdr.clone().into_inner()[rdr.position() as usize..].to_vec();
-    Ok(ConfluentMsg { schema_id, value })
+    Ok(ConfluentMsg {
+        schema_id,
+        message_indexes,
+        value,
+    })
+}
+
+/// Returns schema id of a confluent-framed value without parsing the rest of it
+pub fn peek_schema_id(value: &[u8]) -> Option<i32> {
+    match value {
+        [0, a, b, c, d, ..] => Some(i32::from_be_bytes([*a, *b, *c, *d])),
+        _ => None,
+    }
+}
+
+/// Frames an encoded protobuf value the way confluent's serializer does
+pub fn write_confluent(schema_id: i32, message_indexes: &[i32], value: &[u8]) -> Result<Vec<u8>> {
+    let mut out = vec![0u8];
+    out.extend_from_slice(&schema_id.to_be_bytes());
+    // the common case of the first message type is written as a single zero
+    if message_indexes == [0] {
+        out.extend(write_varint(0)?);
+    } else {
+        out.extend(write_varint(message_indexes.len().try_into()?)?);
+        for idx in message_indexes {
+            out.extend(write_varint(*idx)?);
+        }
+    }
+    out.extend_from_slice(value);
+    Ok(out)
 }
 
 fn parse_message_indexes(rdr: &mut Cursor<&[u8]>) -> Result<Vec<i32>> {
@@ -44,7 +76,7 @@ fn unsigned_right_shift_i32(x: i32, n: usize) -> Result<i32> {
 #[cfg(test)]
 mod test {
     use std::io::Cursor;
-    use crate::parse::confluent::{read_unsigned_varint, read_varint, write_unsigned_varint, write_varint};
+    use crate::parse::confluent::{parse_confluent, read_unsigned_varint, read_varint, write_confluent, write_unsigned_varint, write_varint};
 
     // https://github.com/a0x8o/kafka/blob/54eff6af115ee647f60129f2ce6a044cb17215d0/clients/src/test/java/org/apache/kafka/common/utils/ByteUtilsTest.java#L142
 
@@ -66,6 +98,20 @@ mod test {
         assert_eq!(value, read_val);
     }
 
+    #[test]
+    fn test_confluent_roundtrip() {
+        let buf = write_confluent(42, &[0], b"abc").unwrap();
+        assert_eq!(buf, &[0, 0, 0, 0, 42, 0, b'a', b'b', b'c']);
+        let buf = write_confluent(7, &[1, 2], b"abc").unwrap();
+        let msg = parse_confluent(&buf).unwrap();
+        assert_eq!(msg.schema_id, 7);
+        assert_eq!(msg.message_indexes, vec![1, 2]);
+        assert_eq!(msg.value, b"abc");
+        let msg = parse_confluent(&[0, 0, 0, 0, 42, 0, b'a']).unwrap();
+        assert!(msg.message_indexes.is_empty());
+        assert!(parse_confluent(&[1, 0, 0, 0, 7]).is_err());
+    }
+
     #[test]
     fn test_invalid_varint() {
         let r = read_varint(&mut Cursor::new(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]));
@@ -117,11 +163,29 @@ mod test {
     }
 }
 
-fn read_varint(rdr: &mut Cursor<&[u8]>) -> Result<i32> {
+pub(crate) fn read_varint(rdr: &mut Cursor<&[u8]>) -> Result<i32> {
     let value1 = read_unsigned_varint(rdr)?;
     Ok(unsigned_right_shift_i32(value1, 1)? ^ -(value1 & 1))
 }
 
+/// Zigzag encoded varint of up to 64 bits
+pub(crate) fn read_varlong(rdr: &mut Cursor<&[u8]>) -> Result<i64> {
+    let mut value: u64 = 0;
+    let mut i = 0;
+    loop {
+        let b = rdr.read_u8()?;
+        value |= ((b & 0x7f) as u64) << i;
+        if b & 0x80 == 0 {
+            break;
+        }
+        i += 7;
+        if i > 63 {
+            return Err(VarintError::InvalidVarint.into());
+        }
+    }
+    Ok(((value >> 1) as i64) ^ -((value & 1) as i64))
+}
+
 #[allow(overflowing_literals)]
 fn write_unsigned_varint(mut value: i32) -> Result<Vec<u8>> {
     let mut out = vec![];
@@ -135,8 +199,7 @@ fn write_unsigned_varint(mut value: i32) -> Result<Vec<u8>> {
 }
 
 #[allow(overflowing_literals)]
-#[allow(unused)]
-fn write_varint(value: i32) -> Result<Vec<u8>> {
+pub(crate) fn write_varint(value: i32) -> Result<Vec<u8>> {
     write_unsigned_varint((value << 1) ^ (value >> 31))
 }
 
@@ -168,6 +231,8 @@ fn read_unsigned_varint(rdr: &mut Cursor<&[u8]>) -> std::result::Result<i32, Var
 pub struct Schema {
     pub id: i32,
     pub version: usize,
+    /// left out by the registry for avro schemas
+    #[serde(default)]
     pub schema_type: String,
     pub subject: String,
     pub references: Option<Vec<Reference>>,
@@ -188,11 +253,17 @@ pub fn parse_schemas(sl: &[u8]) -> Result<Vec<Schema>> {
 
 pub fn get_schemas_http(url: String) -> Result<Vec<Schema>> {
     //info!("getting schemas from {url}");
-    let resp = reqwest::blocking::get(url)?;
-    parse_schemas(resp.bytes()?.deref())
+    let fetch = || -> Result<Vec<Schema>> {
+        let resp = reqwest::blocking::get(&url)?;
+        parse_schemas(resp.bytes()?.deref())
+    };
+    fetch().with_context(|| format!("fetching {}", url))
 }
 
-pub fn get_schemas_fs(path: &str) -> Result<Vec<Schema>> {
-    //info!("reading schemas from {path}");
-    parse_schemas(std::fs::read(path)?.as_slice())
+/// Latest registered version of `subject`, if any
+pub fn latest_schema<'a>(schemas: &'a [Schema], subject: &str) -> Option<&'a Schema> {
+    schemas
+        .iter()
+        .filter(|s| s.subject == subject)
+        .max_by_key(|s| s.version)
 }
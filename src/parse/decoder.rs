@@ -0,0 +1,207 @@
+use crate::parse::confluent::Schema;
+use crate::parse::envelope::Envelope;
+use crate::parse::error::*;
+use crate::parse::metrics::Metrics;
+use crate::parse::msg::Msg;
+use crate::parse::proto2json::Proto2Json;
+use crate::parse::redact::Redaction;
+use crate::parse::source::SchemaSource;
+use std::path::Path;
+use std::sync::Arc;
+
+/// One message decoded from a value
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DecodedValue {
+    /// None for JSON values
+    pub schema_id: Option<i32>,
+    /// fully qualified protobuf message type, None for JSON values
+    pub message_type: Option<String>,
+    /// from 0 to 1, set when the message type was guessed for lack of message indexes
+    pub confidence: Option<f64>,
+    pub value: serde_json::Value,
+}
+
+/// Decodes confluent framed protobuf, and JSON as is.
+///
+/// Schemas are compiled on first use. Clones share compiled schemas, so decoding on several
+/// threads takes one clone per thread.
+#[derive(Clone)]
+pub struct Decoder {
+    p: Proto2Json,
+}
+
+impl Decoder {
+    /// Schemas are written to `proto_path` to be compiled, their imports are looked up in
+    /// `includes`, `proto_path` and the current directory
+    pub fn new(source: Arc<dyn SchemaSource>, proto_path: &Path, includes: &[String]) -> Decoder {
+        Decoder {
+            p: Proto2Json::new(
+                source,
+                Some(proto_path.display().to_string()),
+                includes.to_vec(),
+            ),
+        }
+    }
+
+    /// Applied to every decoded value
+    pub fn set_redaction(&mut self, redaction: Redaction) {
+        self.p.set_redaction(redaction);
+    }
+
+    /// Records schema fetch latency and descriptor cache hits, shared by clones made afterwards
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.p.set_metrics(metrics);
+    }
+
+    /// None if the schema is unknown or its source failed
+    pub fn schema(&self, schema_id: i32) -> Option<Schema> {
+        self.p.schema(schema_id)
+    }
+
+    /// A value without message indexes is decoded as the type it fits best. It fails with
+    /// `NoMatchingMessageType` if it fits none of the types of its schema, and with
+    /// `AmbiguousMessageType` if several fit equally well.
+    pub fn decode(&mut self, value: &[u8]) -> Result<Vec<DecodedValue>> {
+        let (schema_id, confidence, values) = self.p.decode_json(value)?;
+        Ok(values
+            .into_iter()
+            .map(|(message_type, value)| DecodedValue {
+                schema_id,
+                message_type,
+                confidence,
+                value,
+            })
+            .collect())
+    }
+
+    /// Decodes the message's value, and its key if it is confluent framed
+    pub fn decode_msg(&mut self, msg: &Msg) -> Result<Vec<Envelope>> {
+        self.p.envelopes(msg)
+    }
+
+    /// Encodes `value` as `message_type`, the first message of the schema if unset
+    pub fn encode(
+        &mut self,
+        schema_id: i32,
+        message_type: Option<&str>,
+        value: &serde_json::Value,
+    ) -> Result<Vec<u8>> {
+        self.p.encode(schema_id, message_type, value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::confluent::{parse_confluent, write_confluent, Reference, Schema};
+    use crate::parse::decoder::Decoder;
+    use crate::parse::error::Error;
+    use crate::parse::protobuf::ProtobufError;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = std::env::temp_dir().join(format!("decoder-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let schema = |id: i32, subject: &str, references| Schema {
+            id,
+            version: 1,
+            schema_type: "PROTOBUF".to_string(),
+            subject: subject.to_string(),
+            references,
+            schema: "syntax = \"proto3\";\npackage acme;\nmessage Order { string id = 1; }\n"
+                .to_string(),
+        };
+        let escape = Reference {
+            name: "../escape.proto".to_string(),
+            subject: "common".to_string(),
+            version: 1,
+        };
+        let schemas = vec![
+            schema(7, "orders-value", None),
+            schema(8, "escape-value", Some(vec![escape])),
+            schema(9, "common", None),
+        ];
+        let mut decoder = Decoder::new(Arc::new(schemas), &dir, &[]);
+        let bytes = decoder.encode(7, None, &json!({"id": "x"})).unwrap();
+        let decoded = decoder.decode(&bytes).unwrap();
+        assert_eq!(decoded[0].schema_id, Some(7));
+        assert_eq!(decoded[0].message_type.as_deref(), Some("acme.Order"));
+        assert_eq!(decoded[0].value, json!({"id": "x"}));
+        assert_eq!(decoder.decode(b"[1]").unwrap()[0].value, json!([1]));
+        // references are written below the proto path only
+        let escaping = decoder.decode(&[0, 0, 0, 0, 8, 0]).unwrap_err();
+        assert_eq!(escaping.kind(), "InvalidSchema");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_message_type_guess() {
+        let dir = std::env::temp_dir().join(format!("decoder-guess-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let schemas = vec![Schema {
+            id: 7,
+            version: 1,
+            schema_type: "PROTOBUF".to_string(),
+            subject: "orders-value".to_string(),
+            references: None,
+            schema: "syntax = \"proto3\";\npackage acme;\nenum Status { NEW = 0; PAID = 1; }\n\
+                     message Order { string id = 1; Status status = 2; }\n\
+                     message Refund { string id = 1; int64 amount = 3; }\n\
+                     message Ping { string id = 1; int64 sent = 4; }\n\
+                     message Pong { string id = 1; int64 sent = 4; }\n"
+                .to_string(),
+        }];
+        let mut decoder = Decoder::new(Arc::new(schemas), &dir, &[]);
+
+        // the first type is framed with empty message indexes
+        let bytes = decoder
+            .encode(7, Some("acme.Order"), &json!({"id": "x"}))
+            .unwrap();
+        assert_eq!(bytes, [0, 0, 0, 0, 7, 0, 0x0a, 1, b'x']);
+        let decoded = decoder.decode(&bytes).unwrap();
+        assert_eq!(decoded[0].message_type.as_deref(), Some("acme.Order"));
+        assert_eq!(decoded[0].confidence, None);
+        let empty = decoder.decode(&[0, 0, 0, 0, 7, 0]).unwrap();
+        assert_eq!(empty[0].message_type.as_deref(), Some("acme.Order"));
+
+        // message indexes tell the type
+        let bytes = decoder
+            .encode(7, Some("acme.Refund"), &json!({"id": "x", "amount": "5"}))
+            .unwrap();
+        let decoded = decoder.decode(&bytes).unwrap();
+        assert_eq!(decoded[0].message_type.as_deref(), Some("acme.Refund"));
+        assert_eq!(decoded[0].confidence, None);
+
+        // a legacy producer left them empty
+        let legacy = write_confluent(7, &[0], &parse_confluent(&bytes).unwrap().value).unwrap();
+        let decoded = decoder.decode(&legacy).unwrap();
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].message_type.as_deref(), Some("acme.Refund"));
+        assert_eq!(decoded[0].confidence, Some(1.0));
+
+        // status 5 isn't one, which is as bad as a field unknown to the other types
+        let order = write_confluent(7, &[0], &[0x0a, 1, b'x', 0x10, 5]).unwrap();
+        let decoded = decoder.decode(&order).unwrap();
+        assert_eq!(decoded[0].message_type.as_deref(), Some("acme.Order"));
+        assert_eq!(decoded[0].confidence, Some(0.5));
+
+        let sent = write_confluent(7, &[0], &[0x0a, 1, b'x', 0x20, 1]).unwrap();
+        match decoder.decode(&sent) {
+            Err(Error::Protobuf(ProtobufError::AmbiguousMessageType(7, names))) => {
+                assert_eq!(names, vec!["acme.Ping", "acme.Pong"])
+            }
+            other => panic!("{:?}", other),
+        }
+
+        // truncated
+        let truncated = write_confluent(7, &[0], &[0x0a, 5, b'x']).unwrap();
+        match decoder.decode(&truncated) {
+            Err(Error::Protobuf(ProtobufError::NoMatchingMessageType(7))) => {}
+            other => panic!("{:?}", other),
+        }
+        let truncated = write_confluent(7, &[1], &[0x0a, 5, b'x']).unwrap();
+        assert_eq!(decoder.decode(&truncated).unwrap_err().kind(), "Protobuf");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+use crate::parse::confluent::peek_schema_id;
+use crate::parse::error::*;
+use crate::parse::msg::{Msg, ParsedKey};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::collections::BTreeMap;
+use std::io::BufRead;
+
+/// A record that failed to decode, one JSON object per line. Key, value and header values are
+/// the original bytes base64 encoded, so that the record can be read back with `Parser` and
+/// retried once the schemas are fixed.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DeadLetter {
+    pub topic: String,
+    pub partition: i64,
+    pub offset: i64,
+    pub ts: i64,
+    pub key: Option<String>,
+    pub value: String,
+    pub headers: Vec<(String, Option<String>)>,
+    pub schema_id: Option<i32>,
+    /// `Error::kind` of the failure
+    pub error: String,
+    pub message: String,
+}
+
+impl DeadLetter {
+    pub fn new(msg: &Msg, e: &Error) -> DeadLetter {
+        let encode = |key: &ParsedKey| key.as_bytes().map(|b| STANDARD.encode(b));
+        DeadLetter {
+            topic: msg.topic.clone(),
+            partition: msg.partition,
+            offset: msg.offset,
+            ts: msg.ts,
+            key: encode(&msg.key),
+            value: STANDARD.encode(&msg.msg),
+            headers: msg
+                .headers
+                .iter()
+                .map(|(name, value)| (name.clone(), encode(value)))
+                .collect(),
+            schema_id: peek_schema_id(&msg.msg),
+            error: e.kind().to_string(),
+            message: report(e),
+        }
+    }
+
+    /// The record as it was read before it failed
+    pub fn to_msg(&self) -> Result<Msg> {
+        let decode = |v: &Option<String>| -> Result<ParsedKey> {
+            Ok(match v {
+                Some(v) => ParsedKey::new(&STANDARD.decode(v)?),
+                None => ParsedKey::None,
+            })
+        };
+        let key = decode(&self.key)?;
+        let msg = STANDARD.decode(&self.value)?;
+        Ok(Msg {
+            topic: self.topic.clone(),
+            partition: self.partition,
+            offset: self.offset,
+            ts: self.ts,
+            key_len: key.len(),
+            key,
+            msg_len: msg.len(),
+            msg,
+            headers: self
+                .headers
+                .iter()
+                .map(|(name, value)| Ok((name.clone(), decode(value)?)))
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+/// Parser for dead letters, one per line, yielding the records that failed
+pub struct Parser<R> {
+    r: R,
+}
+
+impl<R: BufRead> Parser<R> {
+    pub fn new(r: R) -> Self {
+        Parser { r }
+    }
+}
+
+impl<R: BufRead> Iterator for Parser<R> {
+    type Item = Result<Msg>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut line = String::new();
+            match self.r.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) if line.trim().is_empty() => continue,
+                Ok(_) => {
+                    return Some(
+                        serde_json::from_str::<DeadLetter>(&line)
+                            .map_err(Error::from)
+                            .and_then(|letter| letter.to_msg()),
+                    )
+                }
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+/// Number of failed records by error kind and schema id
+#[derive(Debug, Default)]
+pub struct Failures {
+    counts: BTreeMap<(&'static str, Option<i32>), usize>,
+}
+
+impl Failures {
+    pub fn add(&mut self, msg: &Msg, e: &Error) {
+        *self
+            .counts
+            .entry((e.kind(), peek_schema_id(&msg.msg)))
+            .or_default() += 1;
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    /// On stderr, stdout holds the decoded records
+    pub fn print_summary(&self) {
+        if self.counts.is_empty() {
+            return;
+        }
+        eprintln!("{:<40} {:>9} {:>10}", "ERROR", "SCHEMA_ID", "COUNT");
+        for ((kind, schema_id), count) in &self.counts {
+            let schema_id = schema_id.map_or("-".to_string(), |id| id.to_string());
+            eprintln!("{:<40} {:>9} {:>10}", kind, schema_id, count);
+        }
+        eprintln!("{:<40} {:>9} {:>10}", "TOTAL", "", self.total());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::dlq::{DeadLetter, Failures, Parser};
+    use crate::parse::error::Error;
+    use crate::parse::msg::{Msg, ParsedKey};
+    use crate::parse::protobuf::ProtobufError;
+
+    #[test]
+    fn test_dead_letters() {
+        let msg = Msg {
+            topic: "orders".to_string(),
+            partition: 2,
+            offset: 42,
+            ts: 1000,
+            key: ParsedKey::NotUtf8(vec![0xff, 0]),
+            key_len: 2,
+            msg: vec![0, 0, 0, 0, 7, 0, 1],
+            msg_len: 7,
+            headers: vec![
+                ("trace".to_string(), ParsedKey::Utf8("t".to_string())),
+                ("empty".to_string(), ParsedKey::None),
+            ],
+        };
+        let e: Error = ProtobufError::SchemaNotFound(7).into();
+        let letter = DeadLetter::new(&msg, &e);
+        assert_eq!(letter.schema_id, Some(7));
+        assert_eq!(letter.error, "SchemaNotFound");
+
+        let lines = format!("{}\n\n", serde_json::to_string(&letter).unwrap());
+        let read = Parser::new(lines.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].msg, msg.msg);
+        assert_eq!(read[0].key.as_bytes(), msg.key.as_bytes());
+        assert_eq!(read[0].headers[0].1.as_bytes(), Some(&b"t"[..]));
+        assert!(read[0].headers[1].1.as_bytes().is_none());
+        assert_eq!(DeadLetter::new(&read[0], &e), letter);
+
+        let mut failures = Failures::default();
+        failures.add(&msg, &e);
+        failures.add(&msg, &e);
+        failures.add(&read[0], &Error::Eof);
+        assert_eq!(failures.total(), 3);
+        assert_eq!(failures.counts[&("SchemaNotFound", Some(7))], 2);
+    }
+}
@@ -0,0 +1,88 @@
+use crate::parse::error::*;
+use crate::parse::msg::ParsedKey;
+
+/// A record together with its kafka coordinates, one JSON object per line
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Envelope {
+    pub topic: String,
+    pub partition: i64,
+    #[serde(default)]
+    pub offset: i64,
+    #[serde(default)]
+    pub ts: i64,
+    #[serde(default)]
+    pub key: ParsedKey,
+    /// the key decoded with its schema, when it was written with one
+    #[serde(default)]
+    pub decoded_key: Option<serde_json::Value>,
+    #[serde(default)]
+    pub headers: Vec<(String, ParsedKey)>,
+    #[serde(default)]
+    pub schema_id: Option<i32>,
+    #[serde(default)]
+    pub subject: Option<String>,
+    #[serde(default)]
+    pub version: Option<usize>,
+    /// fully qualified protobuf message type
+    #[serde(default)]
+    pub message_type: Option<String>,
+    /// from 0 to 1, how well the value fits `message_type` when that was guessed because the
+    /// record had no message indexes and its schema has several types
+    #[serde(default)]
+    pub confidence: Option<f64>,
+    pub value: serde_json::Value,
+}
+
+impl Envelope {
+    /// The envelope as a JSON object, moving the value rather than copying it
+    pub fn into_json(mut self) -> Result<serde_json::Value> {
+        let value = self.value.take();
+        let mut json = serde_json::to_value(&self)?;
+        json["value"] = value;
+        Ok(json)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::envelope::Envelope;
+    use crate::parse::filter::Filter;
+    use crate::parse::msg::ParsedKey;
+    use serde_json::json;
+
+    #[test]
+    fn test_key_and_headers() {
+        let envelope = Envelope {
+            topic: "orders".to_string(),
+            partition: 3,
+            offset: 42,
+            ts: 0,
+            key: ParsedKey::Utf8("k1".to_string()),
+            decoded_key: None,
+            headers: vec![
+                ("source".to_string(), ParsedKey::Utf8("web".to_string())),
+                ("trace".to_string(), ParsedKey::NotUtf8(vec![0xff, 0])),
+                ("empty".to_string(), ParsedKey::None),
+            ],
+            schema_id: None,
+            subject: None,
+            version: None,
+            message_type: None,
+            confidence: None,
+            value: json!({}),
+        };
+        let json = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(json["key"], json!("k1"));
+        assert_eq!(
+            json["headers"],
+            json!([["source", "web"], ["trace", {"base64": "/wA="}], ["empty", null]])
+        );
+        let filter = Filter::new(Some("key == 'k1'"), None).unwrap();
+        assert!(filter.matches(&json));
+
+        let read: Envelope = serde_json::from_value(json).unwrap();
+        assert!(matches!(&read.key, ParsedKey::Utf8(k) if k == "k1"));
+        assert_eq!(read.headers[1].1.as_bytes(), Some(&[0xff, 0][..]));
+        assert!(matches!(read.headers[2].1, ParsedKey::None));
+    }
+}
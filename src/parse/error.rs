@@ -1,6 +1,13 @@
 use crate::parse::confluent::VarintError;
+use crate::parse::filter::FilterError;
+use crate::parse::kafka::ConsumerError;
+use crate::parse::kcat::KcatError;
+use crate::parse::pcap::PcapError;
 use crate::parse::protobuf::ProtobufError;
+use crate::parse::redact::RedactError;
+use crate::parse::segment::SegmentError;
 use derive_more::From;
+use std::fmt;
 use std::num::{ParseIntError, TryFromIntError};
 use std::string::FromUtf8Error;
 
@@ -12,14 +19,439 @@ pub enum Error {
     TryFromIntError(TryFromIntError),
     Infallible(std::convert::Infallible),
     Eof,
+    InvalidMagicByte(u8),
     EolNotFound,
     NeedAtLeastOneBrokerHostname,
     NeedAtLeastOneTopic,
+    /// the checkpoint was written for another source or other inputs
+    CheckpointMismatch,
+    /// the source's records can't be counted to the same place again
+    CheckpointUnsupported,
+    /// the HTTP service already follows as many tails as it may
+    TooManyTails(usize),
+    /// the registry failed to answer for a schema moments ago, it isn't asked again yet
+    RegistryFailed(String),
     Varint(VarintError),
     Protobuf(ProtobufError),
     SerdeJson(serde_json::Error),
     Reqwest(reqwest::Error),
     JsonPrint(protobuf_json_mapping::PrintError),
+    JsonParse(protobuf_json_mapping::ParseError),
+    Protobuf3(protobuf::Error),
+    Kafka(rdkafka::error::KafkaError),
+    Consumer(ConsumerError),
+    Kcat(KcatError),
+    Segment(SegmentError),
+    Pcap(PcapError),
+    Filter(FilterError),
+    Redact(RedactError),
+    Base64(base64::DecodeError),
+    Hex(hex::FromHexError),
+    /// what was being done when the error happened, such as the file, URL or record involved
+    #[from(ignore)]
+    Context(String, Box<Error>),
+    /// context of an error in the configuration, such as a schema file given that isn't valid,
+    /// whatever the error itself is
+    #[from(ignore)]
+    Config(String, Box<Error>),
 }
 
 pub type Result<A> = std::result::Result<A, Error>;
+
+impl Error {
+    /// Variant name, used to group errors in reports
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::IoError(_) => "IoError",
+            Error::FromUtf8Error(_) => "FromUtf8Error",
+            Error::ParseIntError(_) => "ParseIntError",
+            Error::TryFromIntError(_) => "TryFromIntError",
+            Error::Infallible(_) => "Infallible",
+            Error::Eof => "Eof",
+            Error::InvalidMagicByte(_) => "InvalidMagicByte",
+            Error::EolNotFound => "EolNotFound",
+            Error::NeedAtLeastOneBrokerHostname => "NeedAtLeastOneBrokerHostname",
+            Error::NeedAtLeastOneTopic => "NeedAtLeastOneTopic",
+            Error::CheckpointMismatch => "CheckpointMismatch",
+            Error::CheckpointUnsupported => "CheckpointUnsupported",
+            Error::TooManyTails(_) => "TooManyTails",
+            Error::RegistryFailed(_) => "RegistryFailed",
+            Error::Varint(VarintError::InvalidVarint) => "InvalidVarint",
+            Error::Protobuf(ProtobufError::SchemaNotFound(_)) => "SchemaNotFound",
+            Error::Protobuf(ProtobufError::CouldNotFindFileDescriptorForSchema(_)) => {
+                "CouldNotFindFileDescriptorForSchema"
+            }
+            Error::Protobuf(ProtobufError::MessageTypeNotFound(..)) => "MessageTypeNotFound",
+            Error::Protobuf(ProtobufError::ReferenceNotFound(..)) => "ReferenceNotFound",
+            Error::Protobuf(ProtobufError::MissingImport(_)) => "MissingImport",
+            Error::Protobuf(ProtobufError::InvalidSchema(..)) => "InvalidSchema",
+            Error::Protobuf(ProtobufError::AmbiguousMessageType(..)) => "AmbiguousMessageType",
+            Error::Protobuf(ProtobufError::NoMatchingMessageType(_)) => "NoMatchingMessageType",
+            Error::SerdeJson(_) => "SerdeJson",
+            Error::Reqwest(_) => "Reqwest",
+            Error::JsonPrint(_) => "JsonPrint",
+            Error::JsonParse(_) => "JsonParse",
+            Error::Protobuf3(_) => "Protobuf",
+            Error::Kafka(_) => "Kafka",
+            Error::Consumer(ConsumerError::UnknownPartition(..)) => "UnknownPartition",
+            Error::Consumer(ConsumerError::Fatal(_)) => "KafkaFatal",
+            Error::Consumer(ConsumerError::Idle) => "Idle",
+            Error::Consumer(ConsumerError::NotInGroup) => "NotInGroup",
+            Error::Consumer(ConsumerError::UnknownTopic(_)) => "UnknownTopic",
+            Error::Kcat(KcatError::UnknownField(_)) => "UnknownField",
+            Error::Kcat(KcatError::UnknownEscape(_)) => "UnknownEscape",
+            Error::Kcat(KcatError::FieldNeedsDelimiter(_)) => "FieldNeedsDelimiter",
+            Error::Kcat(KcatError::UnexpectedDelimiter { .. }) => "UnexpectedDelimiter",
+            Error::Segment(SegmentError::UnsupportedMagic(_)) => "UnsupportedMagic",
+            Error::Segment(SegmentError::UnsupportedCompression(_)) => "UnsupportedCompression",
+            Error::Segment(SegmentError::RecordsTooLong(_)) => "RecordsTooLong",
+            Error::Pcap(PcapError::UnknownMagic(_)) => "UnknownMagic",
+            Error::Pcap(PcapError::UnsupportedLinkType(_)) => "UnsupportedLinkType",
+            Error::Filter(FilterError::UnexpectedToken(_)) => "UnexpectedToken",
+            Error::Filter(FilterError::UnexpectedEnd) => "UnexpectedEnd",
+            Error::Filter(FilterError::InvalidRegex(_)) => "InvalidRegex",
+            Error::Redact(RedactError::UnknownAction(_)) => "UnknownAction",
+            Error::Base64(_) => "Base64",
+            Error::Hex(_) => "Hex",
+            Error::Context(_, e) | Error::Config(_, e) => e.kind(),
+        }
+    }
+
+    /// The error without the context it was wrapped in
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Context(_, e) | Error::Config(_, e) => e.root(),
+            other => other,
+        }
+    }
+
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Error::Config(..) => ErrorClass::Config,
+            Error::Context(_, e) => e.class(),
+            Error::NeedAtLeastOneBrokerHostname
+            | Error::NeedAtLeastOneTopic
+            | Error::CheckpointMismatch
+            | Error::CheckpointUnsupported
+            | Error::Consumer(ConsumerError::UnknownTopic(_))
+            | Error::Kcat(KcatError::UnknownField(_))
+            | Error::Kcat(KcatError::UnknownEscape(_))
+            | Error::Kcat(KcatError::FieldNeedsDelimiter(_))
+            | Error::Filter(_)
+            | Error::Redact(_) => ErrorClass::Config,
+            Error::IoError(e) => match e.kind() {
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied => {
+                    ErrorClass::Config
+                }
+                std::io::ErrorKind::ConnectionRefused
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::TimedOut => ErrorClass::Network,
+                _ => ErrorClass::Other,
+            },
+            Error::Reqwest(_)
+            | Error::RegistryFailed(_)
+            | Error::Kafka(_)
+            | Error::Consumer(ConsumerError::Fatal(_))
+            | Error::Consumer(ConsumerError::NotInGroup) => ErrorClass::Network,
+            Error::Protobuf(ProtobufError::AmbiguousMessageType(..))
+            | Error::Protobuf(ProtobufError::NoMatchingMessageType(_)) => ErrorClass::Decode,
+            Error::Protobuf(_) => ErrorClass::Schema,
+            Error::Consumer(ConsumerError::Idle)
+            | Error::TooManyTails(_)
+            | Error::Infallible(_) => ErrorClass::Other,
+            _ => ErrorClass::Decode,
+        }
+    }
+}
+
+/// What failed, for scripts telling an unreachable registry from a corrupt record
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorClass {
+    /// arguments, or files named by them, that can't be used
+    Config,
+    /// brokers or schema registry unreachable or failing
+    Network,
+    /// schemas missing or not compiling
+    Schema,
+    /// records that aren't what their source or schema says they are
+    Decode,
+    Other,
+}
+
+impl ErrorClass {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorClass::Other => 1,
+            ErrorClass::Config => 2,
+            ErrorClass::Network => 3,
+            ErrorClass::Schema => 4,
+            ErrorClass::Decode => 5,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IoError(e) => write!(f, "{}", e),
+            Error::FromUtf8Error(e) => write!(f, "invalid UTF-8: {}", e),
+            Error::ParseIntError(e) => write!(f, "invalid integer: {}", e),
+            Error::TryFromIntError(e) => write!(f, "integer out of range: {}", e),
+            Error::Infallible(e) => match *e {},
+            Error::Eof => write!(f, "unexpected end of input"),
+            Error::InvalidMagicByte(b) => {
+                write!(f, "magic byte {} instead of 0, not confluent framed", b)
+            }
+            Error::EolNotFound => write!(f, "end of line not found"),
+            Error::NeedAtLeastOneBrokerHostname => write!(f, "no kafka brokers given"),
+            Error::NeedAtLeastOneTopic => write!(f, "no topics given"),
+            Error::CheckpointMismatch => write!(
+                f,
+                "the checkpoint was written for another source or other inputs"
+            ),
+            Error::CheckpointUnsupported => write!(
+                f,
+                "--checkpoint can't resume -m kafka, whose partitions interleave differently on \
+                 every read; use --group-id to resume from committed offsets"
+            ),
+            Error::TooManyTails(max) => write!(f, "already following {} tails", max),
+            Error::RegistryFailed(e) => write!(f, "{} (not retried yet)", e),
+            Error::Varint(e) => write!(f, "{}", e),
+            Error::Protobuf(e) => write!(f, "{}", e),
+            Error::SerdeJson(e) => write!(f, "invalid JSON: {}", e),
+            Error::Reqwest(e) => write!(f, "{}", e),
+            Error::JsonPrint(e) => write!(f, "printing JSON: {}", e),
+            Error::JsonParse(e) => write!(f, "parsing JSON: {}", e),
+            Error::Protobuf3(e) => write!(f, "protobuf: {}", e),
+            Error::Kafka(e) => write!(f, "kafka: {}", e),
+            Error::Consumer(e) => write!(f, "{}", e),
+            Error::Kcat(e) => write!(f, "{}", e),
+            Error::Segment(e) => write!(f, "{}", e),
+            Error::Pcap(e) => write!(f, "{}", e),
+            Error::Filter(e) => write!(f, "{}", e),
+            Error::Redact(e) => write!(f, "{}", e),
+            Error::Base64(e) => write!(f, "invalid base64: {}", e),
+            Error::Hex(e) => write!(f, "invalid hex: {}", e),
+            Error::Context(context, _) | Error::Config(context, _) => write!(f, "{}", context),
+        }
+    }
+}
+
+/// Errors of other crates are displayed in place of the variant wrapping them. Most of them
+/// display their causes already, only those of io errors are left to the chain.
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IoError(e) => e.source(),
+            Error::Context(_, e) | Error::Config(_, e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for VarintError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VarintError::InvalidVarint => write!(f, "invalid varint"),
+        }
+    }
+}
+
+impl fmt::Display for ProtobufError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtobufError::SchemaNotFound(id) => write!(f, "schema {} not found", id),
+            ProtobufError::CouldNotFindFileDescriptorForSchema(id) => {
+                write!(f, "schema {} compiled to no file descriptor", id)
+            }
+            ProtobufError::MessageTypeNotFound(id, name) => {
+                write!(f, "schema {} has no message type {}", id, name)
+            }
+            ProtobufError::ReferenceNotFound(subject, version) => write!(
+                f,
+                "referenced schema {} version {} not found",
+                subject, version
+            ),
+            ProtobufError::MissingImport(name) => {
+                write!(f, "{} is missing from the descriptor set", name)
+            }
+            ProtobufError::InvalidSchema(id, message) => {
+                write!(f, "schema {} does not compile: {}", id, message)
+            }
+            ProtobufError::AmbiguousMessageType(id, names) => write!(
+                f,
+                "record without message indexes fits message types {} of schema {} equally well",
+                names.join(", "),
+                id
+            ),
+            ProtobufError::NoMatchingMessageType(id) => write!(
+                f,
+                "record without message indexes fits no message type of schema {}",
+                id
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ConsumerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsumerError::UnknownPartition(topic, partition) => write!(
+                f,
+                "message of partition {} of {}, which wasn't assigned",
+                partition, topic
+            ),
+            ConsumerError::Fatal(e) => write!(f, "kafka: {}", e),
+            ConsumerError::Idle => write!(f, "nothing arrived within a poll"),
+            ConsumerError::NotInGroup => write!(f, "the consumer is not in its group"),
+            ConsumerError::UnknownTopic(topic) => write!(
+                f,
+                "topic {} doesn't exist or has none of the requested partitions",
+                topic
+            ),
+        }
+    }
+}
+
+impl fmt::Display for KcatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KcatError::UnknownField(c) => write!(f, "unknown kcat format field %{}", c),
+            KcatError::UnknownEscape(c) => write!(f, "unknown kcat format escape \\{}", c),
+            KcatError::FieldNeedsDelimiter(c) => write!(
+                f,
+                "kcat format field %{} must be followed by a delimiter",
+                c
+            ),
+            KcatError::UnexpectedDelimiter { expected, found } => write!(
+                f,
+                "expected delimiter {:?}, found {:?}",
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(found)
+            ),
+        }
+    }
+}
+
+impl fmt::Display for SegmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SegmentError::UnsupportedMagic(magic) => write!(
+                f,
+                "record batches of magic {} are not supported, only 2",
+                magic
+            ),
+            SegmentError::UnsupportedCompression(codec) => {
+                write!(f, "compression codec {} is not supported", codec)
+            }
+            SegmentError::RecordsTooLong(max) => {
+                write!(f, "records decompressing to more than {} bytes", max)
+            }
+        }
+    }
+}
+
+impl fmt::Display for PcapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PcapError::UnknownMagic(magic) => {
+                write!(f, "not a pcap or pcapng capture, magic {:#x}", magic)
+            }
+            PcapError::UnsupportedLinkType(link_type) => {
+                write!(f, "link type {} is not supported", link_type)
+            }
+        }
+    }
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterError::UnexpectedToken(token) => write!(f, "unexpected {} in expression", token),
+            FilterError::UnexpectedEnd => write!(f, "unexpected end of expression"),
+            FilterError::InvalidRegex(e) => write!(f, "invalid regex: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for RedactError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RedactError::UnknownAction(action) => write!(
+                f,
+                "unknown redaction action {}, expected mask, hash or drop",
+                action
+            ),
+        }
+    }
+}
+
+pub trait Context<T> {
+    /// Wraps the error with what was being done, `f` is only called on errors
+    fn with_context(self, f: impl FnOnce() -> String) -> Result<T>;
+
+    /// Wraps the error with the configuration it was in, making it a config error
+    fn with_config_context(self, f: impl FnOnce() -> String) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for std::result::Result<T, E> {
+    fn with_context(self, f: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|e| Error::Context(f(), Box::new(e.into())))
+    }
+
+    fn with_config_context(self, f: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|e| Error::Config(f(), Box::new(e.into())))
+    }
+}
+
+/// The error and its causes, one per line, as printed before exiting
+pub fn report(e: &dyn std::error::Error) -> String {
+    let mut out = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        out.push_str(&format!("\n  caused by: {}", e));
+        source = e.source();
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::error::{report, Context, Error, ErrorClass};
+    use crate::parse::protobuf::ProtobufError;
+
+    #[test]
+    fn test_report_and_class() {
+        let e = std::fs::read("/nonexistent/schemas.json")
+            .with_context(|| "reading schemas from /nonexistent/schemas.json".to_string())
+            .unwrap_err();
+        assert_eq!(e.kind(), "IoError");
+        assert_eq!(e.class(), ErrorClass::Config);
+        assert!(report(&e).starts_with(
+            "reading schemas from /nonexistent/schemas.json\n  caused by: No such file"
+        ));
+
+        let e: Error = ProtobufError::SchemaNotFound(7).into();
+        let e = Err::<(), _>(e)
+            .with_context(|| "decoding record 3 of orders, partition 0 offset 3".to_string())
+            .unwrap_err();
+        assert_eq!(
+            report(&e),
+            "decoding record 3 of orders, partition 0 offset 3\n  caused by: schema 7 not found"
+        );
+        assert_eq!(e.class().exit_code(), 4);
+        assert_eq!(Error::InvalidMagicByte(1).class().exit_code(), 5);
+        assert_eq!(Error::NeedAtLeastOneBrokerHostname.class().exit_code(), 2);
+
+        let e = serde_json::from_str::<Vec<i32>>("[1,")
+            .with_context(|| "reading schemas from schemas.json".to_string())
+            .with_config_context(|| "loading --schemas-path".to_string())
+            .unwrap_err();
+        assert_eq!(e.kind(), "SerdeJson");
+        assert_eq!(e.class(), ErrorClass::Config);
+        assert!(report(&e).starts_with(
+            "loading --schemas-path\n  caused by: reading schemas from schemas.json\n  caused by: "
+        ));
+    }
+}
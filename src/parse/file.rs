@@ -0,0 +1,261 @@
+use crate::parse::error::*;
+use crate::parse::msg::{Msg, ParsedKey};
+use base64::Engine;
+use byteorder::{BigEndian, ReadBytesExt};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use tracing::debug;
+
+#[derive(Debug, Clone, Copy)]
+pub enum LineEncoding {
+    Base64,
+    Hex,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum LengthPrefix {
+    /// unsigned varint, as written by protobuf's writeDelimitedTo
+    Varint,
+    /// 4 byte big endian
+    U32,
+}
+
+fn msg(topic: &str, offset: i64, key: ParsedKey, value: Vec<u8>) -> Msg {
+    Msg {
+        topic: topic.to_string(),
+        partition: -1,
+        offset,
+        ts: -1,
+        key_len: key.len(),
+        key,
+        msg_len: value.len(),
+        msg: value,
+        headers: vec![],
+    }
+}
+
+/// Inputs read one after another starting `start` bytes in, stdin if there are none.
+/// Files are seeked to the start, stdin has to be read up to it.
+pub fn open_inputs(paths: &[String], start: u64) -> Result<Box<dyn BufRead>> {
+    if paths.is_empty() {
+        let mut r = BufReader::new(std::io::stdin());
+        std::io::copy(&mut (&mut r).take(start), &mut std::io::sink())?;
+        return Ok(Box::new(r));
+    }
+    let mut start = start;
+    let mut r: Box<dyn Read> = Box::new(std::io::empty());
+    for path in paths {
+        let mut file = File::open(path).with_context(|| format!("opening {}", path))?;
+        let len = file.metadata()?.len();
+        if start >= len {
+            start -= len;
+            continue;
+        }
+        file.seek(SeekFrom::Start(start))?;
+        start = 0;
+        r = Box::new(r.chain(file));
+    }
+    Ok(Box::new(BufReader::new(r)))
+}
+
+/// Every file becomes one message keyed by its file name, directories are expanded to the files
+/// they contain
+pub fn read_files(
+    paths: &[String],
+    topic: &str,
+) -> Result<impl Iterator<Item = Result<Msg>>> {
+    let mut files = vec![];
+    for path in paths {
+        let path = PathBuf::from(path);
+        if path.is_dir() {
+            let mut entries = std::fs::read_dir(&path)
+                .and_then(|entries| entries.map(|e| e.map(|e| e.path())).collect())
+                .with_context(|| format!("reading {}", path.display()))
+                .map(|entries: Vec<_>| entries)?;
+            entries.retain(|p| p.is_file());
+            entries.sort();
+            files.extend(entries);
+        } else {
+            files.push(path);
+        }
+    }
+    let topic = topic.to_string();
+    Ok(files.into_iter().enumerate().map(move |(i, path)| {
+        debug!(path = path.display().to_string(), "file");
+        let value = std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Ok(msg(&topic, i as i64, ParsedKey::Utf8(name), value))
+    }))
+}
+
+/// One message per line, base64 or hex encoded. Blank lines are skipped, hex may contain
+/// whitespace, `:` separators and a `0x` prefix as found in hex dumps.
+pub struct LinesParser<R> {
+    r: R,
+    encoding: LineEncoding,
+    topic: String,
+    n: i64,
+}
+
+impl<R: BufRead> LinesParser<R> {
+    pub fn new(r: R, encoding: LineEncoding, topic: &str) -> Self {
+        LinesParser {
+            r,
+            encoding,
+            topic: topic.to_string(),
+            n: 0,
+        }
+    }
+
+    fn decode(&self, line: &str) -> Result<Vec<u8>> {
+        match self.encoding {
+            LineEncoding::Base64 => {
+                let line = line.trim();
+                Ok(base64::engine::general_purpose::STANDARD
+                    .decode(line)
+                    .or_else(|_| base64::engine::general_purpose::URL_SAFE.decode(line))?)
+            }
+            LineEncoding::Hex => {
+                let line = line.trim();
+                let line = line.strip_prefix("0x").unwrap_or(line);
+                let digits = line
+                    .chars()
+                    .filter(|c| !c.is_whitespace() && *c != ':')
+                    .collect::<String>();
+                Ok(hex::decode(digits)?)
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for LinesParser<R> {
+    type Item = Result<Msg>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut line = String::new();
+            match self.r.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) if line.trim().is_empty() => continue,
+                Ok(_) => {
+                    let n = self.n;
+                    self.n += 1;
+                    return Some(
+                        self.decode(&line)
+                            .map(|value| msg(&self.topic, n, ParsedKey::None, value)),
+                    );
+                }
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+/// Stream of length prefixed messages
+pub struct PrefixedParser<R> {
+    r: R,
+    prefix: LengthPrefix,
+    topic: String,
+    n: i64,
+}
+
+impl<R: BufRead> PrefixedParser<R> {
+    pub fn new(r: R, prefix: LengthPrefix, topic: &str) -> Self {
+        PrefixedParser {
+            r,
+            prefix,
+            topic: topic.to_string(),
+            n: 0,
+        }
+    }
+
+    /// None at the end of the stream
+    fn length(&mut self) -> Result<Option<usize>> {
+        if self.r.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        match self.prefix {
+            LengthPrefix::U32 => Ok(Some(self.r.read_u32::<BigEndian>()? as usize)),
+            LengthPrefix::Varint => {
+                let mut value: u64 = 0;
+                for shift in (0..64).step_by(7) {
+                    let b = self.r.read_u8()?;
+                    value |= ((b & 0x7f) as u64) << shift;
+                    if b & 0x80 == 0 {
+                        return Ok(Some(value.try_into()?));
+                    }
+                }
+                Err(crate::parse::confluent::VarintError::InvalidVarint.into())
+            }
+        }
+    }
+
+    fn parse_next(&mut self) -> Result<Option<Msg>> {
+        let len = match self.length()? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        // the length isn't trusted with an allocation, the value grows as it is read
+        let mut value = vec![];
+        (&mut self.r).take(len as u64).read_to_end(&mut value)?;
+        if value.len() < len {
+            return Err(Error::Eof);
+        }
+        let n = self.n;
+        self.n += 1;
+        Ok(Some(msg(&self.topic, n, ParsedKey::None, value)))
+    }
+}
+
+impl<R: BufRead> Iterator for PrefixedParser<R> {
+    type Item = Result<Msg>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.parse_next().transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::error::Error;
+    use crate::parse::file::{LengthPrefix, LineEncoding, LinesParser, PrefixedParser};
+
+    #[test]
+    fn test_lines() {
+        let input = "0x00 00 00 00 07\n\n00:01:ff\n";
+        let msgs = LinesParser::new(input.as_bytes(), LineEncoding::Hex, "t")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].msg, &[0, 0, 0, 0, 7]);
+        assert_eq!(msgs[1].msg, &[0, 1, 0xff]);
+        assert_eq!(msgs[1].offset, 1);
+
+        let input = "AAAAAAc=\n";
+        let msgs = LinesParser::new(input.as_bytes(), LineEncoding::Base64, "t")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(msgs[0].msg, &[0, 0, 0, 0, 7]);
+    }
+
+    #[test]
+    fn test_prefixed() {
+        let input = [2u8, b'a', b'b', 0, 1, b'c'];
+        let msgs = PrefixedParser::new(input.as_slice(), LengthPrefix::Varint, "t")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let values = msgs.iter().map(|m| m.msg.as_slice()).collect::<Vec<_>>();
+        assert_eq!(values, vec![b"ab".as_slice(), b"", b"c"]);
+
+        let input = [0u8, 0, 0, 1, b'a', 0, 0];
+        let mut parser = PrefixedParser::new(input.as_slice(), LengthPrefix::U32, "t");
+        assert_eq!(parser.next().unwrap().unwrap().msg, b"a");
+        assert!(parser.next().unwrap().is_err());
+
+        let input = [0xffu8, 0xff, 0xff, 0xff, b'a'];
+        let mut parser = PrefixedParser::new(input.as_slice(), LengthPrefix::U32, "t");
+        assert!(matches!(parser.next(), Some(Err(Error::Eof))));
+    }
+}
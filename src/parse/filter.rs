@@ -0,0 +1,547 @@
+//! `--where` and `--select` expressions over envelopes.
+//!
+//! Paths start at the envelope fields (`topic`, `partition`, `offset`, `key`, `schema_id`,
+//! `message_type`, `value`, ...) and continue with `.field`, `[index]` or `["field"]`.
+//!
+//! ```text
+//! value.order_id == "X" and partition in [0, 1]
+//! exists(value.customer.email) or not value.status =~ "^(NEW|PAID)$"
+//! ```
+//!
+//! Strings compare equal to numbers they parse as, protobuf prints 64 bit integers as strings.
+use crate::parse::error::*;
+use serde_json::Value;
+
+#[derive(Debug)]
+pub enum FilterError {
+    UnexpectedToken(String),
+    UnexpectedEnd,
+    InvalidRegex(regex::Error),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Field(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path(Vec<Segment>);
+
+impl Path {
+    pub fn parse(s: &str) -> Result<Path> {
+        let mut parser = Parser::new(s)?;
+        let path = parser.path()?;
+        parser.end()?;
+        Ok(path)
+    }
+
+    pub fn get<'a>(&self, mut value: &'a Value) -> Option<&'a Value> {
+        for segment in &self.0 {
+            value = match segment {
+                Segment::Field(name) => value.get(name)?,
+                Segment::Index(i) => value.get(i)?,
+            };
+        }
+        Some(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone)]
+pub enum Operand {
+    Path(Path),
+    Literal(Value),
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Exists(Path),
+    Cmp(Operand, Cmp, Operand),
+    In(Operand, Vec<Value>),
+    Matches(Operand, regex::Regex),
+    /// an operand on its own is true if it is `true`
+    Truthy(Operand),
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Expr> {
+        let mut parser = Parser::new(s)?;
+        let expr = parser.or()?;
+        parser.end()?;
+        Ok(expr)
+    }
+
+    pub fn eval(&self, record: &Value) -> bool {
+        match self {
+            Expr::And(a, b) => a.eval(record) && b.eval(record),
+            Expr::Or(a, b) => a.eval(record) || b.eval(record),
+            Expr::Not(e) => !e.eval(record),
+            Expr::Exists(path) => path.get(record).is_some_and(|v| !v.is_null()),
+            Expr::Cmp(a, cmp, b) => match (a.get(record), b.get(record)) {
+                (Some(a), Some(b)) => compare(a, *cmp, b),
+                _ => *cmp == Cmp::Ne,
+            },
+            Expr::In(a, values) => a
+                .get(record)
+                .is_some_and(|a| values.iter().any(|v| compare(a, Cmp::Eq, v))),
+            Expr::Matches(a, re) => match a.get(record) {
+                Some(Value::String(s)) => re.is_match(s),
+                Some(Value::Null) | None => false,
+                Some(other) => re.is_match(&other.to_string()),
+            },
+            Expr::Truthy(a) => matches!(a.get(record), Some(Value::Bool(true))),
+        }
+    }
+}
+
+impl Operand {
+    fn get<'a>(&'a self, record: &'a Value) -> Option<&'a Value> {
+        match self {
+            Operand::Path(path) => path.get(record),
+            Operand::Literal(value) => Some(value),
+        }
+    }
+}
+
+fn number(v: &Value) -> Option<f64> {
+    match v {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn compare(a: &Value, cmp: Cmp, b: &Value) -> bool {
+    use std::cmp::Ordering;
+    let ordering = match (a, b) {
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Number(_), _) | (_, Value::Number(_)) => match (number(a), number(b)) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => None,
+        },
+        _ if a == b => Some(Ordering::Equal),
+        _ => None,
+    };
+    match (cmp, ordering) {
+        (Cmp::Eq, o) => o == Some(Ordering::Equal),
+        (Cmp::Ne, o) => o != Some(Ordering::Equal),
+        (_, None) => false,
+        (Cmp::Lt, Some(o)) => o == Ordering::Less,
+        (Cmp::Le, Some(o)) => o != Ordering::Greater,
+        (Cmp::Gt, Some(o)) => o == Ordering::Greater,
+        (Cmp::Ge, Some(o)) => o != Ordering::Less,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(serde_json::Number),
+    Punct(&'static str),
+}
+
+const PUNCTS: &[&str] = &[
+    "==", "!=", "<=", ">=", "=~", "!~", "&&", "||", "<", ">", "!", "(", ")", "[", "]", ",", ".",
+];
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = s.char_indices().peekable();
+    while let Some(&(i, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' || c == '\'' {
+            chars.next();
+            let mut out = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c)) => out.push(c),
+                        None => return Err(FilterError::UnexpectedEnd.into()),
+                    },
+                    Some((_, q)) if q == c => break,
+                    Some((_, c)) => out.push(c),
+                    None => return Err(FilterError::UnexpectedEnd.into()),
+                }
+            }
+            tokens.push(Token::Str(out));
+        } else if c.is_ascii_digit() || c == '-' {
+            let mut end = i + c.len_utf8();
+            chars.next();
+            while let Some(&(j, c)) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '.' || c == '+' || c == '-') {
+                    break;
+                }
+                end = j + c.len_utf8();
+                chars.next();
+            }
+            let n = serde_json::from_str(&s[i..end])
+                .map_err(|_| FilterError::UnexpectedToken(s[i..end].to_string()))?;
+            tokens.push(Token::Num(n));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut out = String::new();
+            while let Some(&(_, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_') {
+                    break;
+                }
+                out.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Ident(out));
+        } else {
+            let punct = PUNCTS
+                .iter()
+                .find(|p| s[i..].starts_with(**p))
+                .ok_or_else(|| FilterError::UnexpectedToken(c.to_string()))?;
+            for _ in 0..punct.len() {
+                chars.next();
+            }
+            tokens.push(Token::Punct(punct));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(s: &str) -> Result<Parser> {
+        Ok(Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self.peek().cloned().ok_or(FilterError::UnexpectedEnd)?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn unexpected(token: &Token) -> Error {
+        FilterError::UnexpectedToken(format!("{:?}", token)).into()
+    }
+
+    fn end(&self) -> Result<()> {
+        match self.peek() {
+            Some(token) => Err(Self::unexpected(token)),
+            None => Ok(()),
+        }
+    }
+
+    /// consumes the next token if it is one of `alternatives`
+    fn accept(&mut self, alternatives: &[&str]) -> bool {
+        let found = match self.peek() {
+            Some(Token::Punct(p)) => alternatives.contains(p),
+            Some(Token::Ident(i)) => alternatives.contains(&i.as_str()),
+            _ => false,
+        };
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<()> {
+        match self.next()? {
+            Token::Punct(p) if p == punct => Ok(()),
+            other => Err(Self::unexpected(&other)),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.accept(&["or", "||"]) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        while self.accept(&["and", "&&"]) {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.accept(&["not", "!"]) {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.accept(&["("]) {
+            let expr = self.or()?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        if self.accept(&["exists"]) {
+            self.expect("(")?;
+            let path = self.path()?;
+            self.expect(")")?;
+            return Ok(Expr::Exists(path));
+        }
+        let a = self.operand()?;
+        let cmp = match self.peek() {
+            Some(Token::Punct("==")) => Cmp::Eq,
+            Some(Token::Punct("!=")) => Cmp::Ne,
+            Some(Token::Punct("<")) => Cmp::Lt,
+            Some(Token::Punct("<=")) => Cmp::Le,
+            Some(Token::Punct(">")) => Cmp::Gt,
+            Some(Token::Punct(">=")) => Cmp::Ge,
+            Some(Token::Punct(p @ ("=~" | "!~"))) => {
+                let negated = *p == "!~";
+                self.pos += 1;
+                let re = match self.next()? {
+                    Token::Str(s) => regex::Regex::new(&s).map_err(FilterError::InvalidRegex)?,
+                    other => return Err(Self::unexpected(&other)),
+                };
+                let expr = Expr::Matches(a, re);
+                return Ok(if negated {
+                    Expr::Not(Box::new(expr))
+                } else {
+                    expr
+                });
+            }
+            Some(Token::Ident(i)) if i == "in" => {
+                self.pos += 1;
+                return Ok(Expr::In(a, self.list()?));
+            }
+            _ => return Ok(Expr::Truthy(a)),
+        };
+        self.pos += 1;
+        Ok(Expr::Cmp(a, cmp, self.operand()?))
+    }
+
+    fn list(&mut self) -> Result<Vec<Value>> {
+        self.expect("[")?;
+        let mut values = vec![];
+        if self.accept(&["]"]) {
+            return Ok(values);
+        }
+        loop {
+            values.push(self.literal()?);
+            if self.accept(&["]"]) {
+                return Ok(values);
+            }
+            self.expect(",")?;
+        }
+    }
+
+    fn literal(&mut self) -> Result<Value> {
+        match self.operand()? {
+            Operand::Literal(value) => Ok(value),
+            Operand::Path(path) => Err(FilterError::UnexpectedToken(format!("{:?}", path)).into()),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand> {
+        match self.peek().cloned().ok_or(FilterError::UnexpectedEnd)? {
+            Token::Str(s) => {
+                self.pos += 1;
+                Ok(Operand::Literal(Value::String(s)))
+            }
+            Token::Num(n) => {
+                self.pos += 1;
+                Ok(Operand::Literal(Value::Number(n)))
+            }
+            Token::Ident(i) if i == "true" || i == "false" => {
+                self.pos += 1;
+                Ok(Operand::Literal(Value::Bool(i == "true")))
+            }
+            Token::Ident(i) if i == "null" => {
+                self.pos += 1;
+                Ok(Operand::Literal(Value::Null))
+            }
+            _ => Ok(Operand::Path(self.path()?)),
+        }
+    }
+
+    fn path(&mut self) -> Result<Path> {
+        let mut segments = match self.next()? {
+            Token::Ident(i) => vec![Segment::Field(i)],
+            other => return Err(Self::unexpected(&other)),
+        };
+        loop {
+            if self.accept(&["."]) {
+                match self.next()? {
+                    Token::Ident(i) => segments.push(Segment::Field(i)),
+                    other => return Err(Self::unexpected(&other)),
+                }
+            } else if self.accept(&["["]) {
+                match self.next()? {
+                    Token::Str(s) => segments.push(Segment::Field(s)),
+                    Token::Num(n) if n.is_u64() => {
+                        segments.push(Segment::Index(n.as_u64().unwrap_or_default() as usize))
+                    }
+                    other => return Err(Self::unexpected(&other)),
+                }
+                self.expect("]")?;
+            } else {
+                return Ok(Path(segments));
+            }
+        }
+    }
+}
+
+/// Items of a `--select` list, which may have commas within brackets and quotes
+fn split_select(s: &str) -> Vec<&str> {
+    let mut items = vec![];
+    let (mut start, mut depth, mut quote) = (0, 0, None);
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                items.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&s[start..]);
+    items
+}
+
+fn is_ident(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Compiled `--where` and `--select`
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    condition: Option<Expr>,
+    select: Vec<(String, Path)>,
+}
+
+impl Filter {
+    /// `select` is a comma separated list of paths, each optionally preceded by `name=`
+    pub fn new(condition: Option<&str>, select: Option<&str>) -> Result<Filter> {
+        let condition = condition.map(Expr::parse).transpose()?;
+        let mut paths = vec![];
+        for item in split_select(select.unwrap_or_default()) {
+            let item = item.trim();
+            if item.is_empty() {
+                continue;
+            }
+            let (name, path) = match item.split_once('=') {
+                Some((name, path)) if is_ident(name.trim()) => (name.trim(), path),
+                _ => (item, item),
+            };
+            paths.push((name.to_string(), Path::parse(path)?));
+        }
+        Ok(Filter {
+            condition,
+            select: paths,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.condition.is_none() && self.select.is_empty()
+    }
+
+    pub fn matches(&self, record: &Value) -> bool {
+        self.condition.as_ref().is_none_or(|c| c.eval(record))
+    }
+
+    /// Output for envelope `record`: nothing if it doesn't match, otherwise the selected fields,
+    /// or the whole envelope or only its value if nothing is selected
+    pub fn apply(&self, mut record: Value, envelope: bool) -> Option<Value> {
+        if !self.matches(&record) {
+            return None;
+        }
+        Some(match self.select(&record) {
+            Some(selected) => selected,
+            None if envelope => record,
+            None => record["value"].take(),
+        })
+    }
+
+    /// The selected fields as an object, None if nothing is selected
+    pub fn select(&self, record: &Value) -> Option<Value> {
+        if self.select.is_empty() {
+            return None;
+        }
+        let mut out = serde_json::Map::new();
+        for (name, path) in &self.select {
+            out.insert(
+                name.clone(),
+                path.get(record).cloned().unwrap_or(Value::Null),
+            );
+        }
+        Some(Value::Object(out))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::filter::{Expr, Filter};
+    use serde_json::json;
+
+    #[test]
+    fn test_filter() {
+        let record = json!({
+            "topic": "orders",
+            "partition": 3,
+            "value": {"orderId": "12345", "status": "PAID", "lines": [{"sku": "a"}], "gift": true}
+        });
+        let eval = |s: &str| Expr::parse(s).unwrap().eval(&record);
+        assert!(eval("value.orderId == 12345"));
+        assert!(eval("value.orderId == '12345' && topic != \"payments\""));
+        assert!(eval("partition in [1, 3] and value.lines[0].sku == 'a'"));
+        assert!(eval("value[\"status\"] =~ '^(NEW|PAID)$'"));
+        assert!(eval("not exists(value.missing) or partition > 5"));
+        assert!(eval("value.gift and partition >= 3"));
+        assert!(!eval("value.missing == null"));
+        assert!(!eval("value.status !~ 'PAI' || partition < 3"));
+        assert!(Expr::parse("value.orderId ==").is_err());
+        assert!(Expr::parse("partition in [1").is_err());
+        assert!(Expr::parse("topic =~ '('").is_err());
+
+        let filter = Filter::new(
+            Some("topic == 'orders'"),
+            Some("id=value.orderId, partition"),
+        )
+        .unwrap();
+        assert!(filter.matches(&record));
+        assert_eq!(
+            filter.select(&record).unwrap(),
+            json!({"id": "12345", "partition": 3})
+        );
+
+        let record = json!({"value": {"a,b": 1, "c=d": 2, "e": [3]}});
+        let filter =
+            Filter::new(None, Some(r#"value["a,b"], x=value['c=d'], value.e[0]"#)).unwrap();
+        assert_eq!(
+            filter.select(&record).unwrap(),
+            json!({r#"value["a,b"]"#: 1, "x": 2, "value.e[0]": 3})
+        );
+    }
+}
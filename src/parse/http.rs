@@ -0,0 +1,477 @@
+//! HTTP service decoding and encoding confluent framed protobuf with the schemas of a
+//! `Proto2Json`, for tools that can't run the CLI.
+//!
+//! - `POST /decode` takes the raw message as body, or with `Content-Type: application/json` a
+//!   `DecodeRequest` holding base64 encoded bytes, and answers with the decoded envelopes
+//! - `POST /encode` takes an `EncodeRequest` and answers with the base64 encoded message
+//! - `GET /schemas/{id}/descriptor` answers with the compiled schema and its imports as a JSON
+//!   `FileDescriptorSet`
+//! - `GET /topics/{topic}/tail` streams the topic's decoded records as server-sent events, see
+//!   `TailQuery` for its parameters. Records are `record` events whose id is
+//!   `<partition>:<offset>`, failures are `error` events. Only served with brokers configured,
+//!   and answered with 503 while `Tail::max_tails` tails are followed.
+//! - `GET /metrics` answers with the metrics of the `Proto2Json` in the prometheus text format,
+//!   if it has any
+//!
+//! Failures are answered with an `ErrorResponse`.
+use crate::parse::envelope::Envelope;
+use crate::parse::error::*;
+use crate::parse::filter;
+use crate::parse::kafka;
+use crate::parse::kafka::{ConsumerError, FollowIter, KafkaOptions, Start};
+use crate::parse::metrics;
+use crate::parse::msg::{Msg, ParsedKey};
+use crate::parse::proto2json::Proto2Json;
+use crate::parse::protobuf::ProtobufError;
+use base64::Engine;
+use protobuf::descriptor::FileDescriptorSet;
+use protobuf::reflect::FileDescriptor;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::Sender;
+use tracing::{debug, info, warn};
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::reply::Response;
+use warp::sse::Event;
+use warp::{Filter, Reply};
+
+/// Largest request body accepted, in bytes
+const MAX_BODY_LEN: u64 = 16 * 1024 * 1024;
+
+/// Records waiting to be sent to a tail's client, a slow client slows down its consumer
+const TAIL_QUEUE_LEN: usize = 1024;
+
+/// Cloned by every request, the clones share the schemas compiled on first use
+pub type Shared = Arc<Proto2Json>;
+
+/// Brokers topics are tailed from
+#[derive(Debug, Clone)]
+pub struct Tail {
+    pub brokers: String,
+    pub options: KafkaOptions,
+    /// tails followed at the same time, each has its own consumer and thread
+    pub max_tails: usize,
+}
+
+/// One of the `max_tails` tails, given back when its consumer stops
+struct TailSlot(Arc<AtomicUsize>);
+
+impl TailSlot {
+    fn take(active: &Arc<AtomicUsize>, max: usize) -> Result<Self> {
+        active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .map_err(|_| Error::TooManyTails(max))?;
+        Ok(TailSlot(active.clone()))
+    }
+}
+
+impl Drop for TailSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct TailQuery {
+    /// `beginning`, `end`, an offset, `-N` for the N last messages of every partition or
+    /// `@millis` for the first ones at or after a timestamp. `end` if unset.
+    pub from: Option<String>,
+    /// comma separated partitions, all if unset
+    pub partitions: Option<String>,
+    /// only records matching this `--where` expression
+    #[serde(rename = "where")]
+    pub condition: Option<String>,
+    /// `--select` paths sent instead of the whole record
+    pub select: Option<String>,
+    /// `value` for decoded values only, envelopes if unset
+    pub output: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DecodeRequest {
+    /// base64 encoded message
+    pub value: String,
+    /// base64 encoded key, decoded as well when it is confluent framed
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub topic: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct EncodeRequest {
+    pub schema_id: i32,
+    /// first message of the schema if unset
+    #[serde(default)]
+    pub message_type: Option<String>,
+    pub value: serde_json::Value,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct EncodeResponse {
+    /// base64 encoded confluent framed message
+    pub value: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ErrorResponse {
+    /// `Error::kind` of the failure
+    pub error: String,
+    pub message: String,
+}
+
+fn base64_decode(s: &str) -> Result<Vec<u8>> {
+    Ok(base64::engine::general_purpose::STANDARD
+        .decode(s)
+        .or_else(|_| base64::engine::general_purpose::URL_SAFE.decode(s))?)
+}
+
+fn request_msg(topic: String, key: Option<Vec<u8>>, value: Vec<u8>) -> Msg {
+    let key = ParsedKey::from_option(key.as_deref());
+    Msg {
+        topic,
+        partition: -1,
+        offset: -1,
+        ts: -1,
+        key_len: key.len(),
+        key,
+        msg_len: value.len(),
+        msg: value,
+        headers: vec![],
+    }
+}
+
+pub fn decode(p: &mut Proto2Json, json: bool, body: &[u8]) -> Result<Vec<Envelope>> {
+    let msg = if json {
+        let request: DecodeRequest = serde_json::from_slice(body)?;
+        let key = request.key.as_deref().map(base64_decode).transpose()?;
+        request_msg(request.topic, key, base64_decode(&request.value)?)
+    } else {
+        request_msg(String::new(), None, body.to_vec())
+    };
+    p.envelopes(&msg)
+}
+
+pub fn encode(p: &mut Proto2Json, body: &[u8]) -> Result<EncodeResponse> {
+    let request: EncodeRequest = serde_json::from_slice(body)?;
+    let bytes = p.encode(
+        request.schema_id,
+        request.message_type.as_deref(),
+        &request.value,
+    )?;
+    Ok(EncodeResponse {
+        value: base64::engine::general_purpose::STANDARD.encode(bytes),
+    })
+}
+
+/// The schema's file and its imports, imports first
+pub fn descriptor_set(p: &mut Proto2Json, schema_id: i32) -> Result<FileDescriptorSet> {
+    fn add(fd: &FileDescriptor, set: &mut FileDescriptorSet) {
+        if set.file.iter().any(|f| f.name() == fd.name()) {
+            return;
+        }
+        fd.deps().iter().for_each(|dep| add(dep, set));
+        set.file.push(fd.proto().clone());
+    }
+    let mut set = FileDescriptorSet::new();
+    add(&p.descriptor(schema_id)?, &mut set);
+    Ok(set)
+}
+
+fn status(e: &Error) -> StatusCode {
+    match e.root() {
+        Error::Protobuf(ProtobufError::SchemaNotFound(_))
+        | Error::Protobuf(ProtobufError::MessageTypeNotFound(..))
+        | Error::Consumer(ConsumerError::UnknownTopic(_)) => StatusCode::NOT_FOUND,
+        Error::NeedAtLeastOneBrokerHostname => StatusCode::NOT_IMPLEMENTED,
+        Error::TooManyTails(_) => StatusCode::SERVICE_UNAVAILABLE,
+        Error::IoError(_) | Error::Reqwest(_) | Error::RegistryFailed(_) => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        Error::Kafka(_) | Error::Consumer(_) => StatusCode::BAD_GATEWAY,
+        _ => StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+fn error_response(e: &Error) -> ErrorResponse {
+    ErrorResponse {
+        error: e.kind().to_string(),
+        message: report(e),
+    }
+}
+
+fn respond<T: serde::Serialize>(result: Result<T>) -> Response {
+    match result {
+        Ok(body) => warp::reply::json(&body).into_response(),
+        Err(e) => {
+            debug!("request failed: {:?}", e);
+            warp::reply::with_status(warp::reply::json(&error_response(&e)), status(&e))
+                .into_response()
+        }
+    }
+}
+
+/// Runs `f` off the runtime's threads, decoding compiles schemas and writes them to disk. Each
+/// request has its own clone of `p`, a slow schema fetch doesn't hold up the others.
+async fn blocking<T, F>(p: Shared, f: F) -> std::result::Result<Response, warp::Rejection>
+where
+    T: serde::Serialize + Send + 'static,
+    F: FnOnce(&mut Proto2Json) -> Result<T> + Send + 'static,
+{
+    let result = tokio::task::spawn_blocking(move || f(&mut (*p).clone()))
+        .await
+        .map_err(|e| Error::IoError(e.into()))
+        .and_then(|result| result);
+    Ok(respond(result))
+}
+
+/// Consumer and decoder of a tail, on its own thread until its client goes away
+fn follow(
+    mut p: Proto2Json,
+    it: FollowIter,
+    filter: filter::Filter,
+    envelope: bool,
+    tx: Sender<Event>,
+    _slot: TailSlot,
+) {
+    let error = |e: &Error| Event::default().event("error").json_data(error_response(e));
+    let metrics = p.metrics().cloned();
+    for item in it {
+        // records the filter drops are never sent, which would go on without noticing
+        if tx.is_closed() {
+            debug!("tail client went away");
+            break;
+        }
+        let msg = match item {
+            Ok(msg) => msg,
+            Err(Error::Consumer(e)) if !e.is_fatal() => continue,
+            Err(e) => {
+                warn!("tail stopped: {:?}", e);
+                if let Ok(event) = error(&e) {
+                    let _ = tx.blocking_send(event);
+                }
+                break;
+            }
+        };
+        let id = format!("{}:{}", msg.partition, msg.offset);
+        let records = p.envelopes(&msg).and_then(|envelopes| {
+            envelopes
+                .into_iter()
+                .map(|e| Ok(filter.apply(e.into_json()?, envelope)))
+                .collect::<Result<Vec<_>>>()
+        });
+        if let Some(metrics) = &metrics {
+            metrics.consumed(&msg);
+            match &records {
+                Ok(_) => metrics.decoded(&msg),
+                Err(e) => metrics.failed(&msg, e),
+            }
+        }
+        let events = match records {
+            Ok(records) => records
+                .into_iter()
+                .flatten()
+                .map(|r| Event::default().event("record").id(&id).data(r.to_string()))
+                .collect(),
+            Err(e) => error(&e)
+                .map(|event| vec![event.id(&id)])
+                .unwrap_or_default(),
+        };
+        for event in events {
+            if tx.blocking_send(event).is_err() {
+                debug!("tail client went away");
+                return;
+            }
+        }
+    }
+}
+
+/// Starts the consumer, which happens before the response so that unknown topics are reported
+/// with a status
+async fn tail(
+    p: Shared,
+    tail: Option<Tail>,
+    active: Arc<AtomicUsize>,
+    topic: String,
+    query: TailQuery,
+) -> std::result::Result<Response, warp::Rejection> {
+    let started = tokio::task::spawn_blocking(move || {
+        let tail = tail.ok_or(Error::NeedAtLeastOneBrokerHostname)?;
+        let slot = TailSlot::take(&active, tail.max_tails)?;
+        let filter = filter::Filter::new(query.condition.as_deref(), query.select.as_deref())?;
+        let start = match &query.from {
+            Some(from) => from.parse()?,
+            None => Start::End,
+        };
+        let partitions = query
+            .partitions
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|p| !p.trim().is_empty())
+            .map(|p| Ok(p.trim().parse()?))
+            .collect::<Result<Vec<i32>>>()?;
+        let p = (*p).clone();
+        let metrics = p.metrics().cloned();
+        let (brokers, options) = (&tail.brokers, &tail.options);
+        let it = kafka::read_kafka_tail(brokers, &topic, &partitions, start, options, metrics)?;
+        let envelope = query.output.as_deref() != Some("value");
+        let (tx, rx) = mpsc::channel(TAIL_QUEUE_LEN);
+        std::thread::Builder::new()
+            .name(format!("tail-{}", topic))
+            .spawn(move || follow(p, it, filter, envelope, tx, slot))?;
+        Ok(rx)
+    })
+    .await
+    .map_err(|e| Error::IoError(e.into()))
+    .and_then(|started| started);
+    let rx = match started {
+        Ok(rx) => rx,
+        Err(e) => return Ok(respond::<()>(Err(e))),
+    };
+    let events = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|event| (Ok::<_, Infallible>(event), rx))
+    });
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response())
+}
+
+pub fn routes(
+    p: Shared,
+    tail: Option<Tail>,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    let metrics = p.metrics().cloned();
+    let shared = warp::any().map(move || p.clone());
+    let decode_route = warp::path!("decode")
+        .and(warp::post())
+        .and(shared.clone())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::content_length_limit(MAX_BODY_LEN))
+        .and(warp::body::bytes())
+        .and_then(|p: Shared, content_type: Option<String>, body: Bytes| {
+            let json = content_type.is_some_and(|t| t.starts_with("application/json"));
+            blocking(p, move |p| decode(p, json, &body))
+        });
+    let encode_route = warp::path!("encode")
+        .and(warp::post())
+        .and(shared.clone())
+        .and(warp::body::content_length_limit(MAX_BODY_LEN))
+        .and(warp::body::bytes())
+        .and_then(|p: Shared, body: Bytes| blocking(p, move |p| encode(p, &body)));
+    let descriptor_route = warp::path!("schemas" / i32 / "descriptor")
+        .and(warp::get())
+        .and(shared.clone())
+        .and_then(|schema_id: i32, p: Shared| {
+            blocking(p, move |p| {
+                let set = descriptor_set(p, schema_id)?;
+                let json = protobuf_json_mapping::print_to_string(&set)?;
+                Ok(serde_json::from_str::<serde_json::Value>(&json)?)
+            })
+        });
+    let active = Arc::new(AtomicUsize::new(0));
+    let tail_route = warp::path!("topics" / String / "tail")
+        .and(warp::get())
+        .and(shared.clone())
+        .and(warp::any().map(move || tail.clone()))
+        .and(warp::any().map(move || active.clone()))
+        .and(warp::query::<TailQuery>())
+        .and_then(
+            |topic: String, p: Shared, t: Option<Tail>, a: Arc<AtomicUsize>, query: TailQuery| {
+                self::tail(p, t, a, topic, query)
+            },
+        );
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
+        .map(move || match &metrics {
+            Some(metrics) => metrics::response(metrics),
+            None => StatusCode::NOT_FOUND.into_response(),
+        });
+    decode_route
+        .or(encode_route)
+        .unify()
+        .or(descriptor_route)
+        .unify()
+        .or(tail_route)
+        .unify()
+        .or(metrics_route)
+        .unify()
+}
+
+/// Serves until the process is stopped. `p` has to be loaded beforehand, schemas are fetched
+/// with a blocking client which can't run on the server's runtime. Topics can only be tailed
+/// with `tail`.
+pub fn serve(p: Proto2Json, addr: SocketAddr, tail: Option<Tail>) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let routes = routes(Arc::new(p), tail).with(warp::log("proto2json::http"));
+    info!(addr = addr.to_string(), "serving");
+    runtime.block_on(warp::serve(routes).run(addr));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::confluent::Schema;
+    use crate::parse::http::{routes, ErrorResponse, Tail};
+    use crate::parse::proto2json::Proto2Json;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_decode_json() {
+        let p = Proto2Json::new(Arc::new(Vec::<Schema>::new()), None, vec![]);
+        let routes = routes(Arc::new(p), None);
+        let res = warp::test::request()
+            .method("POST")
+            .path("/decode")
+            .body(r#"{"a":1}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body[0]["value"], serde_json::json!({"a": 1}));
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/decode")
+            .header("content-type", "application/json")
+            .body(r#"{"value":"AAAAAAEAAQ=="}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 404);
+        let body: ErrorResponse = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body.error, "SchemaNotFound");
+
+        // tails need brokers
+        let res = warp::test::request()
+            .path("/topics/orders/tail?from=-10")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 501);
+    }
+
+    #[tokio::test]
+    async fn test_too_many_tails() {
+        let p = Proto2Json::new(Arc::new(Vec::<Schema>::new()), None, vec![]);
+        let tail = Tail {
+            brokers: "localhost:9".into(),
+            options: Default::default(),
+            max_tails: 0,
+        };
+        let routes = routes(Arc::new(p), Some(tail));
+        let res = warp::test::request()
+            .path("/topics/orders/tail")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), 503);
+        let body: ErrorResponse = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body.error, "TooManyTails");
+    }
+}
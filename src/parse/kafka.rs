@@ -1,22 +1,127 @@
+use crate::parse::confluent::peek_schema_id;
+use crate::parse::error::*;
+use crate::parse::metrics::Metrics;
 use crate::parse::msg::{Msg, ParsedKey};
 use fnv::{FnvHashMap, FnvHashSet};
 use itertools::Itertools;
 use rdkafka::admin::AdminClient;
-use rdkafka::consumer::{BaseConsumer, Consumer, DefaultConsumerContext};
-use rdkafka::message::BorrowedMessage;
+use futures::StreamExt;
+use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer, DefaultConsumerContext, StreamConsumer};
+use rdkafka::consumer::ConsumerContext;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::{BorrowedMessage, Header, Headers, OwnedHeaders};
+use rdkafka::producer::{BaseProducer, BaseRecord, Producer as _};
+use rdkafka::statistics::Statistics;
 use rdkafka::util::Timeout;
+use rdkafka::ClientContext;
 use rdkafka::Offset;
 use rdkafka::{Message, TopicPartitionList};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::mpsc::Sender;
 
-use tracing::info;
+use tracing::{info, warn};
 use uuid::Uuid;
 
 const MAX_RETRIES: usize = 10;
 
+/// Statistics of following consumers, and the consumer lag in them, are emitted this often
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum ConsumerError {
+    /// message from a partition that wasn't assigned, the message is skipped
+    UnknownPartition(String, i32),
+    /// non transient broker error or transient one that persisted after all retries
+    Fatal(KafkaError),
+    /// nothing arrived within a poll of a consumer that never ends, pending output can be flushed
+    Idle,
+    /// offsets can only be committed in a transaction by a consumer that joined its group
+    NotInGroup,
+    /// topic without partitions, or none of the requested ones
+    UnknownTopic(String),
+}
+
+impl ConsumerError {
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            ConsumerError::Fatal(_) | ConsumerError::NotInGroup | ConsumerError::UnknownTopic(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KafkaOptions {
+    /// timeout of metadata and watermark requests
+    pub request_timeout: Duration,
+    /// consecutive empty polls before the topics are assumed to be fully read
+    pub max_idle_polls: usize,
+    /// consecutive transient errors tolerated before giving up
+    pub max_error_retries: usize,
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for KafkaOptions {
+    fn default() -> Self {
+        KafkaOptions {
+            request_timeout: Duration::from_secs(30),
+            max_idle_polls: MAX_RETRIES,
+            max_error_retries: MAX_RETRIES,
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Errors worth retrying, broker restarts and leader elections mostly
+pub(crate) fn is_transient(e: &KafkaError) -> bool {
+    matches!(
+        e.rdkafka_error_code(),
+        Some(
+            RDKafkaErrorCode::BrokerTransportFailure
+                | RDKafkaErrorCode::Resolve
+                | RDKafkaErrorCode::AllBrokersDown
+                | RDKafkaErrorCode::OperationTimedOut
+                | RDKafkaErrorCode::RequestTimedOut
+                | RDKafkaErrorCode::NetworkException
+                | RDKafkaErrorCode::LeaderNotAvailable
+                | RDKafkaErrorCode::NotLeaderForPartition
+                | RDKafkaErrorCode::CoordinatorNotAvailable
+                | RDKafkaErrorCode::NotCoordinator
+        )
+    )
+}
+
+pub(crate) struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new(options: &KafkaOptions) -> Self {
+        Backoff {
+            min: options.min_backoff,
+            max: options.max_backoff,
+            current: options.min_backoff,
+        }
+    }
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = std::cmp::min(self.current * 2, self.max);
+        delay
+    }
+    pub(crate) fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
+
 #[derive(Debug, Clone)]
-struct Watermark {
+pub(crate) struct Watermark {
     lo: i64,
     hi: i64,
     received: i64,
@@ -24,14 +129,18 @@ struct Watermark {
 
 struct Iter {
     consumer: BaseConsumer<DefaultConsumerContext>,
-    positions: HashMap<(String, i32), Vec<Offset>>,
     watermarks: fnv::FnvHashMap<(String, i32), Watermark>,
+    options: KafkaOptions,
+    backoff: Backoff,
     attempts: usize,
     messages_received: usize,
     retries: usize,
+    error_retries: usize,
+    errors: usize,
+    done: bool,
 }
 
-fn parse(m: BorrowedMessage) -> Msg {
+pub(crate) fn parse(m: BorrowedMessage) -> Msg {
     let key = m
         .key()
         .map(|bytes| ParsedKey::new(bytes))
@@ -39,15 +148,56 @@ fn parse(m: BorrowedMessage) -> Msg {
     let msg = m.payload().map(|bytes| bytes.to_vec()).unwrap_or(vec![]);
     let key_len = key.len();
     let msg_len = msg.len();
+    let headers = m
+        .headers()
+        .map(|hs| {
+            hs.iter()
+                .map(|h| (h.key.to_string(), ParsedKey::from_option(h.value)))
+                .collect()
+        })
+        .unwrap_or_default();
     Msg {
         topic: m.topic().to_string(),
         partition: m.partition() as i64,
         offset: m.offset() as i64,
-        ts: m.timestamp().to_millis().unwrap(),
+        ts: m.timestamp().to_millis().unwrap_or(-1),
         key,
         key_len,
         msg,
         msg_len,
+        headers,
+    }
+}
+
+/// Logged at warn, visible at the default verbosity, if the consumer stopped early or had errors
+fn log_summary(watermarks: &Watermarks, messages_received: usize, errors: usize, fatal: bool) {
+    let warn = fatal || errors > 0;
+    for ((topic, partition), wm) in watermarks.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+        let (received, expected) = (wm.received, wm.hi - wm.lo);
+        if warn {
+            warn!(topic = topic.as_str(), partition, received, expected);
+        } else {
+            info!(topic = topic.as_str(), partition, received, expected);
+        }
+    }
+    if fatal {
+        warn!(
+            messages_received = messages_received,
+            errors = errors,
+            "kafka consumer stopped on a fatal error"
+        );
+    } else if warn {
+        warn!(
+            messages_received = messages_received,
+            errors = errors,
+            "kafka consumer finished with errors"
+        );
+    } else {
+        info!(
+            messages_received = messages_received,
+            errors = errors,
+            "kafka consumer finished"
+        );
     }
 }
 
@@ -56,45 +206,82 @@ impl Iter {
         let mut missing = self.watermarks.len();
         for tp in tpl.elements() {
             if let Offset::Offset(offset) = tp.offset() {
-                let wm = self
-                    .watermarks
-                    .get(&(tp.topic().to_owned(), tp.partition()))
-                    .unwrap();
-                if (offset >= wm.hi) {
-                    missing -= 1;
+                match self.watermarks.get(&(tp.topic().to_owned(), tp.partition())) {
+                    Some(wm) if offset >= wm.hi => missing -= 1,
+                    _ => {}
                 }
             }
         }
         missing
     }
+
+    fn finish(&mut self) -> Option<Result<Msg>> {
+        self.done = true;
+        log_summary(&self.watermarks, self.messages_received, self.errors, false);
+        None
+    }
+
+    /// Sleeps and returns Ok if `e` is worth another try, otherwise ends the iterator
+    fn retry(&mut self, e: KafkaError) -> Result<()> {
+        self.errors += 1;
+        if is_transient(&e) && self.error_retries > 0 {
+            self.error_retries -= 1;
+            let delay = self.backoff.next_delay();
+            warn!(
+                retries = self.error_retries,
+                delay_ms = delay.as_millis() as u64,
+                "transient kafka error: {:?}",
+                e
+            );
+            std::thread::sleep(delay);
+            Ok(())
+        } else {
+            self.done = true;
+            log_summary(&self.watermarks, self.messages_received, self.errors, true);
+            Err(ConsumerError::Fatal(e).into())
+        }
+    }
 }
 
 impl Iterator for Iter {
-    type Item = Msg;
+    type Item = Result<Msg>;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.retries == 0 {
+        if self.done {
             return None;
         }
         loop {
             self.attempts += 1;
-            let missing = self.count_missing(self.consumer.position().unwrap());
-            if (missing == 0) {
-                return None;
+            let missing = match self.consumer.position() {
+                Ok(tpl) => self.count_missing(tpl),
+                Err(e) => match self.retry(e) {
+                    Ok(()) => continue,
+                    Err(e) => return Some(Err(e)),
+                },
+            };
+            if missing == 0 {
+                return self.finish();
             }
             let r = self.consumer.poll(Duration::from_secs(1));
             match r {
                 Some(Ok(msg)) => {
-                    self.retries = MAX_RETRIES;
+                    self.retries = self.options.max_idle_polls;
+                    self.error_retries = self.options.max_error_retries;
+                    self.backoff.reset();
                     self.messages_received += 1;
                     let key = (msg.topic().to_owned(), msg.partition());
-                    if let Some(mut wm) = self.watermarks.get_mut(&key) {
-                        wm.received += 1;
-                    } else {
-                        panic!("Received message on unknown topic partition: {:?}", key);
+                    match self.watermarks.get_mut(&key) {
+                        Some(wm) => wm.received += 1,
+                        None => {
+                            return Some(Err(ConsumerError::UnknownPartition(key.0, key.1).into()))
+                        }
+                    }
+                    return Some(Ok(parse(msg)));
+                }
+                Some(Err(e)) => {
+                    if let Err(e) = self.retry(e) {
+                        return Some(Err(e));
                     }
-                    return Some(parse(msg));
                 }
-                Some(Err(e)) => panic!("rdkafka error: {:?}", e),
                 None if self.retries > 0 => {
                     info!(missing = missing, retries = self.retries);
                     self.retries -= 1;
@@ -102,15 +289,14 @@ impl Iterator for Iter {
                 None => {
                     info!(missing = missing, retries = self.retries);
                     info!("exiting kafka poll loop");
-                    break;
+                    return self.finish();
                 }
             };
         }
-        None
     }
 }
 
-pub fn list_topics(servers_csv: &str) -> impl Iterator<Item = (String, Vec<i32>)> {
+pub(crate) fn client_config(servers_csv: &str) -> rdkafka::config::ClientConfig {
     let mut config = rdkafka::config::ClientConfig::new();
     config.set("bootstrap.servers", servers_csv);
     config.set("enable.auto.commit", "false");
@@ -118,14 +304,417 @@ pub fn list_topics(servers_csv: &str) -> impl Iterator<Item = (String, Vec<i32>)
     config.set("group.id", Uuid::new_v4().to_string().as_str());
     config.set("auto.offset.reset", "beginning");
     config.set("debug", "all");
-    let client: BaseConsumer = config.create().unwrap();
-    let md = client.fetch_metadata(None, Timeout::Never).unwrap();
+    config
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PartitionInfo {
+    pub partition: i32,
+    pub lo: i64,
+    pub hi: i64,
+    /// approximate, compaction and transaction markers are not accounted for
+    pub count: i64,
+    /// schema id -> number of sampled records carrying it
+    pub sampled_schema_ids: BTreeMap<i32, usize>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct TopicInfo {
+    pub name: String,
+    pub partitions: Vec<PartitionInfo>,
+}
+
+impl TopicInfo {
+    pub fn count(&self) -> i64 {
+        self.partitions.iter().map(|p| p.count).sum()
+    }
+}
+
+pub fn list_topics(servers_csv: &str) -> Result<impl Iterator<Item = TopicInfo>> {
+    let config = client_config(servers_csv);
+    let timeout = KafkaOptions::default().request_timeout;
+    let client: BaseConsumer = config.create()?;
+    let md = client
+        .fetch_metadata(None, timeout)
+        .with_context(|| format!("fetching topics from {}", servers_csv))?;
     let mut out = vec![];
     for x in md.topics() {
-        let p = x.partitions().into_iter().map(|p| p.id()).collect_vec();
-        out.push((x.name().to_string(), p))
+        let mut partitions = vec![];
+        for p in x.partitions() {
+            let (lo, hi) = client
+                .fetch_watermarks(x.name(), p.id(), timeout)
+                .with_context(|| format!("fetching offsets of {}/{}", x.name(), p.id()))?;
+            partitions.push(PartitionInfo {
+                partition: p.id(),
+                lo,
+                hi,
+                count: hi - lo,
+                sampled_schema_ids: BTreeMap::new(),
+            });
+        }
+        out.push(TopicInfo {
+            name: x.name().to_string(),
+            partitions,
+        })
+    }
+    Ok(out.into_iter())
+}
+
+/// Reads up to `n` last records of every partition and counts confluent schema ids found in them
+pub fn sample_schema_ids(servers_csv: &str, topics: &mut [TopicInfo], n: usize) -> Result<()> {
+    if n == 0 {
+        return Ok(());
+    }
+    let consumer: BaseConsumer = client_config(servers_csv).create()?;
+    for topic in topics.iter_mut() {
+        for p in topic.partitions.iter_mut() {
+            if p.hi <= p.lo {
+                continue;
+            }
+            let start = std::cmp::max(p.lo, p.hi - n as i64);
+            let mut tpl = TopicPartitionList::new();
+            tpl.add_partition_offset(&topic.name, p.partition, Offset::Offset(start))?;
+            consumer.assign(&tpl)?;
+            let mut retries = MAX_RETRIES;
+            while retries > 0 {
+                match consumer.poll(Duration::from_secs(1)) {
+                    Some(Ok(msg)) => {
+                        if let Some(id) = msg.payload().and_then(peek_schema_id) {
+                            *p.sampled_schema_ids.entry(id).or_default() += 1;
+                        }
+                        if msg.offset() >= p.hi - 1 {
+                            break;
+                        }
+                    }
+                    Some(Err(e)) => {
+                        warn!(
+                            topic = topic.name.as_str(),
+                            partition = p.partition,
+                            "sampling failed: {:?}",
+                            e
+                        );
+                        break;
+                    }
+                    None => retries -= 1,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub struct Producer {
+    producer: BaseProducer,
+    timeout: Timeout,
+}
+
+impl Producer {
+    pub fn new(servers_csv: &str) -> Result<Self> {
+        let mut config = rdkafka::config::ClientConfig::new();
+        config.set("bootstrap.servers", servers_csv);
+        Ok(Producer {
+            producer: config.create()?,
+            timeout: Timeout::Never,
+        })
+    }
+
+    /// Producer whose records only become visible once committed, with a transaction begun
+    pub fn transactional(
+        servers_csv: &str,
+        transactional_id: &str,
+        options: &KafkaOptions,
+    ) -> Result<Self> {
+        let mut config = rdkafka::config::ClientConfig::new();
+        config.set("bootstrap.servers", servers_csv);
+        config.set("transactional.id", transactional_id);
+        let producer: BaseProducer = config.create()?;
+        producer.init_transactions(options.request_timeout)?;
+        producer.begin_transaction()?;
+        Ok(Producer {
+            producer,
+            timeout: options.request_timeout.into(),
+        })
+    }
+
+    /// Commits the records sent in the current transaction together with the group's `next`
+    /// offsets, and begins the next transaction. Nothing is committed if this fails, and the
+    /// group seeks back to the `first` offsets of the transaction's records to consume them
+    /// again.
+    pub fn commit(
+        &self,
+        first: &BTreeMap<(String, i32), i64>,
+        next: &BTreeMap<(String, i32), i64>,
+        group: &GroupConsumer,
+    ) -> Result<()> {
+        let mut tpl = TopicPartitionList::new();
+        for ((topic, partition), offset) in next {
+            tpl.add_partition_offset(topic, *partition, Offset::Offset(*offset))?;
+        }
+        let result = group
+            .group_metadata()
+            .ok_or_else(|| ConsumerError::NotInGroup.into())
+            .and_then(|metadata| {
+                self.producer
+                    .send_offsets_to_transaction(&tpl, &metadata, self.timeout)?;
+                Ok(self.producer.commit_transaction(self.timeout)?)
+            });
+        if let Err(e) = result {
+            warn!("aborting transaction: {:?}", e);
+            let aborted = self.producer.abort_transaction(self.timeout);
+            // the group's position is past the aborted records, later transactions would
+            // commit offsets beyond them
+            for ((topic, partition), offset) in first {
+                group.seek(topic, *partition, Offset::Offset(*offset), self.timeout)?;
+            }
+            aborted?;
+            self.producer.begin_transaction()?;
+            return Err(e);
+        }
+        Ok(self.producer.begin_transaction()?)
     }
-    out.into_iter()
+
+    pub fn send(
+        &self,
+        topic: &str,
+        partition: Option<i32>,
+        key: &ParsedKey,
+        headers: &[(String, ParsedKey)],
+        payload: &[u8],
+    ) -> Result<()> {
+        let mut owned_headers = OwnedHeaders::new_with_capacity(headers.len());
+        for (k, v) in headers {
+            owned_headers = owned_headers.insert(Header {
+                key: k.as_str(),
+                value: v.as_bytes(),
+            });
+        }
+        let mut record: BaseRecord<[u8], [u8]> = BaseRecord::to(topic)
+            .payload(payload)
+            .headers(owned_headers);
+        if let Some(key) = key.as_bytes() {
+            record = record.key(key);
+        }
+        if let Some(partition) = partition {
+            record = record.partition(partition);
+        }
+        loop {
+            match self.producer.send(record) {
+                Ok(()) => break,
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), r)) => {
+                    self.producer.poll(Duration::from_millis(100));
+                    record = r;
+                }
+                Err((e, _)) => return Err(e.into()),
+            }
+        }
+        self.producer.poll(Duration::ZERO);
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        Ok(self.producer.flush(Timeout::Never)?)
+    }
+}
+
+/// Context of the consumers that follow partitions, reporting their consumer lag from the
+/// statistics librdkafka emits every `STATS_INTERVAL`
+#[derive(Default)]
+pub struct LagContext {
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl ClientContext for LagContext {
+    fn stats(&self, statistics: Statistics) {
+        let metrics = match &self.metrics {
+            Some(metrics) => metrics,
+            None => return,
+        };
+        for (topic, t) in &statistics.topics {
+            for (partition, p) in &t.partitions {
+                // partition -1 is internal, the lag is -1 until the high watermark is known
+                if *partition >= 0 && p.consumer_lag >= 0 {
+                    metrics.lag(topic, *partition as i64, p.consumer_lag);
+                }
+            }
+        }
+    }
+}
+
+impl ConsumerContext for LagContext {}
+
+/// Consumer following partitions, with statistics if there are `metrics` for its lag
+fn following_consumer(
+    mut config: rdkafka::config::ClientConfig,
+    metrics: Option<Arc<Metrics>>,
+) -> Result<BaseConsumer<LagContext>> {
+    if metrics.is_some() {
+        let interval = STATS_INTERVAL.as_millis().to_string();
+        config.set("statistics.interval.ms", interval);
+    }
+    Ok(config.create_with_context(LagContext { metrics })?)
+}
+
+/// Consumer that joined a consumer group, shared with the producer committing its offsets
+pub type GroupConsumer = Arc<BaseConsumer<LagContext>>;
+
+/// Commits the group's `next` offsets, outside of any transaction
+pub fn commit_offsets(group: &GroupConsumer, next: &BTreeMap<(String, i32), i64>) -> Result<()> {
+    let mut tpl = TopicPartitionList::new();
+    for ((topic, partition), offset) in next {
+        tpl.add_partition_offset(topic, *partition, Offset::Offset(*offset))?;
+    }
+    Ok(group.commit(&tpl, CommitMode::Sync)?)
+}
+
+/// Messages read without end, by a consumer group from its committed offsets on or by a tail
+/// from its start
+pub struct FollowIter {
+    consumer: GroupConsumer,
+    options: KafkaOptions,
+    backoff: Backoff,
+    error_retries: usize,
+}
+
+impl Iterator for FollowIter {
+    type Item = Result<Msg>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.consumer.poll(Duration::from_secs(1)) {
+                Some(Ok(msg)) => {
+                    self.error_retries = self.options.max_error_retries;
+                    self.backoff.reset();
+                    return Some(Ok(parse(msg)));
+                }
+                Some(Err(e)) if is_transient(&e) && self.error_retries > 0 => {
+                    self.error_retries -= 1;
+                    let delay = self.backoff.next_delay();
+                    warn!(
+                        retries = self.error_retries,
+                        delay_ms = delay.as_millis() as u64,
+                        "transient kafka error: {:?}",
+                        e
+                    );
+                    std::thread::sleep(delay);
+                }
+                Some(Err(e)) => return Some(Err(ConsumerError::Fatal(e).into())),
+                None => return Some(Err(ConsumerError::Idle.into())),
+            }
+        }
+    }
+}
+
+/// Consumes `user_topics` as member of `group_id`. Offsets are not committed automatically,
+/// committing them is up to the producer of the output. The lag is reported to `metrics`.
+pub fn read_kafka_group(
+    servers_csv: &str,
+    user_topics: &[&str],
+    group_id: &str,
+    options: &KafkaOptions,
+    metrics: Option<Arc<Metrics>>,
+) -> Result<(FollowIter, GroupConsumer)> {
+    let mut config = client_config(servers_csv);
+    config.set("group.id", group_id);
+    let consumer = following_consumer(config, metrics)?;
+    info!(group_id = group_id, "subscribing to {:?}", user_topics);
+    consumer.subscribe(user_topics)?;
+    let consumer = Arc::new(consumer);
+    let it = FollowIter {
+        consumer: consumer.clone(),
+        options: options.clone(),
+        backoff: Backoff::new(options),
+        error_retries: options.max_error_retries,
+    };
+    Ok((it, consumer))
+}
+
+/// Where a tail starts reading every partition
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Start {
+    Beginning,
+    /// only messages produced after the tail started
+    End,
+    /// this offset, or the start of the log if it was deleted
+    Offset(i64),
+    /// this many last messages
+    Last(i64),
+    /// first message at or after this timestamp in milliseconds
+    Timestamp(i64),
+}
+
+impl FromStr for Start {
+    type Err = Error;
+    /// `beginning`, `end`, an offset, `-N` for the N last messages or `@millis`
+    fn from_str(s: &str) -> Result<Start> {
+        Ok(match s {
+            "beginning" => Start::Beginning,
+            "end" => Start::End,
+            _ => match (s.strip_prefix('-'), s.strip_prefix('@')) {
+                (Some(n), _) => Start::Last(n.parse()?),
+                (_, Some(ts)) => Start::Timestamp(ts.parse()?),
+                _ => Start::Offset(s.parse()?),
+            },
+        })
+    }
+}
+
+/// Partitions of `topics`, all of them or only `partitions`, at the offsets `start` resolves to
+pub(crate) fn assignment<C: ConsumerContext>(
+    consumer: &impl Consumer<C>,
+    topics: &[&str],
+    partitions: &[i32],
+    start: Start,
+    options: &KafkaOptions,
+) -> Result<TopicPartitionList> {
+    let mut tpl = TopicPartitionList::new();
+    for topic in topics {
+        let count = tpl.count();
+        let md = consumer.fetch_metadata(Some(topic), options.request_timeout)?;
+        for p in md.topics().iter().flat_map(|t| t.partitions()) {
+            if !partitions.is_empty() && !partitions.contains(&p.id()) {
+                continue;
+            }
+            let offset = match start {
+                Start::Beginning => Offset::Beginning,
+                Start::End => Offset::End,
+                Start::Offset(offset) | Start::Timestamp(offset) => Offset::Offset(offset),
+                Start::Last(n) => {
+                    let (lo, hi) =
+                        consumer.fetch_watermarks(topic, p.id(), options.request_timeout)?;
+                    Offset::Offset(std::cmp::max(lo, hi - n))
+                }
+            };
+            tpl.add_partition_offset(topic, p.id(), offset)?;
+        }
+        if tpl.count() == count {
+            return Err(ConsumerError::UnknownTopic(topic.to_string()).into());
+        }
+    }
+    if let Start::Timestamp(_) = start {
+        tpl = consumer.offsets_for_times(tpl, options.request_timeout)?;
+    }
+    Ok(tpl)
+}
+
+/// Follows `topic` from `start` on, in all its partitions or only in `partitions`. The lag is
+/// reported to `metrics`.
+pub fn read_kafka_tail(
+    servers_csv: &str,
+    topic: &str,
+    partitions: &[i32],
+    start: Start,
+    options: &KafkaOptions,
+    metrics: Option<Arc<Metrics>>,
+) -> Result<FollowIter> {
+    let consumer = following_consumer(client_config(servers_csv), metrics)?;
+    let tpl = assignment(&consumer, &[topic], partitions, start, options)?;
+    info!(topic = topic, partitions = tpl.count(), "tailing from {:?}", start);
+    consumer.assign(&tpl)?;
+    Ok(FollowIter {
+        consumer: Arc::new(consumer),
+        options: options.clone(),
+        backoff: Backoff::new(options),
+        error_retries: options.max_error_retries,
+    })
 }
 
 pub struct Topic {
@@ -134,20 +723,18 @@ pub struct Topic {
     end_offset: Offset,
 }
 
-pub fn read_kafka(servers_csv: &str, user_topics: &[&str]) -> impl Iterator<Item = Msg> {
-    let mut config = rdkafka::config::ClientConfig::new();
-    config.set("bootstrap.servers", servers_csv);
-    config.set("enable.auto.commit", "false");
-    config.set("isolation.level", "read_committed");
-    config.set("group.id", Uuid::new_v4().to_string().as_str());
-    config.set("auto.offset.reset", "beginning");
-    config.set("debug", "all");
-    let consumer: BaseConsumer = config.create().unwrap();
+pub(crate) type Watermarks = FnvHashMap<(String, i32), Watermark>;
+
+pub(crate) fn fetch_watermarks(
+    servers_csv: &str,
+    user_topics: &[&str],
+    options: &KafkaOptions,
+) -> Result<Watermarks> {
+    let config = client_config(servers_csv);
+    let consumer: BaseConsumer = config.create()?;
     let mut partitions = vec![];
     for topic in user_topics {
-        let md = consumer
-            .fetch_metadata(Some(topic), Timeout::Never)
-            .unwrap();
+        let md = consumer.fetch_metadata(Some(topic), options.request_timeout)?;
         md.topics()
             .iter()
             .flat_map(|t| {
@@ -162,10 +749,10 @@ pub fn read_kafka(servers_csv: &str, user_topics: &[&str]) -> impl Iterator<Item
 
     drop(consumer);
 
-    let consumer: BaseConsumer = config.create().unwrap();
+    let consumer: BaseConsumer = config.create()?;
     let mut watermarks = FnvHashMap::default();
     for (t, p) in partitions {
-        let (lo, hi) = consumer.fetch_watermarks(&t, p, Timeout::Never).unwrap();
+        let (lo, hi) = consumer.fetch_watermarks(&t, p, options.request_timeout)?;
         info!(topic = t, partition = p, lo = lo, hi = hi, d = hi - lo);
         watermarks.insert(
             (t.to_string(), p),
@@ -177,24 +764,190 @@ pub fn read_kafka(servers_csv: &str, user_topics: &[&str]) -> impl Iterator<Item
         );
         //eprintln!("{:?}", consumer.position());
     }
-    let topics = watermarks
+    Ok(watermarks)
+}
+
+fn subscribed_topics(watermarks: &Watermarks) -> Vec<&str> {
+    watermarks
         .keys()
-        .into_iter()
         .map(|(t, _)| t.as_str())
         .collect::<FnvHashSet<_>>()
         .into_iter()
-        .collect_vec();
-    let consumer: BaseConsumer = config.create().unwrap();
+        .collect_vec()
+}
+
+pub fn read_kafka(
+    servers_csv: &str,
+    user_topics: &[&str],
+    options: &KafkaOptions,
+) -> Result<impl Iterator<Item = Result<Msg>>> {
+    let watermarks = fetch_watermarks(servers_csv, user_topics, options)?;
+    let topics = subscribed_topics(&watermarks);
+    let consumer: BaseConsumer = client_config(servers_csv).create()?;
     info!("subscribing to {:?}", topics);
-    consumer.subscribe(&topics).unwrap();
+    consumer.subscribe(&topics)?;
     info!("subscribed to {:?}", topics);
     let it = Iter {
         consumer,
-        positions: HashMap::new(),
         watermarks,
-        retries: MAX_RETRIES,
+        options: options.clone(),
+        backoff: Backoff::new(options),
+        retries: options.max_idle_polls,
+        error_retries: options.max_error_retries,
+        errors: 0,
         messages_received: 0,
         attempts: 0,
+        done: false,
     };
-    it
+    Ok(it)
+}
+
+/// Async counterpart of `read_kafka`. Messages are sent to `senders[partition % senders.len()]`,
+/// so all messages of a partition go through the same channel in offset order
+pub(crate) async fn stream_kafka(
+    servers_csv: String,
+    mut watermarks: Watermarks,
+    senders: Vec<Sender<Msg>>,
+    options: KafkaOptions,
+) -> Result<()> {
+    let topics = subscribed_topics(&watermarks);
+    let consumer: StreamConsumer = client_config(&servers_csv).create()?;
+    info!("subscribing to {:?}", topics);
+    consumer.subscribe(&topics)?;
+    let mut remaining = watermarks
+        .iter()
+        .filter(|(_, wm)| wm.hi > wm.lo)
+        .map(|(k, _)| k.clone())
+        .collect::<FnvHashSet<_>>();
+    let mut retries = options.max_idle_polls;
+    let mut error_retries = options.max_error_retries;
+    let mut backoff = Backoff::new(&options);
+    let mut messages_received = 0;
+    let mut errors = 0;
+    let mut stream = consumer.stream();
+    while !remaining.is_empty() && retries > 0 {
+        match tokio::time::timeout(Duration::from_secs(1), stream.next()).await {
+            Ok(Some(Ok(m))) => {
+                retries = options.max_idle_polls;
+                error_retries = options.max_error_retries;
+                backoff.reset();
+                messages_received += 1;
+                let key = (m.topic().to_owned(), m.partition());
+                match watermarks.get_mut(&key) {
+                    Some(wm) => {
+                        wm.received += 1;
+                        if m.offset() >= wm.hi - 1 {
+                            remaining.remove(&key);
+                        }
+                    }
+                    None => {
+                        warn!("{:?}", ConsumerError::UnknownPartition(key.0, key.1));
+                        continue;
+                    }
+                }
+                let msg = parse(m);
+                let idx = msg.partition as usize % senders.len();
+                if senders[idx].send(msg).await.is_err() {
+                    break;
+                }
+            }
+            Ok(Some(Err(e))) => {
+                errors += 1;
+                if !is_transient(&e) || error_retries == 0 {
+                    log_summary(&watermarks, messages_received, errors, true);
+                    return Err(ConsumerError::Fatal(e).into());
+                }
+                error_retries -= 1;
+                let delay = backoff.next_delay();
+                warn!(
+                    retries = error_retries,
+                    delay_ms = delay.as_millis() as u64,
+                    "transient kafka error: {:?}",
+                    e
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Ok(None) => break,
+            Err(_) => {
+                // transaction markers at the end of a partition are never delivered
+                let position = consumer.position()?;
+                for tp in position.elements() {
+                    let key = (tp.topic().to_owned(), tp.partition());
+                    match (tp.offset(), watermarks.get(&key)) {
+                        (Offset::Offset(offset), Some(wm)) if offset >= wm.hi => {
+                            remaining.remove(&key);
+                        }
+                        _ => {}
+                    }
+                }
+                info!(missing = remaining.len(), retries = retries);
+                retries -= 1;
+            }
+        }
+    }
+    log_summary(&watermarks, messages_received, errors, false);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::parse::kafka::{client_config, LagContext, Producer};
+    use crate::parse::msg::ParsedKey;
+    use rdkafka::consumer::{BaseConsumer, Consumer};
+    use rdkafka::producer::{BaseProducer, Producer as _};
+    use rdkafka::{Message, Offset, TopicPartitionList};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// librdkafka's mock cluster, running as long as the returned client, and its servers
+    pub(crate) fn mock_cluster() -> (BaseProducer, String) {
+        let mock: BaseProducer = rdkafka::ClientConfig::new()
+            .set("test.mock.num.brokers", "1")
+            .create()
+            .unwrap();
+        let metadata = mock
+            .client()
+            .fetch_metadata(None, Duration::from_secs(5))
+            .unwrap();
+        let broker = &metadata.brokers()[0];
+        let servers = format!("{}:{}", broker.host(), broker.port());
+        (mock, servers)
+    }
+
+    #[test]
+    fn test_aborted_commit_rewinds() {
+        let (_mock, servers) = mock_cluster();
+
+        let producer = Producer::new(&servers).unwrap();
+        for i in 0..12 {
+            let value = i.to_string();
+            producer
+                .send("orders", Some(0), &ParsedKey::None, &[], value.as_bytes())
+                .unwrap();
+        }
+        producer.flush().unwrap();
+
+        let consumer: BaseConsumer<LagContext> = client_config(&servers)
+            .create_with_context(LagContext::default())
+            .unwrap();
+        let mut tpl = TopicPartitionList::new();
+        tpl.add_partition_offset("orders", 0, Offset::Beginning)
+            .unwrap();
+        consumer.assign(&tpl).unwrap();
+        let mut consumed = 0;
+        while consumed < 12 {
+            if let Some(m) = consumer.poll(Duration::from_secs(5)) {
+                assert_eq!(m.unwrap().offset(), consumed);
+                consumed += 1;
+            }
+        }
+        let group = Arc::new(consumer);
+        // without a transactional id committing fails
+        let first = BTreeMap::from([(("orders".to_string(), 0), 4)]);
+        let next = BTreeMap::from([(("orders".to_string(), 0), 12)]);
+        assert!(producer.commit(&first, &next, &group).is_err());
+        let m = group.poll(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(m.offset(), 4);
+    }
 }
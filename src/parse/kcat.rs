@@ -1,120 +1,396 @@
 use crate::parse::error::*;
 use crate::parse::msg::{Msg, ParsedKey};
-use byteorder::ReadBytesExt;
-use std::fmt::Debug;
-use std::io::{BufRead, BufReader, Read};
+use std::io::BufRead;
 use tracing::{debug, trace};
 
+/// Format written by kcat.sh
+pub const DEFAULT_FORMAT: &str = "%t\\n%p\\n%o\\n%T\\n%K\\n%k\\n%S\\n%s\\n";
+
+#[derive(Debug)]
+pub enum KcatError {
+    UnknownField(char),
+    UnknownEscape(char),
+    /// a field of unknown length must be followed by a delimiter
+    FieldNeedsDelimiter(char),
+    UnexpectedDelimiter { expected: Vec<u8>, found: Vec<u8> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Topic,
+    Partition,
+    Offset,
+    Timestamp,
+    Key,
+    KeyLen,
+    Msg,
+    MsgLen,
+    /// message length as 4 byte big endian integer
+    MsgLenBinary,
+    Headers,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Field(Field),
+    Literal(Vec<u8>),
+}
+
+/// Parsed kcat `-f` format string
+#[derive(Debug, Clone)]
+pub struct Format {
+    tokens: Vec<Token>,
+}
+
+impl Format {
+    pub fn new(fmt: &str) -> Result<Format> {
+        let mut tokens = vec![];
+        let mut literal = vec![];
+        let mut chars = fmt.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '%' => {
+                    let field = match chars.next() {
+                        Some('t') => Field::Topic,
+                        Some('p') => Field::Partition,
+                        Some('o') => Field::Offset,
+                        Some('T') => Field::Timestamp,
+                        Some('k') => Field::Key,
+                        Some('K') => Field::KeyLen,
+                        Some('s') => Field::Msg,
+                        Some('S') => Field::MsgLen,
+                        Some('R') => Field::MsgLenBinary,
+                        Some('h') => Field::Headers,
+                        Some('%') => {
+                            literal.push(b'%');
+                            continue;
+                        }
+                        other => return Err(KcatError::UnknownField(other.unwrap_or('%')).into()),
+                    };
+                    if !literal.is_empty() {
+                        tokens.push(Token::Literal(std::mem::take(&mut literal)));
+                    }
+                    tokens.push(Token::Field(field));
+                }
+                '\\' => match chars.next() {
+                    Some('n') => literal.push(b'\n'),
+                    Some('r') => literal.push(b'\r'),
+                    Some('t') => literal.push(b'\t'),
+                    Some('0') => literal.push(0),
+                    Some('\\') => literal.push(b'\\'),
+                    other => return Err(KcatError::UnknownEscape(other.unwrap_or('\\')).into()),
+                },
+                c => literal.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(literal));
+        }
+        let format = Format { tokens };
+        format.check()?;
+        Ok(format)
+    }
+
+    /// Every field that isn't length prefixed must be followed by a delimiter
+    fn check(&self) -> Result<()> {
+        let mut key_len = false;
+        let mut msg_len = false;
+        for (i, token) in self.tokens.iter().enumerate() {
+            let field = match token {
+                Token::Field(f) => *f,
+                Token::Literal(_) => continue,
+            };
+            let sized = match field {
+                Field::KeyLen => {
+                    key_len = true;
+                    false
+                }
+                Field::MsgLen => {
+                    msg_len = true;
+                    false
+                }
+                Field::MsgLenBinary => {
+                    msg_len = true;
+                    true
+                }
+                Field::Key => key_len,
+                Field::Msg => msg_len,
+                _ => false,
+            };
+            if !sized && !matches!(self.tokens.get(i + 1), Some(Token::Literal(_))) {
+                return Err(KcatError::FieldNeedsDelimiter(field_char(field)).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::new(DEFAULT_FORMAT).unwrap()
+    }
+}
+
+fn field_char(field: Field) -> char {
+    match field {
+        Field::Topic => 't',
+        Field::Partition => 'p',
+        Field::Offset => 'o',
+        Field::Timestamp => 'T',
+        Field::Key => 'k',
+        Field::KeyLen => 'K',
+        Field::Msg => 's',
+        Field::MsgLen => 'S',
+        Field::MsgLenBinary => 'R',
+        Field::Headers => 'h',
+    }
+}
+
+/// Parses kcat output written with `format`. Fields missing from the format are left empty,
+/// numbers as -1.
 pub struct Parser<R> {
-    r: BufReader<R>,
+    r: R,
+    format: Format,
+    done: bool,
 }
 
 impl<R> Iterator for Parser<R>
 where
-    R: Read + Debug,
+    R: BufRead,
 {
-    type Item = Msg;
+    type Item = Result<Msg>;
     fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
         match self.parse_next() {
-            Ok(msg) => Some(msg),
+            Ok(msg) => Some(Ok(msg)),
             Err(Error::Eof) => None,
-            Err(other) => panic!("{:?}", other),
+            Err(other) => {
+                // there is no way to find the start of the next record
+                self.done = true;
+                Some(Err(other))
+            }
         }
     }
 }
 
 impl<R> Parser<R>
 where
-    R: Read + Debug,
+    R: BufRead,
 {
-    #[tracing::instrument]
     pub fn new(r: R) -> Self {
+        Self::with_format(r, Format::default())
+    }
+
+    #[tracing::instrument(skip(r))]
+    pub fn with_format(r: R, format: Format) -> Self {
         trace!("new");
         Parser {
-            r: BufReader::new(r),
+            r,
+            format,
+            done: false,
         }
     }
+
     #[tracing::instrument(skip(self))]
-    fn token(&mut self) -> Result<Vec<u8>> {
-        let mut buf = Vec::with_capacity(16 * 4096);
-        self.r.read_until(b'\n', &mut buf)?;
-        if buf.is_empty() {
-            debug!("eof");
-            return Err(Error::Eof);
-        }
-        if buf.last().copied() == Some(b'\n') {
-            buf.pop();
-        } else {
-            return Err(Error::EolNotFound);
+    fn until(&mut self, delimiter: &[u8]) -> Result<Vec<u8>> {
+        let last = *delimiter.last().unwrap();
+        let mut buf = Vec::with_capacity(4096);
+        loop {
+            let n = self.r.read_until(last, &mut buf)?;
+            if n == 0 {
+                return Err(Error::EolNotFound);
+            }
+            if buf.ends_with(delimiter) {
+                buf.truncate(buf.len() - delimiter.len());
+                break;
+            }
         }
         trace!(buf = format!("{:?}", buf));
         Ok(buf)
     }
 
-    #[tracing::instrument(skip(self))]
-    fn string(&mut self) -> Result<String> {
-        let a = self.token()?;
-        let s = String::from_utf8(a)?;
-        trace!(s = format!("{:?}", s));
-        Ok(s)
-    }
-
-    #[tracing::instrument(skip(self))]
-    fn string_int(&mut self) -> Result<i64> {
-        let s = self.string()?;
-        let i = s.parse::<i64>()?;
-        trace!(i = format!("{:?}", i));
-        Ok(i)
-    }
-
     #[tracing::instrument(skip(self))]
     fn exact(&mut self, n: usize) -> Result<Vec<u8>> {
         let mut buf = vec![0u8; n];
         self.r.read_exact(&mut buf)?;
-        self.consume_eol().unwrap();
         trace!(buf = format!("{:?}", buf));
         Ok(buf)
     }
 
     #[tracing::instrument(skip(self))]
-    fn consume_eol(&mut self) -> Result<()> {
-        trace!("");
-        assert_eq!(self.r.read_u8()?, b'\n');
+    fn literal(&mut self, expected: &[u8]) -> Result<()> {
+        let found = self.exact(expected.len())?;
+        if found != expected {
+            return Err(KcatError::UnexpectedDelimiter {
+                expected: expected.to_vec(),
+                found,
+            }
+            .into());
+        }
         Ok(())
     }
 
-    #[tracing::instrument(skip(self))]
-    fn exact_string(&mut self, n: usize) -> Result<String> {
-        Ok(String::from_utf8(self.exact(n)?)?)
+    fn string(&mut self, delimiter: &[u8]) -> Result<String> {
+        Ok(String::from_utf8(self.until(delimiter)?)?)
+    }
+
+    fn string_int(&mut self, delimiter: &[u8]) -> Result<i64> {
+        let i = self.string(delimiter)?.parse::<i64>()?;
+        trace!(i = i);
+        Ok(i)
+    }
+
+    /// Reads a field either by its length (-1 meaning null) or up to the delimiter
+    fn bytes(&mut self, len: Option<i64>, delimiter: &[u8]) -> Result<Option<Vec<u8>>> {
+        match len {
+            Some(-1) => Ok(None),
+            Some(n) => Ok(Some(self.exact(n.try_into()?)?)),
+            None => Ok(Some(self.until(delimiter)?)),
+        }
+    }
+
+    /// `name=value` pairs separated by commas, as printed by `%h`
+    fn headers(&mut self, delimiter: &[u8]) -> Result<Vec<(String, ParsedKey)>> {
+        let buf = self.until(delimiter)?;
+        let mut out = vec![];
+        for pair in buf.split(|b| *b == b',').filter(|p| !p.is_empty()) {
+            let (name, value) = match pair.iter().position(|b| *b == b'=') {
+                Some(i) => (&pair[..i], ParsedKey::new(&pair[i + 1..])),
+                None => (pair, ParsedKey::None),
+            };
+            out.push((String::from_utf8(name.to_vec())?, value));
+        }
+        Ok(out)
     }
 
     #[tracing::instrument(skip(self))]
     fn parse_next(&mut self) -> Result<Msg> {
-        let topic = self.string()?;
-        debug!(topic = topic.as_str());
-        let partition = self.string_int()?;
-        debug!(partition = partition);
-        let offset = self.string_int()?;
-        debug!(offset = offset);
-        let ts = self.string_int()?;
-        debug!(ts = ts);
-        let key_len = self.string_int()?;
-        debug!(key_len = key_len);
-        let key = ParsedKey::new(self.exact(key_len.try_into()?)?.as_slice());
-        debug!(key = format!("{:?}", key).as_str());
-        let msg_len = self.string_int()?;
-        debug!(msg_len = msg_len);
-        let msg = self.exact(msg_len.try_into()?)?;
-        debug!(msg = msg.len());
-        Ok(Msg {
-            topic,
-            partition,
-            offset,
-            ts,
-            key_len: key_len.try_into()?,
-            key,
-            msg,
-            msg_len: msg_len.try_into()?,
-        })
+        if self.r.fill_buf()?.is_empty() {
+            debug!("eof");
+            return Err(Error::Eof);
+        }
+        let mut msg = Msg {
+            topic: String::new(),
+            partition: -1,
+            offset: -1,
+            ts: -1,
+            key: ParsedKey::None,
+            key_len: 0,
+            msg: vec![],
+            msg_len: 0,
+            headers: vec![],
+        };
+        let mut key_len = None;
+        let mut msg_len = None;
+        let tokens = self.format.tokens.clone();
+        // fields of unknown length are read up to and including the delimiter that follows them
+        let mut delimiter_consumed = false;
+        for (i, token) in tokens.iter().enumerate() {
+            let field = match token {
+                Token::Literal(_) if delimiter_consumed => {
+                    delimiter_consumed = false;
+                    continue;
+                }
+                Token::Literal(l) => {
+                    self.literal(l)?;
+                    continue;
+                }
+                Token::Field(f) => *f,
+            };
+            delimiter_consumed = match field {
+                Field::Key => key_len.is_none(),
+                Field::Msg => msg_len.is_none(),
+                Field::MsgLenBinary => false,
+                _ => true,
+            };
+            let delimiter = match tokens.get(i + 1) {
+                Some(Token::Literal(l)) => l.as_slice(),
+                _ => &[],
+            };
+            match field {
+                Field::Topic => msg.topic = self.string(delimiter)?,
+                Field::Partition => msg.partition = self.string_int(delimiter)?,
+                Field::Offset => msg.offset = self.string_int(delimiter)?,
+                Field::Timestamp => msg.ts = self.string_int(delimiter)?,
+                Field::KeyLen => key_len = Some(self.string_int(delimiter)?),
+                Field::MsgLen => msg_len = Some(self.string_int(delimiter)?),
+                Field::MsgLenBinary => {
+                    let buf = self.exact(4)?;
+                    msg_len = Some(i32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]).into());
+                }
+                Field::Key => {
+                    msg.key = ParsedKey::from_option(self.bytes(key_len, delimiter)?.as_deref());
+                    msg.key_len = msg.key.len();
+                }
+                Field::Msg => {
+                    msg.msg = self.bytes(msg_len, delimiter)?.unwrap_or_default();
+                    msg.msg_len = msg.msg.len();
+                }
+                Field::Headers => msg.headers = self.headers(delimiter)?,
+            }
+            trace!(field = format!("{:?}", field).as_str());
+        }
+        debug!(
+            topic = msg.topic.as_str(),
+            partition = msg.partition,
+            offset = msg.offset,
+            msg = msg.msg.len()
+        );
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::kcat::{Format, Parser};
+    use crate::parse::msg::ParsedKey;
+
+    #[test]
+    fn test_default_format() {
+        let mut input = b"orders\n2\n10\n1683000000000\n2\nk1\n4\n".to_vec();
+        input.extend_from_slice(&[0, b'\n', 0xff, 1]);
+        input.extend_from_slice(b"\norders\n2\n11\n1683000000001\n-1\n\n0\n\n");
+        let msgs = Parser::new(input.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].topic, "orders");
+        assert_eq!((msgs[0].partition, msgs[0].offset), (2, 10));
+        assert!(matches!(&msgs[0].key, ParsedKey::Utf8(k) if k == "k1"));
+        assert_eq!(msgs[0].msg, &[0, b'\n', 0xff, 1]);
+        assert!(matches!(msgs[1].key, ParsedKey::None));
+        assert!(msgs[1].msg.is_empty());
+    }
+
+    #[test]
+    fn test_custom_format() {
+        let format = Format::new("%t|%p|%o|%h|%k\\t%s\\n").unwrap();
+        let input = b"orders|0|5|a=1,b|key\tvalue\n".to_vec();
+        let msgs = Parser::with_format(input.as_slice(), format)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].ts, -1);
+        assert_eq!(msgs[0].msg, b"value");
+        assert_eq!(msgs[0].headers.len(), 2);
+        assert!(matches!(msgs[0].headers[1].1, ParsedKey::None));
+    }
+
+    #[test]
+    fn test_invalid_format() {
+        assert!(Format::new("%t%p\\n").is_err());
+        assert!(Format::new("%x\\n").is_err());
+        assert!(Format::new("%S%s").is_err());
+        assert!(Format::new("%R%s").is_ok());
+    }
+
+    #[test]
+    fn test_mismatched_delimiter() {
+        let format = Format::new("%t\\n%p\\n").unwrap();
+        let mut parser = Parser::with_format(b"orders\nx\n".as_slice(), format);
+        assert!(parser.next().unwrap().is_err());
+        assert!(parser.next().is_none());
     }
 }
@@ -0,0 +1,139 @@
+use crate::parse::error::*;
+use crate::parse::msg::{Msg, ParsedKey};
+use std::io::BufRead;
+use tracing::trace;
+
+/// One line of `kcat -J` output
+#[derive(Debug, serde::Deserialize)]
+struct Record {
+    topic: String,
+    partition: i64,
+    offset: i64,
+    #[serde(default)]
+    tstype: Option<String>,
+    #[serde(default)]
+    ts: Option<i64>,
+    /// names and values, alternating
+    #[serde(default)]
+    headers: Option<Vec<Option<String>>>,
+    #[serde(default)]
+    key: Option<serde_json::Value>,
+    #[serde(default)]
+    payload: Option<serde_json::Value>,
+}
+
+/// Parser for kcat's JSON envelope (`kcat -J`), one record per line
+pub struct Parser<R> {
+    r: R,
+}
+
+impl<R: BufRead> Iterator for Parser<R> {
+    type Item = Result<Msg>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut buf = vec![];
+            match self.r.read_until(b'\n', &mut buf) {
+                Ok(0) => return None,
+                Ok(_) if buf.iter().all(|b| b.is_ascii_whitespace()) => continue,
+                Ok(_) => return Some(parse_line(&buf)),
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Parser<R> {
+    pub fn new(r: R) -> Self {
+        Parser { r }
+    }
+}
+
+/// kcat writes binary keys and payloads into JSON strings as they are, escaping only control
+/// characters, so the line is usually not valid UTF-8. Reading it as latin1 maps every byte to
+/// a char and makes it valid, `bytes` maps the chars of the parsed strings back to the original bytes.
+fn latin1(line: &[u8]) -> String {
+    line.iter().map(|b| *b as char).collect()
+}
+
+fn bytes(s: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        match u8::try_from(c) {
+            Ok(b) => out.push(b),
+            Err(_) => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    out
+}
+
+fn string(s: &str) -> Result<String> {
+    Ok(String::from_utf8(bytes(s))?)
+}
+
+/// Strings are raw bytes, anything else was produced by a kcat deserializer (`-s avro`, `-s i`)
+fn value_bytes(v: Option<&serde_json::Value>) -> Option<Vec<u8>> {
+    match v {
+        None | Some(serde_json::Value::Null) => None,
+        Some(serde_json::Value::String(s)) => Some(bytes(s)),
+        Some(other) => Some(other.to_string().into_bytes()),
+    }
+}
+
+fn parse_line(line: &[u8]) -> Result<Msg> {
+    let record: Record = serde_json::from_str(&latin1(line))?;
+    trace!(record = format!("{:?}", record).as_str());
+    let key = ParsedKey::from_option(value_bytes(record.key.as_ref()).as_deref());
+    let msg = value_bytes(record.payload.as_ref()).unwrap_or_default();
+    let ts = match record.tstype.as_deref() {
+        Some("create") | Some("logappend") => record.ts.unwrap_or(-1),
+        _ => -1,
+    };
+    let mut headers = vec![];
+    for pair in record.headers.unwrap_or_default().chunks(2) {
+        let name = pair[0].as_deref().unwrap_or_default();
+        let value = pair.get(1).cloned().flatten().map(|v| bytes(&v));
+        headers.push((string(name)?, ParsedKey::from_option(value.as_deref())));
+    }
+    Ok(Msg {
+        topic: string(&record.topic)?,
+        partition: record.partition,
+        offset: record.offset,
+        ts,
+        key_len: key.len(),
+        key,
+        msg_len: msg.len(),
+        msg,
+        headers,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::kcat_json::Parser;
+    use crate::parse::msg::ParsedKey;
+
+    #[test]
+    fn test_kcat_json() {
+        let mut input = br#"{"topic":"orders","partition":3,"offset":42,"tstype":"create","ts":1683000000000,"broker":1,"headers":["source","web","trace",null],"key":"k1","payload":"\u0000\u0000\u0000\u0000\u0007"#.to_vec();
+        input.extend_from_slice(&[0xff, 0xfe]);
+        input.extend_from_slice(b"\"}\n\n");
+        input.extend_from_slice(br#"{"topic":"orders","partition":0,"offset":1,"tstype":"none","key":null,"payload":null}"#);
+        input.push(b'\n');
+        let msgs = Parser::new(input.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(msgs.len(), 2);
+        let m = &msgs[0];
+        assert_eq!(m.topic, "orders");
+        assert_eq!((m.partition, m.offset, m.ts), (3, 42, 1683000000000));
+        assert!(matches!(&m.key, ParsedKey::Utf8(k) if k == "k1"));
+        assert_eq!(m.msg, &[0, 0, 0, 0, 7, 0xff, 0xfe]);
+        assert_eq!(m.headers.len(), 2);
+        assert!(matches!(&m.headers[0].1, ParsedKey::Utf8(v) if v == "web"));
+        assert!(matches!(m.headers[1].1, ParsedKey::None));
+        let m = &msgs[1];
+        assert_eq!(m.ts, -1);
+        assert!(matches!(m.key, ParsedKey::None));
+        assert!(m.msg.is_empty());
+    }
+}
@@ -0,0 +1,390 @@
+//! Metrics of long running decoding, rendered in the prometheus text format and served on
+//! `GET /metrics`.
+use crate::parse::confluent::peek_schema_id;
+use crate::parse::error::*;
+use crate::parse::msg::Msg;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tracing::info;
+use warp::reply::Response;
+use warp::Filter;
+
+/// Upper bounds of the latency histograms, in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+struct Metric {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+}
+
+const CONSUMED: Metric = Metric {
+    name: "proto2json_records_consumed_total",
+    help: "Records read from the source",
+    kind: Kind::Counter,
+};
+const DECODED: Metric = Metric {
+    name: "proto2json_records_decoded_total",
+    help: "Records decoded",
+    kind: Kind::Counter,
+};
+const FAILED: Metric = Metric {
+    name: "proto2json_records_failed_total",
+    help: "Records that failed to decode",
+    kind: Kind::Counter,
+};
+const LAG: Metric = Metric {
+    name: "proto2json_consumer_lag",
+    help: "Messages between the last one consumed and the end of the partition",
+    kind: Kind::Gauge,
+};
+const SCHEMA_FETCH: Metric = Metric {
+    name: "proto2json_schema_fetch_seconds",
+    help: "Time taken to fetch a schema and its references from the schema source",
+    kind: Kind::Histogram,
+};
+const DESCRIPTOR_CACHE: Metric = Metric {
+    name: "proto2json_descriptor_cache_total",
+    help: "Lookups of compiled schemas, by whether they were cached",
+    kind: Kind::Counter,
+};
+const OUTPUT_RECORDS: Metric = Metric {
+    name: "proto2json_output_records_total",
+    help: "Lines written to the sink",
+    kind: Kind::Counter,
+};
+const OUTPUT_BYTES: Metric = Metric {
+    name: "proto2json_output_bytes_total",
+    help: "Bytes written to the sink",
+    kind: Kind::Counter,
+};
+
+type Labels = Vec<(&'static str, String)>;
+
+/// Counts of the records of a topic with a schema id
+#[derive(Debug, Default)]
+struct RecordCounts {
+    consumed: AtomicU64,
+    decoded: AtomicU64,
+    /// by error kind
+    failed: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// Count per bucket of `LATENCY_BUCKETS`, not cumulative, and the sum and count of all
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Counters, gauges and histograms of a run, shared by its threads. Series are added on first
+/// use, later updates don't allocate.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// by topic and schema id
+    records: RwLock<BTreeMap<String, BTreeMap<Option<i32>, RecordCounts>>>,
+    /// by topic and partition
+    lag: Mutex<BTreeMap<(String, i64), i64>>,
+    schema_fetch: Mutex<Histogram>,
+    descriptor_hits: AtomicU64,
+    descriptor_misses: AtomicU64,
+    output_records: AtomicU64,
+    output_bytes: AtomicU64,
+}
+
+/// Backslashes, double quotes and line feeds are escaped in label values
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_labels(labels: &[(&'static str, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", labels.join(","))
+}
+
+fn write_header(out: &mut String, metric: &Metric) {
+    let kind = match metric.kind {
+        Kind::Counter => "counter",
+        Kind::Gauge => "gauge",
+        Kind::Histogram => "histogram",
+    };
+    let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+    let _ = writeln!(out, "# TYPE {} {}", metric.name, kind);
+}
+
+/// `metric` with its series, left out if it has none
+fn write_metric(out: &mut String, metric: &Metric, series: Vec<(Labels, f64)>) {
+    if series.is_empty() {
+        return;
+    }
+    write_header(out, metric);
+    for (labels, value) in series {
+        let _ = writeln!(out, "{}{} {}", metric.name, format_labels(&labels), value);
+    }
+}
+
+/// Lines and bytes of output `lines`, each followed by a line feed
+pub fn output_size(lines: &[String]) -> (usize, usize) {
+    (lines.len(), lines.iter().map(|l| l.len() + 1).sum())
+}
+
+impl Metrics {
+    /// Counts of the records of `msg`'s topic and schema id
+    fn records<R>(&self, msg: &Msg, f: impl FnOnce(&RecordCounts) -> R) -> R {
+        let schema_id = peek_schema_id(&msg.msg);
+        if let Some(counts) = self
+            .records
+            .read()
+            .unwrap()
+            .get(msg.topic.as_str())
+            .and_then(|topic| topic.get(&schema_id))
+        {
+            return f(counts);
+        }
+        let mut records = self.records.write().unwrap();
+        let counts = records
+            .entry(msg.topic.clone())
+            .or_default()
+            .entry(schema_id)
+            .or_default();
+        f(counts)
+    }
+
+    pub fn consumed(&self, msg: &Msg) {
+        self.records(msg, |c| c.consumed.fetch_add(1, Ordering::Relaxed));
+    }
+
+    pub fn decoded(&self, msg: &Msg) {
+        self.records(msg, |c| c.decoded.fetch_add(1, Ordering::Relaxed));
+    }
+
+    pub fn failed(&self, msg: &Msg, e: &Error) {
+        self.records(msg, |c| {
+            *c.failed.lock().unwrap().entry(e.kind()).or_default() += 1
+        });
+    }
+
+    pub fn lag(&self, topic: &str, partition: i64, lag: i64) {
+        let key = (topic.to_string(), partition);
+        self.lag.lock().unwrap().insert(key, lag.max(0));
+    }
+
+    pub fn schema_fetch(&self, elapsed: Duration) {
+        let value = elapsed.as_secs_f64();
+        let mut histogram = self.schema_fetch.lock().unwrap();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|le| value <= *le) {
+            histogram.buckets[i] += 1;
+        }
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    pub fn descriptor_cache(&self, hit: bool) {
+        let counter = match hit {
+            true => &self.descriptor_hits,
+            false => &self.descriptor_misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Lines and bytes written to the sink, see `output_size`
+    pub fn written(&self, (records, bytes): (usize, usize)) {
+        self.output_records
+            .fetch_add(records as u64, Ordering::Relaxed);
+        self.output_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Metrics with labels are left out until they have a series
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let records = self.records.read().unwrap();
+        let labels = |topic: &str, schema_id: &Option<i32>| -> Labels {
+            let schema_id = schema_id.map_or("none".to_string(), |id| id.to_string());
+            vec![("topic", topic.to_string()), ("schema_id", schema_id)]
+        };
+        let counts = || {
+            records.iter().flat_map(|(topic, by_schema)| {
+                by_schema
+                    .iter()
+                    .map(move |(schema_id, counts)| (labels(topic, schema_id), counts))
+            })
+        };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed) as f64;
+        let consumed = counts().map(|(l, c)| (l, load(&c.consumed))).collect();
+        write_metric(&mut out, &CONSUMED, consumed);
+        let decoded = counts().map(|(l, c)| (l, load(&c.decoded))).collect();
+        write_metric(&mut out, &DECODED, decoded);
+        let mut failed = vec![];
+        for (labels, counts) in counts() {
+            for (kind, n) in counts.failed.lock().unwrap().iter() {
+                let mut labels = labels.clone();
+                labels.push(("error", kind.to_string()));
+                failed.push((labels, *n as f64));
+            }
+        }
+        write_metric(&mut out, &FAILED, failed);
+        let lag = self
+            .lag
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((topic, partition), lag)| {
+                let labels = vec![
+                    ("topic", topic.clone()),
+                    ("partition", partition.to_string()),
+                ];
+                (labels, *lag as f64)
+            })
+            .collect();
+        write_metric(&mut out, &LAG, lag);
+
+        let histogram = self.schema_fetch.lock().unwrap();
+        write_header(&mut out, &SCHEMA_FETCH);
+        let mut cumulative = 0;
+        let bounds = LATENCY_BUCKETS.iter().map(|le| le.to_string());
+        let overflow = histogram.count - histogram.buckets.iter().sum::<u64>();
+        for (le, n) in bounds
+            .chain(Some("+Inf".to_string()))
+            .zip(histogram.buckets.iter().copied().chain(Some(overflow)))
+        {
+            cumulative += n;
+            let labels = format_labels(&[("le", le)]);
+            let _ = writeln!(out, "{}_bucket{} {}", SCHEMA_FETCH.name, labels, cumulative);
+        }
+        let _ = writeln!(out, "{}_sum {}", SCHEMA_FETCH.name, histogram.sum);
+        let _ = writeln!(out, "{}_count {}", SCHEMA_FETCH.name, histogram.count);
+
+        let result = |r: &str| vec![("result", r.to_string())];
+        let cache = vec![
+            (result("hit"), load(&self.descriptor_hits)),
+            (result("miss"), load(&self.descriptor_misses)),
+        ];
+        write_metric(&mut out, &DESCRIPTOR_CACHE, cache);
+        write_metric(
+            &mut out,
+            &OUTPUT_RECORDS,
+            vec![(vec![], load(&self.output_records))],
+        );
+        write_metric(
+            &mut out,
+            &OUTPUT_BYTES,
+            vec![(vec![], load(&self.output_bytes))],
+        );
+        out
+    }
+}
+
+pub fn response(metrics: &Metrics) -> Response {
+    warp::http::Response::builder()
+        .header("content-type", "text/plain; version=0.0.4")
+        .body(metrics.render().into())
+        .unwrap_or_default()
+}
+
+/// `GET /metrics`
+pub fn route(
+    metrics: Arc<Metrics>,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .map(move || response(&metrics))
+}
+
+/// Serves `metrics` on `addr` from a thread of its own until the process exits
+pub fn serve_in_background(metrics: Arc<Metrics>, addr: SocketAddr) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let (addr, server) = runtime
+        .block_on(async { warp::serve(route(metrics)).try_bind_ephemeral(addr) })
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::AddrInUse, e))
+        .with_context(|| format!("serving metrics on {}", addr))?;
+    info!(addr = addr.to_string(), "serving metrics");
+    std::thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || runtime.block_on(server))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::error::Error;
+    use crate::parse::metrics::{output_size, route, Metrics};
+    use crate::parse::msg::{Msg, ParsedKey};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_render() {
+        let metrics = Arc::new(Metrics::default());
+        let msg = Msg {
+            topic: "orders".to_string(),
+            partition: 0,
+            offset: 9,
+            ts: 0,
+            key: ParsedKey::None,
+            key_len: 0,
+            msg: vec![0, 0, 0, 0, 7, 0],
+            msg_len: 6,
+            headers: vec![],
+        };
+        metrics.consumed(&msg);
+        metrics.consumed(&msg);
+        metrics.decoded(&msg);
+        metrics.failed(&msg, &Error::Eof);
+        metrics.lag("orders", 0, 5);
+        metrics.descriptor_cache(false);
+        metrics.schema_fetch(Duration::from_millis(30));
+        metrics.schema_fetch(Duration::from_secs(60));
+        metrics.written(output_size(&["{}".to_string()]));
+
+        let res = warp::test::request()
+            .path("/metrics")
+            .reply(&route(metrics))
+            .await;
+        assert_eq!(res.status(), 200);
+        let body = String::from_utf8(res.body().to_vec()).unwrap();
+        for line in [
+            "# TYPE proto2json_records_consumed_total counter",
+            "proto2json_records_consumed_total{topic=\"orders\",schema_id=\"7\"} 2",
+            "proto2json_records_failed_total{topic=\"orders\",schema_id=\"7\",error=\"Eof\"} 1",
+            "proto2json_consumer_lag{topic=\"orders\",partition=\"0\"} 5",
+            "proto2json_descriptor_cache_total{result=\"miss\"} 1",
+            "proto2json_schema_fetch_seconds_bucket{le=\"0.025\"} 0",
+            "proto2json_schema_fetch_seconds_bucket{le=\"0.05\"} 1",
+            "proto2json_schema_fetch_seconds_bucket{le=\"+Inf\"} 2",
+            "proto2json_schema_fetch_seconds_count 2",
+            "proto2json_output_bytes_total 3",
+        ] {
+            assert!(
+                body.lines().any(|l| l == line),
+                "{} missing in\n{}",
+                line,
+                body
+            );
+        }
+    }
+}
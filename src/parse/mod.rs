@@ -1,8 +1,24 @@
+pub mod census;
+pub mod checkpoint;
 pub mod confluent;
+pub mod decoder;
+pub mod dlq;
+pub mod envelope;
 pub mod error;
+pub mod file;
+pub mod filter;
 pub mod http;
 pub mod kafka;
 pub mod kcat;
+pub mod kcat_json;
+pub mod metrics;
 pub mod msg;
+pub mod pcap;
+pub mod pipeline;
 pub mod proto2json;
 pub mod protobuf;
+pub mod redact;
+pub mod segment;
+pub mod sink;
+pub mod source;
+pub mod stream;
@@ -1,4 +1,4 @@
-
+use base64::Engine;
 
 #[derive(Debug)]
 pub struct Msg {
@@ -10,16 +10,58 @@ pub struct Msg {
     pub key_len: usize,
     pub msg: Vec<u8>,
     pub msg_len: usize,
+    pub headers: Vec<(String, ParsedKey)>,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+/// A key or header value. In JSON, UTF-8 ones are plain strings, others `{"base64": ...}` and
+/// missing ones null.
+#[derive(Debug, Clone, Default)]
 pub enum ParsedKey {
+    #[default]
     None,
     Utf8(String),
     NotUtf8(Vec<u8>),
 }
 
+/// JSON form of a present `ParsedKey`
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum KeyJson {
+    Utf8(String),
+    Base64 { base64: String },
+}
+
+impl serde::Serialize for ParsedKey {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            ParsedKey::None => s.serialize_none(),
+            ParsedKey::Utf8(v) => s.serialize_str(v),
+            ParsedKey::NotUtf8(v) => KeyJson::Base64 {
+                base64: base64::engine::general_purpose::STANDARD.encode(v),
+            }
+            .serialize(s),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ParsedKey {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        Ok(match Option::<KeyJson>::deserialize(d)? {
+            None => ParsedKey::None,
+            Some(KeyJson::Utf8(v)) => ParsedKey::Utf8(v),
+            Some(KeyJson::Base64 { base64 }) => ParsedKey::NotUtf8(
+                base64::engine::general_purpose::STANDARD
+                    .decode(base64)
+                    .map_err(serde::de::Error::custom)?,
+            ),
+        })
+    }
+}
+
 impl ParsedKey {
+    pub fn from_option(v: Option<&[u8]>) -> Self {
+        v.map(ParsedKey::new).unwrap_or(ParsedKey::None)
+    }
     pub fn new(v: &[u8]) -> Self {
         if let Ok(s) = String::from_utf8(v.to_vec()) {
             ParsedKey::Utf8(s)
@@ -27,6 +69,13 @@ impl ParsedKey {
             ParsedKey::NotUtf8(v.to_vec())
         }
     }
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            ParsedKey::None => None,
+            ParsedKey::Utf8(s) => Some(s.as_bytes()),
+            ParsedKey::NotUtf8(s) => Some(s.as_slice()),
+        }
+    }
     pub fn len(&self) -> usize {
         match self {
             ParsedKey::None => 0,
@@ -0,0 +1,987 @@
+use crate::parse::error::*;
+use crate::parse::msg::{Msg, ParsedKey};
+use crate::parse::segment::parse_record_batches;
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read};
+use std::net::{IpAddr, Ipv4Addr};
+use tracing::{debug, trace, warn};
+
+const PCAP_MICROS: u32 = 0xa1b2c3d4;
+const PCAP_NANOS: u32 = 0xa1b23c4d;
+const PCAPNG_SHB: u32 = 0x0a0d0d0a;
+const PCAPNG_BYTE_ORDER: u32 = 0x1a2b3c4d;
+const PCAPNG_IDB: u32 = 1;
+const PCAPNG_SPB: u32 = 3;
+const PCAPNG_EPB: u32 = 6;
+
+const API_PRODUCE: i16 = 0;
+const API_FETCH: i16 = 1;
+
+/// frames larger than this mean the stream is not aligned on kafka frames
+const MAX_FRAME_LEN: usize = 256 * 1024 * 1024;
+/// Bytes buffered past a gap in a tcp stream before the missing segment is given up on
+const MAX_AHEAD_LEN: usize = 16 * 1024 * 1024;
+
+/// Header names under which messages of a capture record where they were seen
+pub const HEADER_CLIENT_ID: &str = "pcap.client_id";
+pub const HEADER_CAPTURE_TS: &str = "pcap.capture_ts";
+
+#[derive(Debug)]
+pub enum PcapError {
+    UnknownMagic(u32),
+    UnsupportedLinkType(u32),
+}
+
+/// A captured frame with its capture time in milliseconds
+struct Packet {
+    ts: i64,
+    link_type: u32,
+    data: Vec<u8>,
+}
+
+enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    fn u16(&self, b: &[u8]) -> u16 {
+        match self {
+            Endian::Little => LittleEndian::read_u16(b),
+            Endian::Big => BigEndian::read_u16(b),
+        }
+    }
+    fn u32(&self, b: &[u8]) -> u32 {
+        match self {
+            Endian::Little => LittleEndian::read_u32(b),
+            Endian::Big => BigEndian::read_u32(b),
+        }
+    }
+}
+
+enum Format {
+    Pcap {
+        endian: Endian,
+        nanos: bool,
+        link_type: u32,
+    },
+    PcapNg {
+        endian: Endian,
+        /// link type and timestamp units per second of every interface
+        interfaces: Vec<(u32, u64)>,
+    },
+}
+
+/// Frames of a pcap or pcapng capture
+struct Capture<R> {
+    r: BufReader<R>,
+    format: Format,
+}
+
+impl<R: Read> Capture<R> {
+    fn new(r: R) -> Result<Self> {
+        let mut r = BufReader::new(r);
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        let format = match (BigEndian::read_u32(&magic), LittleEndian::read_u32(&magic)) {
+            (PCAPNG_SHB, _) => {
+                let mut header = [0u8; 8];
+                r.read_exact(&mut header)?;
+                let endian = match LittleEndian::read_u32(&header[4..]) {
+                    PCAPNG_BYTE_ORDER => Endian::Little,
+                    _ => Endian::Big,
+                };
+                let len = endian.u32(&header[..4]) as usize;
+                skip(&mut r, len.saturating_sub(12))?;
+                Format::PcapNg {
+                    endian,
+                    interfaces: vec![],
+                }
+            }
+            (m @ (PCAP_MICROS | PCAP_NANOS), _) | (_, m @ (PCAP_MICROS | PCAP_NANOS)) => {
+                let endian = if BigEndian::read_u32(&magic) == m {
+                    Endian::Big
+                } else {
+                    Endian::Little
+                };
+                let mut header = [0u8; 20];
+                r.read_exact(&mut header)?;
+                Format::Pcap {
+                    link_type: endian.u32(&header[16..]),
+                    endian,
+                    nanos: m == PCAP_NANOS,
+                }
+            }
+            (m, _) => return Err(PcapError::UnknownMagic(m).into()),
+        };
+        Ok(Capture { r, format })
+    }
+
+    fn next_packet(&mut self) -> Result<Option<Packet>> {
+        match &mut self.format {
+            Format::Pcap {
+                endian,
+                nanos,
+                link_type,
+            } => {
+                let mut header = [0u8; 16];
+                if !read_or_eof(&mut self.r, &mut header)? {
+                    return Ok(None);
+                }
+                let secs = endian.u32(&header[..4]) as i64;
+                let frac = endian.u32(&header[4..8]) as i64;
+                let len = endian.u32(&header[8..12]) as usize;
+                let data = read_len(&mut self.r, len)?;
+                Ok(Some(Packet {
+                    ts: secs * 1000
+                        + if *nanos {
+                            frac / 1_000_000
+                        } else {
+                            frac / 1000
+                        },
+                    link_type: *link_type,
+                    data,
+                }))
+            }
+            Format::PcapNg { endian, interfaces } => loop {
+                let mut header = [0u8; 8];
+                if !read_or_eof(&mut self.r, &mut header)? {
+                    return Ok(None);
+                }
+                let block_type = endian.u32(&header[..4]);
+                let len = endian.u32(&header[4..]) as usize;
+                let body = read_len(&mut self.r, len.saturating_sub(12))?;
+                skip(&mut self.r, 4)?;
+                if body.len() < 20 && block_type != PCAPNG_SHB && block_type != PCAPNG_IDB {
+                    continue;
+                }
+                match block_type {
+                    PCAPNG_SHB => {
+                        // a new section starts over with its own interfaces
+                        *endian = match LittleEndian::read_u32(body.get(..4).ok_or(Error::Eof)?) {
+                            PCAPNG_BYTE_ORDER => Endian::Little,
+                            _ => Endian::Big,
+                        };
+                        interfaces.clear();
+                    }
+                    PCAPNG_IDB => {
+                        let link_type = endian.u16(body.get(..2).ok_or(Error::Eof)?) as u32;
+                        interfaces.push((
+                            link_type,
+                            ts_resolution(endian, body.get(8..).unwrap_or_default()),
+                        ));
+                    }
+                    PCAPNG_EPB => {
+                        let interface = endian.u32(&body[..4]) as usize;
+                        let (link_type, units) =
+                            interfaces.get(interface).copied().unwrap_or((1, 1_000_000));
+                        let ts = ((endian.u32(&body[4..8]) as u64) << 32)
+                            | endian.u32(&body[8..12]) as u64;
+                        let cap_len = endian.u32(&body[12..16]) as usize;
+                        let data = body.get(20..20 + cap_len).ok_or(Error::Eof)?.to_vec();
+                        return Ok(Some(Packet {
+                            ts: (ts as u128 * 1000 / units as u128) as i64,
+                            link_type,
+                            data,
+                        }));
+                    }
+                    PCAPNG_SPB => {
+                        let (link_type, _) = interfaces.first().copied().unwrap_or((1, 1_000_000));
+                        let orig_len = endian.u32(&body[..4]) as usize;
+                        let data = body[4..].iter().take(orig_len).copied().collect();
+                        return Ok(Some(Packet {
+                            ts: -1,
+                            link_type,
+                            data,
+                        }));
+                    }
+                    other => trace!(block_type = other, "skipping pcapng block"),
+                }
+            },
+        }
+    }
+}
+
+/// Timestamp units per second from the if_tsresol option of an interface description block
+fn ts_resolution(endian: &Endian, mut options: &[u8]) -> u64 {
+    while options.len() >= 4 {
+        let code = endian.u16(&options[..2]);
+        let len = endian.u16(&options[2..4]) as usize;
+        if code == 0 {
+            break;
+        }
+        if code == 9 && len == 1 && options.len() > 4 {
+            let v = options[4];
+            return if v & 0x80 == 0 {
+                10u64.saturating_pow(v as u32)
+            } else {
+                1u64.checked_shl((v & 0x7f) as u32).unwrap_or(u64::MAX)
+            };
+        }
+        options = options.get(4 + len.div_ceil(4) * 4..).unwrap_or_default();
+    }
+    1_000_000
+}
+
+/// false at a clean end of the input
+fn read_or_eof<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match r.read(&mut buf[read..])? {
+            0 if read == 0 => return Ok(false),
+            0 => return Err(Error::Eof),
+            n => read += n,
+        }
+    }
+    Ok(true)
+}
+
+/// `len` bytes, which grow as they are read, the length of a corrupt capture isn't trusted with
+/// an allocation
+fn read_len<R: Read>(r: &mut R, len: usize) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    r.take(len as u64).read_to_end(&mut data)?;
+    if data.len() < len {
+        return Err(Error::Eof);
+    }
+    Ok(data)
+}
+
+fn skip<R: Read>(r: &mut R, n: usize) -> Result<()> {
+    std::io::copy(&mut r.take(n as u64), &mut std::io::sink())?;
+    Ok(())
+}
+
+/// One direction of a tcp connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Flow {
+    src: (IpAddr, u16),
+    dst: (IpAddr, u16),
+}
+
+struct Segment<'a> {
+    flow: Flow,
+    seq: u32,
+    syn: bool,
+    payload: &'a [u8],
+}
+
+/// The tcp segment of a frame, None for anything else
+fn tcp_segment(link_type: u32, frame: &[u8]) -> Result<Option<Segment<'_>>> {
+    let (ether_type, ip) = match link_type {
+        // ethernet, possibly vlan tagged
+        1 => {
+            let mut pos = 12;
+            let mut ether_type = BigEndian::read_u16(frame.get(pos..pos + 2).ok_or(Error::Eof)?);
+            while ether_type == 0x8100 || ether_type == 0x88a8 {
+                pos += 4;
+                ether_type = BigEndian::read_u16(frame.get(pos..pos + 2).ok_or(Error::Eof)?);
+            }
+            (ether_type, &frame[pos + 2..])
+        }
+        // BSD loopback, address family in host byte order
+        0 => {
+            let family = frame.get(..4).ok_or(Error::Eof)?;
+            let family = LittleEndian::read_u32(family).min(BigEndian::read_u32(family));
+            let ether_type = if family == 2 { 0x0800 } else { 0x86dd };
+            (ether_type, &frame[4..])
+        }
+        // linux cooked capture v1 and v2
+        113 => (
+            BigEndian::read_u16(frame.get(14..16).ok_or(Error::Eof)?),
+            &frame[16..],
+        ),
+        276 => (
+            BigEndian::read_u16(frame.get(..2).ok_or(Error::Eof)?),
+            frame.get(20..).ok_or(Error::Eof)?,
+        ),
+        // raw ip
+        12 | 101 | 228 | 229 => match frame.first().map(|b| b >> 4) {
+            Some(4) => (0x0800, frame),
+            _ => (0x86dd, frame),
+        },
+        other => return Err(PcapError::UnsupportedLinkType(other).into()),
+    };
+    let (src, dst, tcp) = match ether_type {
+        0x0800 => {
+            if ip.len() < 20 || ip[9] != 6 {
+                return Ok(None);
+            }
+            if BigEndian::read_u16(&ip[6..8]) & 0x3fff != 0 {
+                debug!("skipping fragmented ip packet");
+                return Ok(None);
+            }
+            let header_len = (ip[0] & 0x0f) as usize * 4;
+            let total_len = (BigEndian::read_u16(&ip[2..4]) as usize).min(ip.len());
+            let src = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+            let dst = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+            (
+                src.into(),
+                dst.into(),
+                ip.get(header_len..total_len).ok_or(Error::Eof)?,
+            )
+        }
+        0x86dd => {
+            // extension headers are not followed
+            if ip.len() < 40 || ip[6] != 6 {
+                return Ok(None);
+            }
+            let payload_len = BigEndian::read_u16(&ip[4..6]) as usize;
+            let mut src = [0u8; 16];
+            src.copy_from_slice(&ip[8..24]);
+            let mut dst = [0u8; 16];
+            dst.copy_from_slice(&ip[24..40]);
+            let end = (40 + payload_len).min(ip.len());
+            (IpAddr::from(src), IpAddr::from(dst), &ip[40..end])
+        }
+        _ => return Ok(None),
+    };
+    if tcp.len() < 20 {
+        return Ok(None);
+    }
+    let data_offset = (tcp[12] >> 4) as usize * 4;
+    Ok(Some(Segment {
+        flow: Flow {
+            src: (src, BigEndian::read_u16(&tcp[..2])),
+            dst: (dst, BigEndian::read_u16(&tcp[2..4])),
+        },
+        seq: BigEndian::read_u32(&tcp[4..8]),
+        syn: tcp[13] & 0x02 != 0,
+        payload: tcp.get(data_offset..).unwrap_or_default(),
+    }))
+}
+
+/// Reassembles one direction of a tcp connection and splits it into kafka frames
+#[derive(Default)]
+struct Stream {
+    next_seq: Option<u32>,
+    /// segments received ahead of next_seq
+    ahead: BTreeMap<u32, Vec<u8>>,
+    /// total length of the segments in ahead
+    ahead_len: usize,
+    buf: Vec<u8>,
+}
+
+impl Stream {
+    fn add(&mut self, seq: u32, syn: bool, payload: &[u8]) {
+        if syn {
+            *self = Stream {
+                next_seq: Some(seq.wrapping_add(1)),
+                ..Default::default()
+            };
+            return;
+        }
+        if payload.is_empty() {
+            return;
+        }
+        let next = *self.next_seq.get_or_insert(seq);
+        let ahead = seq.wrapping_sub(next) as i32;
+        if ahead > 0 {
+            self.ahead_len += payload.len();
+            if let Some(old) = self.ahead.insert(seq, payload.to_vec()) {
+                self.ahead_len -= old.len();
+            }
+            if self.ahead_len > MAX_AHEAD_LEN {
+                // the missing segment wasn't captured, start over with the next segment
+                warn!(
+                    next_seq = next,
+                    "gap in tcp stream never filled, dropping buffered bytes"
+                );
+                *self = Stream::default();
+            }
+            return;
+        }
+        self.append(seq, ahead.unsigned_abs() as usize, payload);
+        while let Some(seq) = self.ahead.keys().next().copied() {
+            let ahead = seq.wrapping_sub(self.next_seq.unwrap_or(seq)) as i32;
+            if ahead > 0 {
+                break;
+            }
+            let payload = self.ahead.remove(&seq).unwrap_or_default();
+            self.ahead_len -= payload.len();
+            self.append(seq, ahead.unsigned_abs() as usize, &payload);
+        }
+    }
+
+    /// Appends `payload` of a segment starting `overlap` bytes before next_seq
+    fn append(&mut self, seq: u32, overlap: usize, payload: &[u8]) {
+        // retransmissions overlap what was already seen
+        if overlap < payload.len() {
+            self.buf.extend_from_slice(&payload[overlap..]);
+            self.next_seq = Some(seq.wrapping_add(payload.len() as u32));
+        }
+    }
+
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        if self.buf.len() < 4 {
+            return None;
+        }
+        let len = BigEndian::read_i32(&self.buf[..4]);
+        if len < 0 || len as usize > MAX_FRAME_LEN {
+            // capture started in the middle of a frame, the rest of this direction is lost
+            warn!(
+                len = len,
+                "invalid kafka frame length, dropping buffered bytes"
+            );
+            self.buf.clear();
+            return None;
+        }
+        let end = 4 + len as usize;
+        if self.buf.len() < end {
+            return None;
+        }
+        let frame = self.buf[4..end].to_vec();
+        self.buf.drain(..end);
+        Some(frame)
+    }
+}
+
+struct Request {
+    api_key: i16,
+    api_version: i16,
+    client_id: Option<String>,
+}
+
+/// Reads the kafka protocol's primitive types, flexible versions use compact encodings
+struct Wire<'a> {
+    rdr: Cursor<&'a [u8]>,
+    flexible: bool,
+}
+
+impl<'a> Wire<'a> {
+    fn new(data: &'a [u8], flexible: bool) -> Self {
+        Wire {
+            rdr: Cursor::new(data),
+            flexible,
+        }
+    }
+
+    fn uvarint(&mut self) -> Result<u64> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let b = self.rdr.read_u8()?;
+            value |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(crate::parse::confluent::VarintError::InvalidVarint.into())
+    }
+
+    fn i16(&mut self) -> Result<i16> {
+        Ok(self.rdr.read_i16::<BigEndian>()?)
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(self.rdr.read_i32::<BigEndian>()?)
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(self.rdr.read_i64::<BigEndian>()?)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let start = self.rdr.position() as usize;
+        let data = *self.rdr.get_ref();
+        let end = start.checked_add(len).ok_or(Error::Eof)?;
+        let out = data.get(start..end).ok_or(Error::Eof)?;
+        self.rdr.set_position(end as u64);
+        Ok(out)
+    }
+
+    /// length of a nullable string, bytes or array, None meaning null
+    fn len(
+        &mut self,
+        flexible: bool,
+        classic: impl FnOnce(&mut Self) -> Result<i32>,
+    ) -> Result<Option<usize>> {
+        if flexible {
+            Ok(self.uvarint()?.checked_sub(1).map(|l| l as usize))
+        } else {
+            let len = classic(self)?;
+            Ok(if len < 0 { None } else { Some(len as usize) })
+        }
+    }
+
+    /// request headers keep the classic encoding of the client id in flexible versions
+    fn classic_string(&mut self) -> Result<Option<String>> {
+        match self.len(false, |w| w.i16().map(i32::from))? {
+            Some(len) => Ok(Some(String::from_utf8(self.take(len)?.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn string(&mut self) -> Result<Option<String>> {
+        match self.len(self.flexible, |w| w.i16().map(i32::from))? {
+            Some(len) => Ok(Some(String::from_utf8(self.take(len)?.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Option<&'a [u8]>> {
+        match self.len(self.flexible, Self::i32)? {
+            Some(len) => Ok(Some(self.take(len)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn array(&mut self) -> Result<usize> {
+        Ok(self.len(self.flexible, Self::i32)?.unwrap_or(0))
+    }
+
+    fn uuid(&mut self) -> Result<String> {
+        Ok(hex::encode(self.take(16)?))
+    }
+
+    fn tagged_fields(&mut self) -> Result<()> {
+        if self.flexible {
+            for _ in 0..self.uvarint()? {
+                let _tag = self.uvarint()?;
+                let len = self.uvarint()? as usize;
+                self.take(len)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn with_capture_headers(mut msgs: Vec<Msg>, client_id: &Option<String>, ts: i64) -> Vec<Msg> {
+    for msg in msgs.iter_mut() {
+        msg.headers.push((
+            HEADER_CLIENT_ID.to_string(),
+            ParsedKey::from_option(client_id.as_deref().map(str::as_bytes)),
+        ));
+        msg.headers.push((
+            HEADER_CAPTURE_TS.to_string(),
+            ParsedKey::Utf8(ts.to_string()),
+        ));
+    }
+    msgs
+}
+
+/// Record batches of a produce request body, v3 and later
+fn parse_produce(w: &mut Wire, version: i16) -> Result<Vec<(String, i64, Vec<Msg>)>> {
+    let _transactional_id = w.string()?;
+    let _acks = w.i16()?;
+    let _timeout = w.i32()?;
+    let mut out = vec![];
+    for _ in 0..w.array()? {
+        let topic = if version >= 13 {
+            w.uuid()?
+        } else {
+            w.string()?.unwrap_or_default()
+        };
+        for _ in 0..w.array()? {
+            let partition = w.i32()? as i64;
+            if let Some(records) = w.bytes()? {
+                out.push((
+                    topic.clone(),
+                    partition,
+                    parse_record_batches(&topic, partition, records)?,
+                ));
+            }
+            w.tagged_fields()?;
+        }
+        w.tagged_fields()?;
+    }
+    Ok(out)
+}
+
+/// Record batches of a fetch response body, v4 and later
+fn parse_fetch(w: &mut Wire, version: i16) -> Result<Vec<(String, i64, Vec<Msg>)>> {
+    let _throttle_time = w.i32()?;
+    if version >= 7 {
+        let _error_code = w.i16()?;
+        let _session_id = w.i32()?;
+    }
+    let mut out = vec![];
+    for _ in 0..w.array()? {
+        let topic = if version >= 13 {
+            w.uuid()?
+        } else {
+            w.string()?.unwrap_or_default()
+        };
+        for _ in 0..w.array()? {
+            let partition = w.i32()? as i64;
+            let _error_code = w.i16()?;
+            let _high_watermark = w.i64()?;
+            let _last_stable_offset = w.i64()?;
+            if version >= 5 {
+                let _log_start_offset = w.i64()?;
+            }
+            for _ in 0..w.array()? {
+                let _producer_id = w.i64()?;
+                let _first_offset = w.i64()?;
+                w.tagged_fields()?;
+            }
+            if version >= 11 {
+                let _preferred_read_replica = w.i32()?;
+            }
+            if let Some(records) = w.bytes()? {
+                out.push((
+                    topic.clone(),
+                    partition,
+                    parse_record_batches(&topic, partition, records)?,
+                ));
+            }
+            w.tagged_fields()?;
+        }
+        w.tagged_fields()?;
+    }
+    Ok(out)
+}
+
+/// Messages of the kafka produce requests and fetch responses found in a pcap or pcapng capture.
+///
+/// Traffic to and from `broker_ports` is reassembled per tcp connection. Requests are matched
+/// to their responses by correlation id, so connections must be captured from their start for
+/// fetch responses to be recognised. Every message gets the client id of the request and the
+/// capture time as headers. Produce requests before v3 and fetch responses before v4 carry the
+/// old message format and are skipped.
+pub struct Parser<R> {
+    capture: Capture<R>,
+    broker_ports: Vec<u16>,
+    streams: HashMap<Flow, Stream>,
+    /// in flight requests of every connection, keyed by the client to broker flow
+    requests: HashMap<Flow, HashMap<i32, Request>>,
+    pending: VecDeque<Result<Msg>>,
+    done: bool,
+}
+
+impl<R: Read> Parser<R> {
+    pub fn new(r: R, broker_ports: &[u16]) -> Result<Self> {
+        Ok(Parser {
+            capture: Capture::new(r)?,
+            broker_ports: broker_ports.to_vec(),
+            streams: HashMap::new(),
+            requests: HashMap::new(),
+            pending: VecDeque::new(),
+            done: false,
+        })
+    }
+
+    fn add_packet(&mut self, packet: Packet) -> Result<()> {
+        let segment = match tcp_segment(packet.link_type, &packet.data) {
+            Ok(Some(segment)) => segment,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                // a truncated or unsupported packet doesn't end the capture
+                warn!("skipping packet: {:?}", e);
+                return Ok(());
+            }
+        };
+        let to_broker = self.broker_ports.contains(&segment.flow.dst.1);
+        if !to_broker && !self.broker_ports.contains(&segment.flow.src.1) {
+            return Ok(());
+        }
+        let flow = segment.flow;
+        let stream = self.streams.entry(flow).or_default();
+        stream.add(segment.seq, segment.syn, segment.payload);
+        let mut frames = vec![];
+        while let Some(frame) = stream.next_frame() {
+            frames.push(frame);
+        }
+        for frame in frames {
+            let result = if to_broker {
+                self.request(flow, &frame, packet.ts)
+            } else {
+                let request_flow = Flow {
+                    src: flow.dst,
+                    dst: flow.src,
+                };
+                self.response(request_flow, &frame, packet.ts)
+            };
+            if let Err(e) = result {
+                // a single malformed frame doesn't desynchronize the stream
+                warn!(
+                    flow = format!("{:?}", flow).as_str(),
+                    "skipping kafka frame: {:?}", e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&mut self, flow: Flow, frame: &[u8], ts: i64) -> Result<()> {
+        let mut w = Wire::new(frame, false);
+        let api_key = w.i16()?;
+        let api_version = w.i16()?;
+        let correlation_id = w.i32()?;
+        let client_id = w.classic_string()?;
+        trace!(
+            api_key = api_key,
+            api_version = api_version,
+            correlation_id = correlation_id,
+            "request"
+        );
+        if api_key == API_PRODUCE {
+            if api_version < 3 {
+                debug!(
+                    api_version = api_version,
+                    "skipping produce request in the old message format"
+                );
+            } else {
+                w.flexible = api_version >= 9;
+                w.tagged_fields()?;
+                for (_, _, msgs) in parse_produce(&mut w, api_version)? {
+                    self.pending.extend(
+                        with_capture_headers(msgs, &client_id, ts)
+                            .into_iter()
+                            .map(Ok),
+                    );
+                }
+            }
+        }
+        if api_key == API_FETCH {
+            self.requests.entry(flow).or_default().insert(
+                correlation_id,
+                Request {
+                    api_key,
+                    api_version,
+                    client_id,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn response(&mut self, flow: Flow, frame: &[u8], ts: i64) -> Result<()> {
+        let correlation_id = BigEndian::read_i32(frame.get(..4).ok_or(Error::Eof)?);
+        let request = match self
+            .requests
+            .get_mut(&flow)
+            .and_then(|r| r.remove(&correlation_id))
+        {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        if request.api_key != API_FETCH {
+            return Ok(());
+        }
+        if request.api_version < 4 {
+            debug!(
+                api_version = request.api_version,
+                "skipping fetch response in the old message format"
+            );
+            return Ok(());
+        }
+        let mut w = Wire::new(&frame[4..], request.api_version >= 12);
+        w.tagged_fields()?;
+        for (_, _, msgs) in parse_fetch(&mut w, request.api_version)? {
+            self.pending.extend(
+                with_capture_headers(msgs, &request.client_id, ts)
+                    .into_iter()
+                    .map(Ok),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for Parser<R> {
+    type Item = Result<Msg>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(msg) = self.pending.pop_front() {
+                return Some(msg);
+            }
+            if self.done {
+                return None;
+            }
+            let result = match self.capture.next_packet() {
+                Ok(Some(packet)) => self.add_packet(packet),
+                Ok(None) => {
+                    self.done = true;
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Messages of every capture in turn, stdin if there are none
+pub fn read_captures(
+    paths: &[String],
+    broker_ports: &[u16],
+) -> Result<Box<dyn Iterator<Item = Result<Msg>>>> {
+    if paths.is_empty() {
+        return Ok(Box::new(Parser::new(std::io::stdin(), broker_ports)?));
+    }
+    let broker_ports = broker_ports.to_vec();
+    Ok(Box::new(Vec::from(paths).into_iter().flat_map(
+        move |path| {
+            debug!(path = path.as_str(), "capture");
+            let it: Box<dyn Iterator<Item = Result<Msg>>> = match File::open(&path)
+                .map_err(Error::from)
+                .and_then(|f| Parser::new(f, &broker_ports))
+            {
+                Ok(parser) => Box::new(parser),
+                Err(e) => Box::new(std::iter::once(Err(e))),
+            };
+            it
+        },
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::msg::ParsedKey;
+    use crate::parse::pcap::{Parser, Stream, Wire, HEADER_CLIENT_ID, MAX_AHEAD_LEN};
+    use crate::parse::segment::test::{batch, record};
+
+    fn frame(src_port: u16, dst_port: u16, seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut tcp = vec![];
+        tcp.extend_from_slice(&src_port.to_be_bytes());
+        tcp.extend_from_slice(&dst_port.to_be_bytes());
+        tcp.extend_from_slice(&seq.to_be_bytes());
+        tcp.extend_from_slice(&0u32.to_be_bytes());
+        tcp.extend_from_slice(&[0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        tcp.extend_from_slice(payload);
+        let mut ip = vec![0x45, 0];
+        ip.extend_from_slice(&(20 + tcp.len() as u16).to_be_bytes());
+        ip.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0, 127, 0, 0, 1, 127, 0, 0, 1]);
+        ip.extend(tcp);
+        let mut eth = vec![0u8; 12];
+        eth.extend_from_slice(&[0x08, 0x00]);
+        eth.extend(ip);
+        eth
+    }
+
+    fn pcap(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+        out.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        out.extend_from_slice(&65535u32.to_le_bytes());
+        out.extend_from_slice(&1u32.to_le_bytes());
+        for (i, f) in frames.iter().enumerate() {
+            out.extend_from_slice(&1683000000u32.to_le_bytes());
+            out.extend_from_slice(&(i as u32 * 1000).to_le_bytes());
+            out.extend_from_slice(&(f.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(f.len() as u32).to_le_bytes());
+            out.extend_from_slice(f);
+        }
+        out
+    }
+
+    fn kafka_frame(body: &[u8]) -> Vec<u8> {
+        let mut out = (body.len() as i32).to_be_bytes().to_vec();
+        out.extend_from_slice(body);
+        out
+    }
+
+    #[test]
+    fn test_pcap() {
+        let records = batch(0, 0, 1, &record(0, Some(b"k"), b"produced", &[]));
+        let mut produce = vec![0, 0, 0, 3, 0, 0, 0, 1, 0, 3];
+        produce.extend_from_slice(b"app");
+        produce.extend_from_slice(&[0xff, 0xff, 0, 1, 0, 0, 0x75, 0x30, 0, 0, 0, 1, 0, 6]);
+        produce.extend_from_slice(b"orders");
+        produce.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2]);
+        produce.extend_from_slice(&(records.len() as i32).to_be_bytes());
+        produce.extend_from_slice(&records);
+        let produce = kafka_frame(&produce);
+
+        let mut fetch_request = vec![0, 1, 0, 4, 0, 0, 0, 2, 0xff, 0xff];
+        fetch_request.extend_from_slice(&[0; 8]);
+        let fetch_request = kafka_frame(&fetch_request);
+
+        let records = batch(7, 0, 1, &record(0, None, b"fetched", &[]));
+        let mut fetch = vec![0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 6];
+        fetch.extend_from_slice(b"orders");
+        fetch.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 5, 0, 0]);
+        fetch.extend_from_slice(&[0; 16]);
+        fetch.extend_from_slice(&(-1i32).to_be_bytes());
+        // the broker cuts the last batch short
+        let truncated = &records[..records.len() - 3];
+        let mut all = records.clone();
+        all.extend_from_slice(truncated);
+        fetch.extend_from_slice(&(all.len() as i32).to_be_bytes());
+        fetch.extend_from_slice(&all);
+        let fetch = kafka_frame(&fetch);
+
+        let (a, b) = produce.split_at(10);
+        let frames = [
+            frame(50000, 9092, 100, 0x02, &[]),
+            // truncated packets are skipped
+            vec![0; 13],
+            // out of order and retransmitted segments
+            frame(50000, 9092, 101 + a.len() as u32, 0x18, b),
+            frame(50000, 9092, 101, 0x18, a),
+            frame(50000, 9092, 101, 0x18, a),
+            frame(
+                50000,
+                9092,
+                101 + produce.len() as u32,
+                0x18,
+                &fetch_request,
+            ),
+            frame(9092, 50000, 500, 0x12, &[]),
+            frame(9092, 50000, 501, 0x18, &fetch),
+        ];
+        let input = pcap(&frames);
+        let msgs = Parser::new(input.as_slice(), &[9092])
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(msgs.len(), 2);
+        assert_eq!((msgs[0].topic.as_str(), msgs[0].partition), ("orders", 2));
+        assert_eq!(msgs[0].msg, b"produced");
+        assert!(
+            matches!(&msgs[0].headers[0], (n, ParsedKey::Utf8(v)) if n == HEADER_CLIENT_ID && v == "app")
+        );
+        assert_eq!((msgs[1].partition, msgs[1].offset), (5, 7));
+        assert_eq!(msgs[1].msg, b"fetched");
+        assert!(matches!(&msgs[1].headers[0].1, ParsedKey::None));
+        assert!(matches!(&msgs[1].headers[1].1, ParsedKey::Utf8(ts) if ts == "1683000000007"));
+    }
+
+    #[test]
+    fn test_stream_gap() {
+        let mut stream = Stream::default();
+        stream.add(0, true, &[]);
+        // the segment at 1 never arrives
+        let segment = vec![0; 1024 * 1024];
+        for i in 0..=MAX_AHEAD_LEN / segment.len() {
+            stream.add(1000 + (i * segment.len()) as u32, false, &segment);
+        }
+        assert!(stream.ahead.is_empty());
+        stream.add(5, false, &kafka_frame(b"next"));
+        assert_eq!(stream.next_frame().unwrap(), b"next");
+    }
+
+    #[test]
+    fn test_stream_drains_ahead() {
+        let mut stream = Stream::default();
+        stream.add(0, true, &[]);
+        let frame = kafka_frame(&vec![7; 200_000]);
+        // every byte after the first arrives before it, in a segment of its own
+        for (i, b) in frame.iter().enumerate().skip(1) {
+            stream.add(1 + i as u32, false, &[*b]);
+        }
+        stream.add(1, false, &frame[..1]);
+        assert!(stream.ahead.is_empty());
+        assert_eq!(stream.next_frame().unwrap().len(), 200_000);
+    }
+
+    #[test]
+    fn test_untrusted_lengths() {
+        // a caplen of 4 GiB isn't allocated before the packet turns out to be missing
+        let mut input = pcap(&[]);
+        input.extend_from_slice(&[0; 8]);
+        input.extend_from_slice(&u32::MAX.to_le_bytes());
+        input.extend_from_slice(&u32::MAX.to_le_bytes());
+        let mut parser = Parser::new(input.as_slice(), &[9092]).unwrap();
+        assert_eq!(parser.next().unwrap().unwrap_err().kind(), "Eof");
+
+        let mut wire = Wire::new(&[1, 2], true);
+        wire.take(1).unwrap();
+        assert_eq!(wire.take(usize::MAX).unwrap_err().kind(), "Eof");
+    }
+}
@@ -0,0 +1,98 @@
+use crate::parse::error::*;
+use crate::parse::kafka;
+use crate::parse::kafka::KafkaOptions;
+use crate::parse::msg::Msg;
+use crate::parse::proto2json::Proto2Json;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use tokio::sync::mpsc;
+use tracing::info;
+
+pub type Decoded = (Msg, Result<Vec<String>>);
+
+/// Turns a message into output lines, such as `Proto2Json::proto2json`
+pub type Decode = Arc<dyn Fn(&mut Proto2Json, &Msg) -> Result<Vec<String>> + Send + Sync>;
+
+/// Decoded messages of a kafka stream, in offset order within each partition
+pub struct Pipeline {
+    // dropping the runtime stops the consumer
+    runtime: tokio::runtime::Runtime,
+    consumer: Option<tokio::task::JoinHandle<Result<()>>>,
+    workers: Vec<JoinHandle<()>>,
+    rx: mpsc::Receiver<Decoded>,
+}
+
+impl Iterator for Pipeline {
+    /// the outer error is a consumer failure, after which the pipeline ends
+    type Item = Result<Decoded>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.rx.blocking_recv() {
+            Some(decoded) => Some(Ok(decoded)),
+            None => {
+                self.workers.drain(..).for_each(|w| w.join().unwrap());
+                let consumer = self.consumer.take()?;
+                match self.runtime.block_on(consumer) {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => Some(Err(e)),
+                    Err(e) => panic!("kafka consumer task failed: {:?}", e),
+                }
+            }
+        }
+    }
+}
+
+/// Consumes `user_topics` on a tokio runtime and decodes messages with `decode` on `workers`
+/// threads.
+///
+/// Each partition is always decoded by the same worker, which keeps per partition ordering.
+/// Every queue holds at most `queue_size` messages, so a slow consumer of the output slows down
+/// decoding and consumption rather than buffering the topic in memory.
+pub fn decode_kafka(
+    p: &Proto2Json,
+    decode: Decode,
+    servers_csv: &str,
+    user_topics: &[&str],
+    options: &KafkaOptions,
+    workers: usize,
+    queue_size: usize,
+) -> Result<Pipeline> {
+    let workers = workers.max(1);
+    let watermarks = kafka::fetch_watermarks(servers_csv, user_topics, options)?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let (out_tx, out_rx) = mpsc::channel(queue_size);
+    let mut senders = vec![];
+    let mut handles = vec![];
+    for i in 0..workers {
+        let (tx, mut rx) = mpsc::channel::<Msg>(queue_size);
+        senders.push(tx);
+        let out_tx = out_tx.clone();
+        let mut p = p.clone();
+        let decode = decode.clone();
+        let handle = std::thread::Builder::new()
+            .name(format!("decode-{}", i))
+            .spawn(move || {
+                while let Some(msg) = rx.blocking_recv() {
+                    let out = decode(&mut p, &msg);
+                    if out_tx.blocking_send((msg, out)).is_err() {
+                        break;
+                    }
+                }
+                info!(worker = i, "decoder done");
+            })?;
+        handles.push(handle);
+    }
+    let consumer = runtime.spawn(kafka::stream_kafka(
+        servers_csv.to_string(),
+        watermarks,
+        senders,
+        options.clone(),
+    ));
+    Ok(Pipeline {
+        runtime,
+        consumer: Some(consumer),
+        workers: handles,
+        rx: out_rx,
+    })
+}
@@ -1,45 +1,169 @@
-use crate::parse::confluent::{get_schemas_fs, get_schemas_http, parse_confluent, Schema};
+use crate::parse::confluent::{parse_confluent, peek_schema_id, Schema};
+use crate::parse::envelope::Envelope;
 use crate::parse::error::*;
+use crate::parse::metrics::Metrics;
 use crate::parse::msg::Msg;
-use crate::parse::protobuf::{to_json_strings, ProtobufFileDescriptors};
-use tracing::{debug, span, warn, Level};
+use crate::parse::protobuf::{to_json_strings, to_json_values, ProtobufFileDescriptors};
+use crate::parse::redact::Redaction;
+use crate::parse::source::SchemaSource;
+use protobuf::reflect::FileDescriptor;
+use protobuf::MessageDyn;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{debug, span, Level};
 
+/// JSON values with their message types, see `json_values`
+pub type JsonValues = Vec<(Option<String>, serde_json::Value)>;
+
+pub enum Decoded {
+    Json(serde_json::Value),
+    Protobuf {
+        schema_id: i32,
+        messages: Vec<Box<dyn MessageDyn>>,
+        /// from 0 to 1, when the message type was guessed for lack of message indexes
+        confidence: Option<f64>,
+    },
+}
+
+impl Decoded {
+    fn schema_id(&self) -> Option<i32> {
+        match self {
+            Decoded::Protobuf { schema_id, .. } => Some(*schema_id),
+            Decoded::Json(_) => None,
+        }
+    }
+    fn confidence(&self) -> Option<f64> {
+        match self {
+            Decoded::Protobuf { confidence, .. } => *confidence,
+            Decoded::Json(_) => None,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Proto2Json {
-    schemas_proto_path: Option<String>,
-    schemas: Vec<Schema>,
-    includes: Vec<String>,
     pfd: ProtobufFileDescriptors,
+    redaction: Redaction,
 }
 
 impl Proto2Json {
-    pub fn load(
+    /// Schemas of `source` are written to `schemas_proto_path`, the current directory if unset,
+    /// to be compiled. Imports are looked up in `includes`, `schemas_proto_path` and the current
+    /// directory.
+    pub fn new(
+        source: Arc<dyn SchemaSource>,
         schemas_proto_path: Option<String>,
-        includes: Option<Vec<String>>,
-        schemas_url: Option<String>,
-        schemas_path: Option<String>,
-    ) -> Result<Proto2Json> {
-        let schemas: Vec<Schema> = match schemas_url {
-            Some(url) => match get_schemas_http(url) {
-                Ok(schemas) => schemas,
-                Err(_) => {
-                    warn!("could not get schemas via HTTP, continuing without");
-                    vec![]
-                }
-            },
-            None => vec![],
-        };
-        let mut includes = includes.unwrap_or_default();
+        mut includes: Vec<String>,
+    ) -> Proto2Json {
+        // schemas are compiled from files written there, which have to be in an include path
+        includes.extend(schemas_proto_path.clone());
         includes.push(".".to_string());
-
-        let pfd = ProtobufFileDescriptors::default();
-        Ok(Proto2Json {
-            schemas_proto_path,
-            schemas,
-            includes,
-            pfd,
-        })
+        let proto_path = PathBuf::from(schemas_proto_path.unwrap_or_else(|| ".".to_string()));
+        Proto2Json {
+            pfd: ProtobufFileDescriptors::new(source, proto_path, includes),
+            redaction: Redaction::default(),
+        }
+    }
+    /// Applied to every decoded value before it is printed
+    pub fn set_redaction(&mut self, redaction: Redaction) {
+        self.redaction = redaction;
+    }
+    /// Shared by clones made afterwards
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.pfd.set_metrics(metrics);
+    }
+    pub fn metrics(&self) -> Option<&Arc<Metrics>> {
+        self.pfd.metrics()
+    }
+    /// None if the schema is unknown or its source failed
+    pub fn schema(&self, schema_id: i32) -> Option<Schema> {
+        match self.pfd.source().by_id(schema_id) {
+            Ok(schema) => schema,
+            Err(e) => {
+                debug!(schema_id = schema_id, "schema lookup failed: {:?}", e);
+                None
+            }
+        }
     }
     pub fn proto2json(&mut self, msg: &Msg) -> Result<Vec<String>> {
+        match self.decode(msg)? {
+            Decoded::Json(mut json) => {
+                self.redaction.redact_json(&mut json);
+                Ok(vec![json.to_string()])
+            }
+            Decoded::Protobuf { messages, .. } => to_json_strings(messages, &self.redaction),
+        }
+    }
+    /// One envelope per decoded message, serialized
+    pub fn proto2envelopes(&mut self, msg: &Msg) -> Result<Vec<String>> {
+        self.envelopes(msg)?
+            .iter()
+            .map(|e| Ok(serde_json::to_string(e)?))
+            .collect()
+    }
+    /// Decoded messages with their kafka coordinates and the schema they were decoded with
+    pub fn envelopes(&mut self, msg: &Msg) -> Result<Vec<Envelope>> {
+        let decoded_key = match msg.key.as_bytes() {
+            Some(key) if peek_schema_id(key).is_some() => {
+                match self
+                    .decode_value(key)
+                    .and_then(|d| json_values(d, &self.redaction))
+                {
+                    Ok(mut values) if !values.is_empty() => Some(values.remove(0).1),
+                    Ok(_) => None,
+                    Err(e) => {
+                        debug!("key not decoded: {:?}", e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let decoded = self.decode(msg)?;
+        let schema_id = decoded.schema_id();
+        let confidence = decoded.confidence();
+        let (subject, version) = schema_id
+            .and_then(|id| self.schema(id))
+            .map(|s| (Some(s.subject.clone()), Some(s.version)))
+            .unwrap_or((None, None));
+        Ok(json_values(decoded, &self.redaction)?
+            .into_iter()
+            .map(|(message_type, value)| Envelope {
+                topic: msg.topic.clone(),
+                partition: msg.partition,
+                offset: msg.offset,
+                ts: msg.ts,
+                key: msg.key.clone(),
+                decoded_key: decoded_key.clone(),
+                headers: msg.headers.clone(),
+                schema_id,
+                subject: subject.clone(),
+                version,
+                message_type,
+                confidence,
+                value,
+            })
+            .collect())
+    }
+    pub fn encode(
+        &mut self,
+        schema_id: i32,
+        message_type: Option<&str>,
+        value: &serde_json::Value,
+    ) -> Result<Vec<u8>> {
+        self.pfd.encode(
+            schema_id,
+            message_type,
+            &value.to_string(),
+        )
+    }
+    /// Compiled schema, cached
+    pub fn descriptor(&mut self, schema_id: i32) -> Result<FileDescriptor> {
+        self.pfd.file_descriptor(
+            schema_id,
+        )
+    }
+    pub fn decode(&mut self, msg: &Msg) -> Result<Decoded> {
         let key = format!("{:?}", msg.key);
         let sp = span!(
             Level::INFO,
@@ -58,24 +182,61 @@ impl Proto2Json {
             len = msg.msg.len(),
             "message"
         );
+        self.decode_value(&msg.msg)
+    }
+    /// Decoded `value` as JSON values with their message types, the id of the schema it was
+    /// decoded with and the confidence in the message type if it was guessed
+    pub fn decode_json(&mut self, value: &[u8]) -> Result<(Option<i32>, Option<f64>, JsonValues)> {
+        let decoded = self.decode_value(value)?;
+        let schema_id = decoded.schema_id();
+        let confidence = decoded.confidence();
+        Ok((
+            schema_id,
+            confidence,
+            json_values(decoded, &self.redaction)?,
+        ))
+    }
+    fn decode_value(&mut self, value: &[u8]) -> Result<Decoded> {
         // Try parsing as JSON first
-        let json: serde_json::Result<serde_json::Value> = serde_json::from_slice(&msg.msg);
+        let json: serde_json::Result<serde_json::Value> = serde_json::from_slice(value);
         if let Ok(json) = json {
-            return Ok(vec![json.to_string()]);
+            return Ok(Decoded::Json(json));
         }
-        let msg = parse_confluent(&msg.msg)?;
+        let msg = parse_confluent(value)?;
         debug!(
             schema_id = msg.schema_id,
             len = msg.value.len(),
             "confluent"
         );
-        let msg = self.pfd.parse(
-            &self.schemas_proto_path.as_deref().unwrap_or("."),
-            &self.includes,
-            &self.schemas,
+        let (messages, confidence) = self.pfd.parse(
             msg.schema_id,
+            &msg.message_indexes,
             msg.value,
         )?;
-        to_json_strings(msg)
+        Ok(Decoded::Protobuf {
+            schema_id: msg.schema_id,
+            messages,
+            confidence,
+        })
+    }
+}
+
+/// Decoded messages as JSON values, with their fully qualified type when decoded with protobuf
+fn json_values(decoded: Decoded, redaction: &Redaction) -> Result<JsonValues> {
+    match decoded {
+        Decoded::Json(mut json) => {
+            redaction.redact_json(&mut json);
+            Ok(vec![(None, json)])
+        }
+        Decoded::Protobuf { messages, .. } => {
+            let message_types = messages
+                .iter()
+                .map(|m| Some(m.descriptor_dyn().full_name().to_string()))
+                .collect::<Vec<_>>();
+            Ok(message_types
+                .into_iter()
+                .zip(to_json_values(messages, redaction)?)
+                .collect())
+        }
     }
 }
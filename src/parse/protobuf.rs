@@ -1,53 +1,215 @@
 use crate::parse::confluent::*;
 use crate::parse::error::*;
+use crate::parse::metrics::Metrics;
+use crate::parse::redact::Redaction;
+use crate::parse::source::{resolve_references, SchemaSource};
 use itertools::Itertools;
-use protobuf::reflect::FileDescriptor;
+use protobuf::reflect::{FileDescriptor, MessageDescriptor, ReflectFieldRef, ReflectValueRef};
 use protobuf::MessageDyn;
 use protobuf_json_mapping::PrintOptions;
 use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tracing::{debug, info, warn};
 
 #[derive(Debug)]
 pub enum ProtobufError {
     SchemaNotFound(i32),
     CouldNotFindFileDescriptorForSchema(i32),
+    MessageTypeNotFound(i32, String),
+    /// subject and version of a schema reference the source doesn't know
+    ReferenceNotFound(String, usize),
+    /// file imported by a descriptor set but missing from it
+    MissingImport(String),
+    /// schema text that doesn't compile, with the compiler's message
+    InvalidSchema(i32, String),
+    /// message types of a schema that a record without message indexes fits equally well
+    AmbiguousMessageType(i32, Vec<String>),
+    /// schema that a record without message indexes fails to parse as with any message type
+    NoMatchingMessageType(i32),
 }
 
-#[derive(Default)]
+/// `data` parsed as `md`, and how well it fits. Required fields left unset are scored rather
+/// than failing the parse.
+fn fit_as(md: &MessageDescriptor, data: &[u8]) -> Option<(Fit, Box<dyn MessageDyn>)> {
+    let mut parsed = md.new_instance();
+    match parsed.merge_from_bytes_dyn(data) {
+        Ok(()) => {
+            let fit = Fit::of(&*parsed);
+            debug!(
+                known = fit.known,
+                problems = fit.problems,
+                "ok {}",
+                md.name()
+            );
+            Some((fit, parsed))
+        }
+        Err(e) => {
+            debug!(e = e.to_string().as_str(), "fail {}", md.name());
+            None
+        }
+    }
+}
+
+/// Messages parsed from a payload, and the confidence in their type when it was guessed
+pub type Parsed = (Vec<Box<dyn MessageDyn>>, Option<f64>);
+
+/// How well a payload fits a message type it was parsed as, counted in the message and its
+/// submessages
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Fit {
+    /// values of fields the type knows
+    known: usize,
+    /// unknown fields, enum values the enum doesn't have and unset proto2 required fields
+    problems: usize,
+}
+
+impl Fit {
+    /// Strings that aren't UTF-8 fail parsing already, they don't get this far
+    fn of(m: &dyn MessageDyn) -> Fit {
+        let mut fit = Fit::default();
+        fit.add_message(m);
+        fit
+    }
+
+    fn add_message(&mut self, m: &dyn MessageDyn) {
+        self.problems += m.unknown_fields_dyn().iter().count();
+        for field in m.descriptor_dyn().fields() {
+            if field.is_required() && !field.has_field(m) {
+                self.problems += 1;
+            }
+            match field.get_reflect(m) {
+                ReflectFieldRef::Optional(value) => {
+                    if let Some(value) = value.value() {
+                        self.add_value(value);
+                    }
+                }
+                ReflectFieldRef::Repeated(values) => {
+                    for value in values {
+                        self.add_value(value);
+                    }
+                }
+                ReflectFieldRef::Map(map) => {
+                    for (_, value) in &map {
+                        self.add_value(value);
+                    }
+                }
+            }
+        }
+    }
+
+    fn add_value(&mut self, value: ReflectValueRef) {
+        match value {
+            ReflectValueRef::Enum(ed, n) if ed.value_by_number(n).is_none() => self.problems += 1,
+            ReflectValueRef::Message(m) => {
+                self.known += 1;
+                self.add_message(&*m);
+            }
+            _ => self.known += 1,
+        }
+    }
+
+    /// Fewest problems first, then most known values
+    fn rank(&self) -> (usize, std::cmp::Reverse<usize>) {
+        (self.problems, std::cmp::Reverse(self.known))
+    }
+
+    /// Share of the values that fit, from 0 to 1
+    fn confidence(&self) -> f64 {
+        self.known as f64 / (self.known + self.problems).max(1) as f64
+    }
+}
+
+/// Cache of compiled schemas, shared between clones
+#[derive(Clone)]
 pub struct ProtobufFileDescriptors {
-    map: BTreeMap<i32, FileDescriptor>,
+    map: Arc<Mutex<BTreeMap<i32, FileDescriptor>>>,
+    /// held while compiling so that clones don't write the same temp file concurrently
+    compiling: Arc<Mutex<()>>,
+    source: Arc<dyn SchemaSource>,
+    proto_path: PathBuf,
+    includes: Vec<String>,
+    metrics: Option<Arc<Metrics>>,
 }
-fn same_path(a: &str, b: &str) -> bool {
-    let a = a.strip_prefix("./").unwrap_or(a);
-    let b = b.strip_prefix("./").unwrap_or(b);
-    a == b
+
+/// `name` of a compiled file is relative to the include path it was found in
+fn same_path(name: &str, path: &str) -> bool {
+    std::path::Path::new(path).ends_with(name)
 }
 
 impl ProtobufFileDescriptors {
-    fn get_file_descriptor(
-        schemas_proto_path: &str,
-        includes: &[String],
-        schemas: &[Schema],
-        schema_id: i32,
-    ) -> Result<FileDescriptor> {
-        let schema = schemas
-            .iter()
-            .find(|s| s.id == schema_id)
-            .ok_or_else(|| ProtobufError::SchemaNotFound(schema_id))?;
-        let dir = PathBuf::from(schemas_proto_path);
+    /// Schemas of `source` are written to `proto_path` to be compiled, imports that aren't
+    /// schema references are looked up in `includes`
+    pub fn new(
+        source: Arc<dyn SchemaSource>,
+        proto_path: PathBuf,
+        includes: Vec<String>,
+    ) -> ProtobufFileDescriptors {
+        ProtobufFileDescriptors {
+            map: Arc::default(),
+            compiling: Arc::default(),
+            source,
+            proto_path,
+            includes,
+            metrics: None,
+        }
+    }
+
+    pub fn source(&self) -> &dyn SchemaSource {
+        self.source.as_ref()
+    }
+
+    /// Records schema fetch latency and cache hits in `metrics`
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    pub fn metrics(&self) -> Option<&Arc<Metrics>> {
+        self.metrics.as_ref()
+    }
+
+    fn get_file_descriptor(&self, schema_id: i32) -> Result<FileDescriptor> {
+        let started = Instant::now();
+        let schema = self
+            .source
+            .by_id(schema_id)?
+            .ok_or(ProtobufError::SchemaNotFound(schema_id))?;
+        let references = resolve_references(self.source.as_ref(), &schema)?;
+        if let Some(metrics) = &self.metrics {
+            metrics.schema_fetch(started.elapsed());
+        }
+        // references are imported by their name, relative to the proto path
+        for (name, referenced) in references {
+            if !Path::new(&name)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+            {
+                let message = format!("reference {} is outside the proto path", name);
+                return Err(ProtobufError::InvalidSchema(schema_id, message).into());
+            }
+            let path = self.proto_path.join(name);
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("creating {}", dir.display()))?;
+            }
+            debug!("writing {}", path.display().to_string());
+            std::fs::write(&path, &referenced.schema)
+                .with_context(|| format!("writing {}", path.display()))?;
+        }
         //info!("schema={:?}", schema);
-        let path = dir.join(format!("temp-schema-{}.proto", schema.id));
+        let path = self.proto_path.join(format!("temp-schema-{}.proto", schema.id));
         debug!("writing {}", path.display().to_string());
-        std::fs::write(&path, &schema.schema)?;
+        std::fs::write(&path, &schema.schema)
+            .with_context(|| format!("writing {}", path.display()))?;
 
         //info!("include {:?}", includes);
         let file_descriptor_protos = protobuf_parse::Parser::new()
             .pure()
-            .includes(includes)
+            .includes(&self.includes)
             .input(&path)
             .parse_and_typecheck()
-            .unwrap()
+            .map_err(|e| ProtobufError::InvalidSchema(schema_id, format!("{:#}", e)))?
             .file_descriptors;
 
         let temp_path = path.to_str().map(|s| s.to_string()).unwrap();
@@ -71,65 +233,184 @@ impl ProtobufFileDescriptors {
         ))
     }
 
+    /// The message type is the one of the message indexes, empty ones meaning the first type.
+    /// Legacy producers left them empty whatever the type, so when `data` doesn't fit the
+    /// first type every message type of the schema is tried, and the one it fits best is taken
+    /// together with the confidence of that guess. Ties go to the first type.
     #[tracing::instrument(skip(fd, data))]
-    fn parse_message(fd: &FileDescriptor, data: Vec<u8>) -> Result<Vec<Box<dyn MessageDyn>>> {
-        let mut out = vec![];
-        debug!(
-            "names: {:?}",
-            fd.messages().map(|x| x.name().to_string()).collect_vec()
-        );
-        for msg in fd.messages() {
-            let rr: protobuf::Result<Box<dyn MessageDyn>> = msg.parse_from_bytes(data.as_slice());
-            match rr {
-                Ok(parsed) => {
-                    //info!(message_name = msg.name());
-                    debug!("ok {}", msg.name());
-                    out.push(parsed);
-                }
-                Err(e) => {
-                    debug!("fail {}", msg.name());
-                    warn!(
-                        message_name = msg.name(),
-                        e = e.to_string().as_str(),
-                        "failed"
-                    )
-                }
+    fn parse_message(
+        fd: &FileDescriptor,
+        schema_id: i32,
+        indexes: &[i32],
+        data: Vec<u8>,
+    ) -> Result<Parsed> {
+        let not_found = || ProtobufError::MessageTypeNotFound(schema_id, format!("{:?}", indexes));
+        let md = match indexes {
+            [] => fd.messages().next().ok_or_else(not_found)?,
+            indexes => message_by_indexes(fd, indexes).ok_or_else(not_found)?,
+        };
+        let candidates = fd.messages().collect_vec();
+        if !indexes.is_empty() || candidates.len() == 1 {
+            let parsed = md
+                .parse_from_bytes(data.as_slice())
+                .with_context(|| format!("parsing {}", md.full_name()))?;
+            return Ok((vec![parsed], None));
+        }
+        let first = fit_as(&md, &data);
+        if let Some((fit, _)) = &first {
+            if fit.problems == 0 {
+                return Ok((first.into_iter().map(|(_, m)| m).collect(), None));
+            }
+        }
+        // the first type stays ahead of the ones that fit as well, sorting is stable
+        let mut fits = first
+            .map(|(fit, m)| (fit, 0, m))
+            .into_iter()
+            .chain(
+                candidates
+                    .iter()
+                    .enumerate()
+                    .skip(1)
+                    .filter_map(|(i, md)| fit_as(md, &data).map(|(fit, m)| (fit, i, m))),
+            )
+            .collect_vec();
+        fits.sort_by_key(|(fit, _, _)| fit.rank());
+        match fits.as_slice() {
+            [] => Err(ProtobufError::NoMatchingMessageType(schema_id).into()),
+            [(best, i, _), (next, _, _), ..] if *i != 0 && best.rank() == next.rank() => {
+                let names = fits
+                    .iter()
+                    .filter(|(fit, _, _)| fit.rank() == best.rank())
+                    .map(|(_, _, m)| m.descriptor_dyn().full_name().to_string())
+                    .collect();
+                Err(ProtobufError::AmbiguousMessageType(schema_id, names).into())
+            }
+            _ => {
+                let (fit, _, parsed) = fits.remove(0);
+                Ok((vec![parsed], Some(fit.confidence())))
             }
         }
-        Ok(out)
     }
+    /// Compiled schema, compiled once and shared by all clones
+    pub fn file_descriptor(&mut self, schema_id: i32) -> Result<FileDescriptor> {
+        let cached = self.map.lock().unwrap().get(&schema_id).cloned();
+        if let Some(metrics) = &self.metrics {
+            metrics.descriptor_cache(cached.is_some());
+        }
+        if let Some(fd) = cached {
+            return Ok(fd);
+        }
+        // cached schemas are still looked up by other clones meanwhile
+        let _compiling = self.compiling.lock().unwrap();
+        if let Some(fd) = self.map.lock().unwrap().get(&schema_id) {
+            // compiled by another clone while waiting
+            return Ok(fd.clone());
+        }
+        let fd = match self.source.descriptor(schema_id)? {
+            Some(fd) => fd,
+            None => self.get_file_descriptor(schema_id)?,
+        };
+        self.map.lock().unwrap().insert(schema_id, fd.clone());
+        Ok(fd)
+    }
+
+    /// Parsed messages, and the confidence in their type when it had to be guessed
     pub fn parse(
         &mut self,
-        schemas_proto_path: &str,
-        include: &[String], // yuck
-        schemas: &[Schema],
         schema_id: i32,
+        message_indexes: &[i32],
         data: Vec<u8>,
-    ) -> Result<Vec<Box<dyn MessageDyn>>> {
-        match self.map.get(&schema_id) {
-            Some(fd) => Self::parse_message(fd, data),
-            None => {
-                let fd =
-                    Self::get_file_descriptor(schemas_proto_path, include, schemas, schema_id)?;
-                self.map.insert(schema_id, fd);
-                Self::parse_message(self.map.get(&schema_id).unwrap(), data)
-            }
+    ) -> Result<Parsed> {
+        let fd = self.file_descriptor(schema_id)?;
+        Self::parse_message(&fd, schema_id, message_indexes, data)
+    }
+
+    /// Encodes JSON `value` as `message_type` (first message of the schema if unset)
+    /// and frames it as confluent protobuf
+    pub fn encode(
+        &mut self,
+        schema_id: i32,
+        message_type: Option<&str>,
+        value: &str,
+    ) -> Result<Vec<u8>> {
+        let fd = self.file_descriptor(schema_id)?;
+        let not_found = || {
+            ProtobufError::MessageTypeNotFound(
+                schema_id,
+                message_type.unwrap_or_default().to_string(),
+            )
+        };
+        let indexes = match message_type {
+            Some(name) => find_message_indexes(fd.messages(), name).ok_or_else(not_found)?,
+            None => vec![0],
+        };
+        let md = message_by_indexes(&fd, &indexes).ok_or_else(not_found)?;
+        let msg = protobuf_json_mapping::parse_dyn_from_str(&md, value)?;
+        write_confluent(schema_id, &indexes, &msg.write_to_bytes_dyn()?)
+    }
+}
+
+/// Position of a message type in the schema, nested types included, as confluent's message indexes
+fn find_message_indexes(
+    messages: impl Iterator<Item = MessageDescriptor>,
+    name: &str,
+) -> Option<Vec<i32>> {
+    for (i, md) in messages.enumerate() {
+        if md.full_name() == name || md.name() == name {
+            return Some(vec![i as i32]);
+        }
+        if let Some(mut nested) = find_message_indexes(md.nested_messages(), name) {
+            nested.insert(0, i as i32);
+            return Some(nested);
         }
     }
+    None
+}
+
+fn message_by_indexes(fd: &FileDescriptor, indexes: &[i32]) -> Option<MessageDescriptor> {
+    let (first, rest) = indexes.split_first()?;
+    let mut md = fd.messages().nth(*first as usize)?;
+    for idx in rest {
+        let nested = md.nested_messages().nth(*idx as usize)?;
+        md = nested;
+    }
+    Some(md)
+}
+
+pub fn to_json_strings(
+    msg: Vec<Box<dyn MessageDyn>>,
+    redaction: &Redaction,
+) -> Result<Vec<String>> {
+    if !redaction.is_empty() {
+        return Ok(to_json_values(msg, redaction)?
+            .iter()
+            .map(|json| json.to_string())
+            .collect());
+    }
+    msg.iter().map(|msg| print_json(&**msg)).collect()
 }
 
-pub fn to_json_strings(msg: Vec<Box<dyn MessageDyn>>) -> Result<Vec<String>> {
+/// The messages as JSON values, redacted, for callers that go on working with them
+pub fn to_json_values(
+    msg: Vec<Box<dyn MessageDyn>>,
+    redaction: &Redaction,
+) -> Result<Vec<serde_json::Value>> {
+    let mut out = vec![];
+    for msg in msg {
+        // the printer only writes text, which is parsed once
+        let mut json: serde_json::Value = serde_json::from_str(&print_json(&*msg)?)?;
+        redaction.redact_message(&msg.descriptor_dyn(), &mut json);
+        out.push(json);
+    }
+    Ok(out)
+}
+
+fn print_json(msg: &dyn MessageDyn) -> Result<String> {
     let options = PrintOptions {
         enum_values_int: false,
         proto_field_name: false,
         always_output_default_values: true,
         _future_options: (),
     };
-    let mut out = vec![];
-    for msg in msg {
-        let json_str = protobuf_json_mapping::print_to_string_with_options(&*msg, &options)
-            .map_err(Error::JsonPrint)?;
-        out.push(json_str);
-    }
-    Ok(out)
+    protobuf_json_mapping::print_to_string_with_options(msg, &options).map_err(Error::JsonPrint)
 }
@@ -0,0 +1,325 @@
+use crate::parse::error::*;
+use fnv::FnvHashMap;
+use protobuf::reflect::{
+    FieldDescriptor, FileDescriptor, MessageDescriptor, RuntimeFieldType, RuntimeType,
+};
+use protobuf::UnknownValueRef;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+pub enum RedactError {
+    UnknownAction(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// replaced with `***`
+    Mask,
+    /// replaced with the hex encoded sha256 of the value, which keeps values joinable
+    Hash,
+    /// removed from the output
+    Drop,
+}
+
+impl FromStr for Action {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Action> {
+        match s {
+            "mask" => Ok(Action::Mask),
+            "hash" => Ok(Action::Hash),
+            "drop" => Ok(Action::Drop),
+            other => Err(RedactError::UnknownAction(other.to_string()).into()),
+        }
+    }
+}
+
+/// `name` or `name=action`, mask by default
+fn rule(s: &str) -> Result<(String, Action)> {
+    match s.rsplit_once('=') {
+        Some((name, action)) => Ok((name.trim().to_string(), action.trim().parse()?)),
+        None => Ok((s.trim().to_string(), Action::Mask)),
+    }
+}
+
+/// Field numbers of the redacting options declared for a file, with their actions
+type OptionNumbers = Arc<Vec<(u32, Action)>>;
+
+/// Fields removed or obfuscated before messages are printed.
+///
+/// Paths are dotted JSON field names relative to the message, `*` matching any field, and
+/// apply to every element of the arrays they go through. Options name a boolean custom field
+/// option such as `(pii) = true`, by its name or fully qualified name.
+#[derive(Debug, Clone, Default)]
+pub struct Redaction {
+    paths: Vec<(Vec<String>, Action)>,
+    options: Vec<(String, Action)>,
+    /// `option_numbers` by file, shared between clones
+    numbers: Arc<Mutex<FnvHashMap<FileDescriptor, OptionNumbers>>>,
+}
+
+impl Redaction {
+    pub fn new(paths: &[String], options: &[String]) -> Result<Redaction> {
+        Ok(Redaction {
+            paths: paths
+                .iter()
+                .map(|p| {
+                    let (path, action) = rule(p)?;
+                    Ok((path.split('.').map(str::to_string).collect(), action))
+                })
+                .collect::<Result<_>>()?,
+            options: options.iter().map(|o| rule(o)).collect::<Result<_>>()?,
+            numbers: Default::default(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty() && self.options.is_empty()
+    }
+
+    /// Applies field options of `md` and then paths
+    pub fn redact_message(&self, md: &MessageDescriptor, json: &mut Value) {
+        if !self.options.is_empty() {
+            let numbers = self.cached_option_numbers(md.file_descriptor());
+            if !numbers.is_empty() {
+                redact_fields(md, &numbers, json);
+            }
+        }
+        self.redact_json(json);
+    }
+
+    /// Applies paths only, for values that were not decoded with a schema
+    pub fn redact_json(&self, json: &mut Value) {
+        for (path, action) in &self.paths {
+            redact_path(json, path, *action);
+        }
+    }
+
+    /// `option_numbers` of `file`, looked up once per file
+    fn cached_option_numbers(&self, file: &FileDescriptor) -> OptionNumbers {
+        let mut numbers = self.numbers.lock().unwrap();
+        numbers
+            .entry(file.clone())
+            .or_insert_with(|| Arc::new(self.option_numbers(file)))
+            .clone()
+    }
+
+    /// Field numbers of the redacting options, which are extensions of `FieldOptions` declared
+    /// by the file or its imports
+    fn option_numbers(&self, file: &FileDescriptor) -> Vec<(u32, Action)> {
+        let mut out = vec![];
+        let mut files = vec![file.clone()];
+        let mut seen = vec![];
+        while let Some(file) = files.pop() {
+            if seen.contains(&file.name().to_string()) {
+                continue;
+            }
+            seen.push(file.name().to_string());
+            let package = file.proto().package();
+            for ext in &file.proto().extension {
+                if !ext.extendee().ends_with("google.protobuf.FieldOptions") {
+                    continue;
+                }
+                let full_name = format!("{}.{}", package, ext.name());
+                for (name, action) in &self.options {
+                    if name == ext.name() || *name == full_name {
+                        out.push((ext.number() as u32, *action));
+                    }
+                }
+            }
+            files.extend(file.deps().iter().cloned());
+        }
+        out
+    }
+}
+
+fn option_action(field: &FieldDescriptor, numbers: &[(u32, Action)]) -> Option<Action> {
+    let unknown = field
+        .proto()
+        .options
+        .get_or_default()
+        .special_fields
+        .unknown_fields();
+    numbers
+        .iter()
+        .find(|(number, _)| matches!(unknown.get(*number), Some(UnknownValueRef::Varint(v)) if v != 0))
+        .map(|(_, action)| *action)
+}
+
+fn redact_fields(md: &MessageDescriptor, numbers: &[(u32, Action)], json: &mut Value) {
+    let obj = match json {
+        Value::Object(obj) => obj,
+        _ => return,
+    };
+    for field in md.fields() {
+        let key = if obj.contains_key(field.json_name()) {
+            field.json_name()
+        } else {
+            field.name()
+        };
+        if let Some(action) = option_action(&field, numbers) {
+            apply(obj, key, action);
+            continue;
+        }
+        let value = match obj.get_mut(key) {
+            Some(value) => value,
+            None => continue,
+        };
+        match field.runtime_field_type() {
+            RuntimeFieldType::Singular(RuntimeType::Message(m)) => {
+                redact_fields(&m, numbers, value)
+            }
+            RuntimeFieldType::Repeated(RuntimeType::Message(m)) => {
+                if let Value::Array(items) = value {
+                    items.iter_mut().for_each(|i| redact_fields(&m, numbers, i));
+                }
+            }
+            RuntimeFieldType::Map(_, RuntimeType::Message(m)) => {
+                if let Value::Object(entries) = value {
+                    entries
+                        .values_mut()
+                        .for_each(|v| redact_fields(&m, numbers, v));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn redact_path(json: &mut Value, path: &[String], action: Action) {
+    match json {
+        Value::Array(items) => items.iter_mut().for_each(|i| redact_path(i, path, action)),
+        Value::Object(obj) => match path {
+            [last] if last == "*" => {
+                let keys = obj.keys().cloned().collect::<Vec<_>>();
+                keys.iter().for_each(|k| apply(obj, k, action));
+            }
+            [last] => apply(obj, last, action),
+            [first, rest @ ..] if first == "*" => {
+                obj.values_mut().for_each(|v| redact_path(v, rest, action))
+            }
+            [first, rest @ ..] => {
+                if let Some(v) = obj.get_mut(first) {
+                    redact_path(v, rest, action)
+                }
+            }
+            [] => {}
+        },
+        _ => {}
+    }
+}
+
+fn apply(obj: &mut serde_json::Map<String, Value>, key: &str, action: Action) {
+    match (action, obj.get_mut(key)) {
+        (_, None) => {}
+        (Action::Drop, Some(_)) => {
+            obj.remove(key);
+        }
+        (action, Some(value)) => obfuscate(value, action),
+    }
+}
+
+/// Repeated fields keep their length, everything else becomes a string
+fn obfuscate(value: &mut Value, action: Action) {
+    match value {
+        Value::Null => {}
+        Value::Array(items) => items.iter_mut().for_each(|i| obfuscate(i, action)),
+        Value::String(s) if action == Action::Hash => {
+            *s = hex::encode(Sha256::digest(s.as_bytes()))
+        }
+        other if action == Action::Hash => {
+            *other = Value::String(hex::encode(Sha256::digest(other.to_string().as_bytes())))
+        }
+        other => *other = Value::String("***".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::redact::Redaction;
+    use protobuf::reflect::FileDescriptor;
+    use serde_json::json;
+
+    #[test]
+    fn test_redact_paths() {
+        let r = Redaction::new(
+            &[
+                "customer.email".to_string(),
+                "lines.card=hash".to_string(),
+                "*.secret=drop".to_string(),
+            ],
+            &[],
+        )
+        .unwrap();
+        let mut json = json!({
+            "customer": {"email": "a@b.c", "name": "n", "secret": 1},
+            "lines": [{"card": "4111"}, {"card": null}],
+        });
+        r.redact_json(&mut json);
+        assert_eq!(
+            json,
+            json!({
+                "customer": {"email": "***", "name": "n"},
+                "lines": [
+                    {"card": "1f58dbec71994620de8abe61e744f76da60667b7b277ed4bead710a4b8e31ad0"},
+                    {"card": null}
+                ],
+            })
+        );
+        assert!(Redaction::new(&["a=scramble".to_string()], &[]).is_err());
+    }
+
+    #[test]
+    fn test_redact_options() {
+        let dir = std::env::temp_dir().join(format!("redact-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pii.proto");
+        std::fs::write(
+            &path,
+            r#"syntax = "proto3";
+package acme;
+import "google/protobuf/descriptor.proto";
+extend google.protobuf.FieldOptions { bool pii = 50001; }
+message Customer {
+  string email = 1 [(pii) = true];
+  string name = 2;
+}
+message Order {
+  Customer customer = 1;
+  repeated string card_numbers = 2 [(acme.pii) = true];
+}
+"#,
+        )
+        .unwrap();
+        let fdps = protobuf_parse::Parser::new()
+            .pure()
+            .include(&dir)
+            .input(&path)
+            .parse_and_typecheck()
+            .unwrap()
+            .file_descriptors;
+        let mut deps = vec![];
+        for fdp in fdps {
+            deps.push(FileDescriptor::new_dynamic(fdp, &deps).unwrap());
+        }
+        let md = deps
+            .last()
+            .unwrap()
+            .message_by_package_relative_name("Order")
+            .unwrap();
+        let r = Redaction::new(&[], &["pii=drop".to_string()]).unwrap();
+        let mut json = json!({
+            "customer": {"email": "a@b.c", "name": "n"},
+            "cardNumbers": ["4111"],
+        });
+        r.redact_message(&md, &mut json);
+        assert_eq!(json, json!({"customer": {"name": "n"}}));
+        let mut json = json!({"customer": {"email": "d@e.f"}});
+        r.clone().redact_message(&md, &mut json);
+        assert_eq!(json, json!({"customer": {}}));
+        assert_eq!(r.numbers.lock().unwrap().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
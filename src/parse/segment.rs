@@ -0,0 +1,454 @@
+use crate::parse::confluent::{read_varint, read_varlong};
+use crate::parse::error::*;
+use crate::parse::msg::{Msg, ParsedKey};
+use byteorder::{BigEndian, ReadBytesExt};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read};
+use std::path::{Path, PathBuf};
+use tracing::{debug, trace, warn};
+
+/// baseOffset and batchLength, the rest of the batch is batchLength bytes long
+const BATCH_HEADER_LEN: usize = 12;
+const ATTR_COMPRESSION: i16 = 0x07;
+const ATTR_LOG_APPEND_TIME: i16 = 0x08;
+const ATTR_CONTROL: i16 = 0x20;
+const XERIAL_SNAPPY_MAGIC: &[u8] = b"\x82SNAPPY\x00";
+/// Longest records of a batch once decompressed, a corrupt batch can claim anything
+const MAX_RECORDS_LEN: usize = 256 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum SegmentError {
+    /// message format v0 and v1, written by brokers older than 0.11
+    UnsupportedMagic(i8),
+    UnsupportedCompression(i16),
+    /// batch decompressing to more than the given number of bytes
+    RecordsTooLong(usize),
+}
+
+/// Parses a RecordBatch (magic v2) starting at its baseOffset field.
+/// Control batches (transaction markers) yield no messages.
+pub fn parse_record_batch(topic: &str, partition: i64, batch: &[u8]) -> Result<Vec<Msg>> {
+    let mut rdr = Cursor::new(batch);
+    let base_offset = rdr.read_i64::<BigEndian>()?;
+    let _batch_length = rdr.read_i32::<BigEndian>()?;
+    let _partition_leader_epoch = rdr.read_i32::<BigEndian>()?;
+    let magic = rdr.read_i8()?;
+    if magic != 2 {
+        return Err(SegmentError::UnsupportedMagic(magic).into());
+    }
+    let _crc = rdr.read_u32::<BigEndian>()?;
+    let attributes = rdr.read_i16::<BigEndian>()?;
+    let _last_offset_delta = rdr.read_i32::<BigEndian>()?;
+    let base_timestamp = rdr.read_i64::<BigEndian>()?;
+    let max_timestamp = rdr.read_i64::<BigEndian>()?;
+    let _producer_id = rdr.read_i64::<BigEndian>()?;
+    let _producer_epoch = rdr.read_i16::<BigEndian>()?;
+    let _base_sequence = rdr.read_i32::<BigEndian>()?;
+    let count = rdr.read_i32::<BigEndian>()?;
+    trace!(
+        base_offset = base_offset,
+        attributes = attributes,
+        count = count,
+        "batch"
+    );
+    if attributes & ATTR_CONTROL != 0 {
+        debug!(base_offset = base_offset, "skipping control batch");
+        return Ok(vec![]);
+    }
+    let compressed = &batch[rdr.position() as usize..];
+    let records = decompress(attributes & ATTR_COMPRESSION, compressed)?;
+    let mut rdr = Cursor::new(records.as_slice());
+    // every record takes a few bytes, whatever the count says
+    let mut out = Vec::with_capacity((count.max(0) as usize).min(records.len()));
+    for _ in 0..count {
+        let _length = read_varint(&mut rdr)?;
+        let _attributes = rdr.read_i8()?;
+        let timestamp_delta = read_varlong(&mut rdr)?;
+        let offset_delta = read_varint(&mut rdr)?;
+        let key = read_bytes(&mut rdr)?;
+        let value = read_bytes(&mut rdr)?.unwrap_or_default();
+        let headers_count = read_varint(&mut rdr)?;
+        let mut headers = vec![];
+        for _ in 0..headers_count {
+            let name = read_bytes(&mut rdr)?.unwrap_or_default();
+            let value = read_bytes(&mut rdr)?;
+            headers.push((
+                String::from_utf8(name)?,
+                ParsedKey::from_option(value.as_deref()),
+            ));
+        }
+        let key = ParsedKey::from_option(key.as_deref());
+        out.push(Msg {
+            topic: topic.to_string(),
+            partition,
+            offset: base_offset.wrapping_add(offset_delta as i64),
+            ts: if attributes & ATTR_LOG_APPEND_TIME != 0 {
+                max_timestamp
+            } else {
+                base_timestamp.wrapping_add(timestamp_delta)
+            },
+            key_len: key.len(),
+            key,
+            msg_len: value.len(),
+            msg: value,
+            headers,
+        });
+    }
+    Ok(out)
+}
+
+/// Parses consecutive record batches, as found in produce requests and fetch responses.
+/// Brokers may cut the last batch of a fetch response short, it is ignored.
+pub fn parse_record_batches(topic: &str, partition: i64, data: &[u8]) -> Result<Vec<Msg>> {
+    let mut out = vec![];
+    let mut pos = 0;
+    while data.len() >= pos + BATCH_HEADER_LEN {
+        let len =
+            i32::from_be_bytes([data[pos + 8], data[pos + 9], data[pos + 10], data[pos + 11]]);
+        let end = pos + BATCH_HEADER_LEN + len.max(0) as usize;
+        if len <= 0 || end > data.len() {
+            break;
+        }
+        out.extend(parse_record_batch(topic, partition, &data[pos..end])?);
+        pos = end;
+    }
+    Ok(out)
+}
+
+/// varint length followed by as many bytes, -1 meaning null
+fn read_bytes(rdr: &mut Cursor<&[u8]>) -> Result<Option<Vec<u8>>> {
+    let len = read_varint(rdr)?;
+    if len < 0 {
+        return Ok(None);
+    }
+    let remaining = rdr.get_ref().len() - rdr.position() as usize;
+    if len as usize > remaining {
+        return Err(Error::Eof);
+    }
+    let mut buf = vec![0u8; len as usize];
+    rdr.read_exact(&mut buf)?;
+    Ok(Some(buf))
+}
+
+/// Decompressed records, failing rather than growing past `MAX_RECORDS_LEN`
+fn decompress(codec: i16, data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    let limit = MAX_RECORDS_LEN as u64 + 1;
+    match codec {
+        0 => out.extend_from_slice(data),
+        1 => {
+            flate2::read::GzDecoder::new(data)
+                .take(limit)
+                .read_to_end(&mut out)?;
+        }
+        2 if data.starts_with(XERIAL_SNAPPY_MAGIC) => {
+            // java clients frame snappy blocks after a 16 byte header
+            let mut rdr = Cursor::new(data.get(16..).ok_or(Error::Eof)?);
+            while (rdr.position() as usize) < rdr.get_ref().len() {
+                let len = rdr.read_u32::<BigEndian>()? as usize;
+                let start = rdr.position() as usize;
+                let block = rdr.get_ref().get(start..start + len).ok_or(Error::Eof)?;
+                out.extend(unsnappy(block)?);
+                if out.len() > MAX_RECORDS_LEN {
+                    break;
+                }
+                rdr.set_position((start + len) as u64);
+            }
+        }
+        2 => out = unsnappy(data)?,
+        3 => {
+            lz4_flex::frame::FrameDecoder::new(data)
+                .take(limit)
+                .read_to_end(&mut out)?;
+        }
+        4 => {
+            zstd::stream::read::Decoder::new(data)?
+                .take(limit)
+                .read_to_end(&mut out)?;
+        }
+        other => return Err(SegmentError::UnsupportedCompression(other).into()),
+    }
+    if out.len() > MAX_RECORDS_LEN {
+        return Err(SegmentError::RecordsTooLong(MAX_RECORDS_LEN).into());
+    }
+    Ok(out)
+}
+
+/// A raw snappy block, whose decompressed length it tells is allocated at once
+fn unsnappy(block: &[u8]) -> Result<Vec<u8>> {
+    let len = snap::raw::decompress_len(block).map_err(std::io::Error::from)?;
+    if len > MAX_RECORDS_LEN {
+        return Err(SegmentError::RecordsTooLong(MAX_RECORDS_LEN).into());
+    }
+    Ok(snap::raw::Decoder::new()
+        .decompress_vec(block)
+        .map_err(std::io::Error::from)?)
+}
+
+/// Topic and partition from a partition directory name such as `orders-3`
+pub fn topic_partition(path: &Path) -> (String, i64) {
+    let dir = path
+        .parent()
+        .and_then(|p| p.file_name())
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    match dir.rsplit_once('-').map(|(t, p)| (t, p.parse::<i64>())) {
+        Some((topic, Ok(partition))) => (topic.to_string(), partition),
+        _ => {
+            warn!(
+                path = path.display().to_string(),
+                "not in a partition directory"
+            );
+            (dir, -1)
+        }
+    }
+}
+
+/// Reads messages of a single `.log` segment
+pub struct Parser<R> {
+    r: BufReader<R>,
+    topic: String,
+    partition: i64,
+    pending: VecDeque<Msg>,
+    done: bool,
+}
+
+impl Parser<File> {
+    pub fn open(path: &Path) -> Result<Self> {
+        let (topic, partition) = topic_partition(path);
+        Ok(Parser::new(File::open(path)?, topic, partition))
+    }
+}
+
+impl<R: Read> Parser<R> {
+    pub fn new(r: R, topic: String, partition: i64) -> Self {
+        Parser {
+            r: BufReader::new(r),
+            topic,
+            partition,
+            pending: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Next batch, None at the end of the file or of its preallocated, zeroed tail
+    fn next_batch(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut header = [0u8; BATCH_HEADER_LEN];
+        match self.r.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let len = i32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+        if len <= 0 {
+            return Ok(None);
+        }
+        // the length isn't trusted with an allocation, the batch grows as it is read
+        let mut batch = header.to_vec();
+        (&mut self.r).take(len as u64).read_to_end(&mut batch)?;
+        if batch.len() < BATCH_HEADER_LEN + len as usize {
+            return Err(Error::Eof);
+        }
+        Ok(Some(batch))
+    }
+}
+
+impl<R: Read> Iterator for Parser<R> {
+    type Item = Result<Msg>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(msg) = self.pending.pop_front() {
+                return Some(Ok(msg));
+            }
+            if self.done {
+                return None;
+            }
+            let batch = match self.next_batch() {
+                Ok(Some(batch)) => batch,
+                Ok(None) => {
+                    self.done = true;
+                    return None;
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            match parse_record_batch(&self.topic, self.partition, &batch) {
+                Ok(msgs) => self.pending.extend(msgs),
+                // batches are length prefixed, so a broken one doesn't affect the next one
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// `.log` files of `paths`, directories are expanded to the segments they contain
+pub fn segment_files(paths: &[String]) -> Result<Vec<PathBuf>> {
+    let mut out = vec![];
+    for path in paths {
+        let path = PathBuf::from(path);
+        if path.is_dir() {
+            let mut files = std::fs::read_dir(&path)?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            files.retain(|f| f.extension().map(|e| e == "log").unwrap_or(false));
+            // segment names are zero padded base offsets
+            files.sort();
+            out.extend(files);
+        } else {
+            out.push(path);
+        }
+    }
+    Ok(out)
+}
+
+pub fn read_segments(paths: &[String]) -> Result<impl Iterator<Item = Result<Msg>>> {
+    let files = segment_files(paths)?;
+    Ok(files.into_iter().flat_map(|path| {
+        debug!(path = path.display().to_string(), "segment");
+        let it: Box<dyn Iterator<Item = Result<Msg>>> = match Parser::open(&path) {
+            Ok(parser) => Box::new(parser),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        };
+        it
+    }))
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::parse::confluent::write_varint;
+    use crate::parse::error::Error;
+    use crate::parse::msg::ParsedKey;
+    use crate::parse::segment::{parse_record_batch, Parser, SegmentError, XERIAL_SNAPPY_MAGIC};
+    use std::io::Write;
+
+    pub(crate) fn record(
+        offset_delta: i32,
+        key: Option<&[u8]>,
+        value: &[u8],
+        headers: &[(&str, &[u8])],
+    ) -> Vec<u8> {
+        let mut body = vec![0u8];
+        body.extend(write_varint(offset_delta * 10).unwrap());
+        body.extend(write_varint(offset_delta).unwrap());
+        match key {
+            Some(key) => {
+                body.extend(write_varint(key.len() as i32).unwrap());
+                body.extend_from_slice(key);
+            }
+            None => body.extend(write_varint(-1).unwrap()),
+        }
+        body.extend(write_varint(value.len() as i32).unwrap());
+        body.extend_from_slice(value);
+        body.extend(write_varint(headers.len() as i32).unwrap());
+        for (k, v) in headers {
+            body.extend(write_varint(k.len() as i32).unwrap());
+            body.extend_from_slice(k.as_bytes());
+            body.extend(write_varint(v.len() as i32).unwrap());
+            body.extend_from_slice(v);
+        }
+        let mut out = write_varint(body.len() as i32).unwrap();
+        out.extend(body);
+        out
+    }
+
+    pub(crate) fn batch(base_offset: i64, attributes: i16, count: i32, records: &[u8]) -> Vec<u8> {
+        let mut body = vec![];
+        body.extend_from_slice(&0i32.to_be_bytes()); // partition leader epoch
+        body.push(2); // magic
+        body.extend_from_slice(&0u32.to_be_bytes()); // crc
+        body.extend_from_slice(&attributes.to_be_bytes());
+        body.extend_from_slice(&(count - 1).to_be_bytes());
+        body.extend_from_slice(&1000i64.to_be_bytes());
+        body.extend_from_slice(&1010i64.to_be_bytes());
+        body.extend_from_slice(&(-1i64).to_be_bytes());
+        body.extend_from_slice(&(-1i16).to_be_bytes());
+        body.extend_from_slice(&(-1i32).to_be_bytes());
+        body.extend_from_slice(&count.to_be_bytes());
+        body.extend_from_slice(records);
+        let mut out = base_offset.to_be_bytes().to_vec();
+        out.extend_from_slice(&(body.len() as i32).to_be_bytes());
+        out.extend(body);
+        out
+    }
+
+    #[test]
+    fn test_segment() {
+        let mut records = record(0, Some(b"k"), b"v0", &[("h", b"x")]);
+        records.extend(record(1, None, b"v1", &[]));
+        let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gz.write_all(&records).unwrap();
+        let gzipped = gz.finish().unwrap();
+
+        let mut segment = batch(100, 0, 2, &records);
+        segment.extend(batch(102, 0x20, 1, &record(0, None, b"", &[])));
+        segment.extend(batch(103, 1, 2, &gzipped));
+        segment.extend([0u8; 32]);
+
+        let msgs = Parser::new(segment.as_slice(), "orders".to_string(), 3)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let offsets = msgs.iter().map(|m| m.offset).collect::<Vec<_>>();
+        assert_eq!(offsets, &[100, 101, 103, 104]);
+        assert_eq!(msgs[1].ts, 1010);
+        assert!(matches!(&msgs[0].key, ParsedKey::Utf8(k) if k == "k"));
+        assert!(matches!(msgs[1].key, ParsedKey::None));
+        assert_eq!(msgs[2].msg, b"v0");
+        assert_eq!(msgs[2].headers[0].0, "h");
+        assert_eq!(msgs[3].partition, 3);
+    }
+
+    #[test]
+    fn test_codecs() {
+        let mut records = record(0, None, b"v0", &[]);
+        records.extend(record(1, None, b"v1", &[]));
+        let snappy = snap::raw::Encoder::new().compress_vec(&records).unwrap();
+        let mut xerial = XERIAL_SNAPPY_MAGIC.to_vec();
+        xerial.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1]);
+        xerial.extend_from_slice(&(snappy.len() as u32).to_be_bytes());
+        xerial.extend_from_slice(&snappy);
+        let mut lz4 = lz4_flex::frame::FrameEncoder::new(vec![]);
+        lz4.write_all(&records).unwrap();
+        let lz4 = lz4.finish().unwrap();
+        let zstd = zstd::stream::encode_all(records.as_slice(), 0).unwrap();
+
+        for (codec, data) in [(2, snappy), (2, xerial), (3, lz4), (4, zstd)] {
+            let msgs = parse_record_batch("orders", 0, &batch(5, codec, 2, &data)).unwrap();
+            let values = msgs.iter().map(|m| m.msg.as_slice()).collect::<Vec<_>>();
+            assert_eq!(values, vec![b"v0".as_slice(), b"v1"], "codec {}", codec);
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        let records = record(0, None, b"v0", &[]);
+        let segment = batch(0, 0, 1, &records);
+        let mut parser = Parser::new(&segment[..segment.len() - 3], "orders".to_string(), 0);
+        assert!(matches!(parser.next(), Some(Err(Error::Eof))));
+
+        // more records than the batch holds
+        assert!(parse_record_batch("orders", 0, &batch(0, 0, 1_000_000, &records)).is_err());
+        // a key longer than the batch
+        let mut records = vec![0u8; 4];
+        records.extend(write_varint(1_000_000).unwrap());
+        records[0] = (records.len() - 1) as u8 * 2;
+        let truncated = parse_record_batch("orders", 0, &batch(0, 0, 1, &records));
+        assert!(matches!(truncated, Err(Error::Eof)));
+        // the xerial header alone
+        let truncated = parse_record_batch("orders", 0, &batch(0, 2, 1, XERIAL_SNAPPY_MAGIC));
+        assert!(matches!(truncated, Err(Error::Eof)));
+        // offsets past i64::MAX wrap rather than panic
+        let mut records = record(0, None, b"v0", &[]);
+        records.extend(record(1, None, b"v1", &[]));
+        let msgs = parse_record_batch("orders", 0, &batch(i64::MAX, 0, 2, &records)).unwrap();
+        assert_eq!(msgs[1].offset, i64::MIN);
+        // a snappy block claiming 4GiB
+        let huge = batch(0, 2, 1, &[0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert!(matches!(
+            parse_record_batch("orders", 0, &huge),
+            Err(Error::Segment(SegmentError::RecordsTooLong(_)))
+        ));
+    }
+}
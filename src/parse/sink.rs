@@ -0,0 +1,711 @@
+use crate::parse::error::*;
+use crate::parse::kafka;
+use crate::parse::kafka::GroupConsumer;
+use crate::parse::msg::Msg;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+
+/// Where decoded messages go
+pub trait Sink {
+    /// `lines` are the output of decoding `msg`
+    fn write(&mut self, msg: &Msg, lines: Vec<String>) -> Result<()>;
+    /// Makes everything written so far durable, called before checkpoints are saved
+    fn flush(&mut self) -> Result<()>;
+    /// Completes the output, nothing is written afterwards
+    fn close(&mut self) -> Result<()>;
+}
+
+/// One line per decoded message on stdout
+#[derive(Default)]
+pub struct Stdout;
+
+impl Sink for Stdout {
+    fn write(&mut self, _msg: &Msg, lines: Vec<String>) -> Result<()> {
+        let mut out = std::io::stdout().lock();
+        for line in lines {
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(std::io::stdout().flush()?)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "jsonl",
+            Compression::Gzip => "jsonl.gz",
+            Compression::Zstd => "jsonl.zst",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Rotation {
+    /// uncompressed bytes after which a file is completed
+    pub max_bytes: Option<u64>,
+    /// time after which a file is completed
+    pub max_age: Option<Duration>,
+}
+
+enum Writer {
+    Plain(BufWriter<File>),
+    Gzip(flate2::write::GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Writer {
+    fn new(file: File, compression: Compression) -> Result<Writer> {
+        let file = BufWriter::new(file);
+        Ok(match compression {
+            Compression::None => Writer::Plain(file),
+            Compression::Gzip => Writer::Gzip(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            )),
+            Compression::Zstd => Writer::Zstd(zstd::Encoder::new(file, 0)?),
+        })
+    }
+
+    fn inner(&mut self) -> &mut dyn Write {
+        match self {
+            Writer::Plain(w) => w,
+            Writer::Gzip(w) => w,
+            Writer::Zstd(w) => w,
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        let mut file = match self {
+            Writer::Plain(w) => w,
+            Writer::Gzip(w) => w.finish()?,
+            Writer::Zstd(w) => w.finish()?,
+        };
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+/// Recorded next to every completed file as `<file>.offsets.json`
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FileOffsets {
+    pub topic: String,
+    pub partition: i64,
+    pub first_offset: i64,
+    pub last_offset: i64,
+    pub records: u64,
+}
+
+struct OpenFile {
+    path: PathBuf,
+    writer: Writer,
+    opened: Instant,
+    bytes: u64,
+    offsets: FileOffsets,
+}
+
+impl OpenFile {
+    fn complete(self) -> Result<()> {
+        self.writer.finish()?;
+        let path = self.path.with_extension("");
+        std::fs::rename(&self.path, &path)?;
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(".offsets.json");
+        std::fs::write(sidecar, serde_json::to_vec(&self.offsets)?)?;
+        info!(
+            path = path.display().to_string(),
+            records = self.offsets.records,
+            last_offset = self.offsets.last_offset,
+            "completed"
+        );
+        Ok(())
+    }
+}
+
+/// Writes `<dir>/<topic>/<partition>/<yyyy-mm-dd>/<first offset>.jsonl[.gz|.zst]`, dated by
+/// message timestamp. Files are written with a `.partial` suffix which is removed once they
+/// are completed by rotation, a change of date or the end of the run. Existing files are never
+/// overwritten, the first offset is followed by `-1`, `-2`, ... instead.
+pub struct Files {
+    dir: PathBuf,
+    compression: Compression,
+    rotation: Rotation,
+    open: HashMap<(String, i64, String), OpenFile>,
+}
+
+impl Files {
+    pub fn new(dir: &Path, compression: Compression, rotation: Rotation) -> Files {
+        Files {
+            dir: dir.to_path_buf(),
+            compression,
+            rotation,
+            open: HashMap::new(),
+        }
+    }
+
+    fn open(&self, msg: &Msg, date: &str) -> Result<OpenFile> {
+        let dir = self
+            .dir
+            .join(sanitize(&msg.topic))
+            .join(msg.partition.to_string())
+            .join(date);
+        std::fs::create_dir_all(&dir)?;
+        // offsets repeat when a topic is read again, or are all -1 for sources without them
+        let mut n = 0;
+        let (path, file) = loop {
+            let name = match n {
+                0 => format!("{:020}", msg.offset.max(0)),
+                n => format!("{:020}-{}", msg.offset.max(0), n),
+            };
+            let path = dir.join(format!("{}.{}.partial", name, self.compression.extension()));
+            n += 1;
+            if path.with_extension("").exists() {
+                continue;
+            }
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break (path, file),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        debug!(path = path.display().to_string(), "opening");
+        Ok(OpenFile {
+            writer: Writer::new(file, self.compression)?,
+            path,
+            opened: Instant::now(),
+            bytes: 0,
+            offsets: FileOffsets {
+                topic: msg.topic.clone(),
+                partition: msg.partition,
+                first_offset: msg.offset,
+                last_offset: msg.offset,
+                records: 0,
+            },
+        })
+    }
+
+    fn rotate(&self, file: &OpenFile) -> bool {
+        self.rotation.max_bytes.is_some_and(|max| file.bytes >= max)
+            || self
+                .rotation
+                .max_age
+                .is_some_and(|max| file.opened.elapsed() >= max)
+    }
+}
+
+impl Sink for Files {
+    fn write(&mut self, msg: &Msg, lines: Vec<String>) -> Result<()> {
+        let date = date(msg.ts);
+        // files of a partition are completed when its messages move on to the next day
+        let stale = self
+            .open
+            .keys()
+            .filter(|(t, p, d)| *t == msg.topic && *p == msg.partition && *d != date)
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in stale {
+            if let Some(file) = self.open.remove(&key) {
+                file.complete()?;
+            }
+        }
+        let key = (msg.topic.clone(), msg.partition, date);
+        if let Some(file) = self.open.get(&key) {
+            if self.rotate(file) {
+                if let Some(file) = self.open.remove(&key) {
+                    file.complete()?;
+                }
+            }
+        }
+        if !self.open.contains_key(&key) {
+            let file = self.open(msg, &key.2)?;
+            self.open.insert(key.clone(), file);
+        }
+        let file = self.open.get_mut(&key).ok_or(Error::Eof)?;
+        for line in lines {
+            let w = file.writer.inner();
+            w.write_all(line.as_bytes())?;
+            w.write_all(b"\n")?;
+            file.bytes += line.len() as u64 + 1;
+        }
+        file.offsets.last_offset = msg.offset;
+        file.offsets.records += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        for file in self.open.values_mut() {
+            file.writer.inner().flush()?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        for (_, file) in self.open.drain() {
+            file.complete()?;
+        }
+        Ok(())
+    }
+}
+
+/// Output topic of every input topic, `<topic><suffix>` unless mapped explicitly
+#[derive(Debug, Clone, Default)]
+pub struct TopicMap {
+    map: HashMap<String, String>,
+    suffix: String,
+}
+
+impl TopicMap {
+    pub fn new(mappings: &[(String, String)], suffix: &str) -> TopicMap {
+        TopicMap {
+            map: mappings.iter().cloned().collect(),
+            suffix: suffix.to_string(),
+        }
+    }
+
+    pub fn get(&self, topic: &str) -> String {
+        match self.map.get(topic) {
+            Some(to) => to.clone(),
+            None => format!("{}{}", topic, self.suffix),
+        }
+    }
+}
+
+/// Produces every output line as a record to the mapped topic, with the key, headers and
+/// partition of the input message. Output topics need at least as many partitions as their
+/// inputs.
+///
+/// With a consumer group, records are produced in transactions which also commit the group's
+/// offsets, so every input message is output exactly once by a group that is restarted after a
+/// failure. Transactions are committed every `transaction_size` messages and whenever the
+/// consumer is idle.
+pub struct Kafka {
+    producer: kafka::Producer,
+    topics: TopicMap,
+    group: Option<GroupConsumer>,
+    transaction_size: usize,
+    /// offsets of the first and after the last message of each partition in the transaction
+    first: BTreeMap<(String, i32), i64>,
+    offsets: BTreeMap<(String, i32), i64>,
+    pending: usize,
+}
+
+impl Kafka {
+    pub fn new(producer: kafka::Producer, topics: TopicMap) -> Kafka {
+        Kafka {
+            producer,
+            topics,
+            group: None,
+            transaction_size: 0,
+            first: BTreeMap::new(),
+            offsets: BTreeMap::new(),
+            pending: 0,
+        }
+    }
+
+    /// `producer` has to be transactional
+    pub fn transactional(
+        producer: kafka::Producer,
+        topics: TopicMap,
+        group: GroupConsumer,
+        transaction_size: usize,
+    ) -> Kafka {
+        Kafka {
+            group: Some(group),
+            transaction_size: transaction_size.max(1),
+            ..Kafka::new(producer, topics)
+        }
+    }
+}
+
+impl Sink for Kafka {
+    fn write(&mut self, msg: &Msg, lines: Vec<String>) -> Result<()> {
+        let topic = self.topics.get(&msg.topic);
+        let partition = i32::try_from(msg.partition).ok().filter(|p| *p >= 0);
+        for line in lines {
+            self.producer
+                .send(&topic, partition, &msg.key, &msg.headers, line.as_bytes())?;
+        }
+        if self.group.is_some() {
+            let partition = i32::try_from(msg.partition)?;
+            self.first
+                .entry((msg.topic.clone(), partition))
+                .or_insert(msg.offset);
+            self.offsets
+                .insert((msg.topic.clone(), partition), msg.offset + 1);
+            self.pending += 1;
+            if self.pending >= self.transaction_size {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match &self.group {
+            Some(group) if self.pending > 0 => {
+                // an aborted transaction's records are consumed and written again
+                let committed = self.producer.commit(&self.first, &self.offsets, group);
+                self.first.clear();
+                self.offsets.clear();
+                let pending = std::mem::take(&mut self.pending);
+                committed?;
+                debug!(messages = pending, "committed");
+                Ok(())
+            }
+            Some(_) => Ok(()),
+            None => self.producer.flush(),
+        }
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
+/// Commits the offsets of a consumer group once the records written to `sink` are flushed,
+/// every `interval` messages and whenever the consumer is idle. Messages are output at least
+/// once by a group that is restarted after a failure.
+pub struct GroupCommit {
+    sink: Box<dyn Sink>,
+    group: GroupConsumer,
+    interval: usize,
+    offsets: BTreeMap<(String, i32), i64>,
+    pending: usize,
+}
+
+impl GroupCommit {
+    pub fn new(sink: Box<dyn Sink>, group: GroupConsumer, interval: usize) -> GroupCommit {
+        GroupCommit {
+            sink,
+            group,
+            interval: interval.max(1),
+            offsets: BTreeMap::new(),
+            pending: 0,
+        }
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if self.pending > 0 {
+            kafka::commit_offsets(&self.group, &self.offsets)?;
+            debug!(messages = self.pending, "committed");
+            self.offsets.clear();
+            self.pending = 0;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for GroupCommit {
+    fn write(&mut self, msg: &Msg, lines: Vec<String>) -> Result<()> {
+        self.sink.write(msg, lines)?;
+        let partition = i32::try_from(msg.partition)?;
+        self.offsets
+            .insert((msg.topic.clone(), partition), msg.offset + 1);
+        self.pending += 1;
+        if self.pending >= self.interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.sink.flush()?;
+        self.commit()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.sink.close()?;
+        self.commit()
+    }
+}
+
+/// `sink` together with the dead letters of the records that failed to decode. Dead letters are
+/// flushed before anything following them is written to `sink`, so that neither its commits
+/// nor checkpoints get past a dead letter that could still be lost. They are dropped without a
+/// dead-letter sink.
+pub struct WithDeadLetters {
+    sink: Box<dyn Sink>,
+    dead_letters: Option<Box<dyn Sink>>,
+    unflushed: bool,
+}
+
+impl WithDeadLetters {
+    pub fn new(sink: Box<dyn Sink>, dead_letters: Option<Box<dyn Sink>>) -> WithDeadLetters {
+        WithDeadLetters {
+            sink,
+            dead_letters,
+            unflushed: false,
+        }
+    }
+
+    /// `letter` is the dead letter of `msg`
+    pub fn write_dead_letter(&mut self, msg: &Msg, letter: String) -> Result<()> {
+        if let Some(dead_letters) = &mut self.dead_letters {
+            dead_letters.write(msg, vec![letter])?;
+            self.unflushed = true;
+        }
+        Ok(())
+    }
+
+    fn flush_dead_letters(&mut self) -> Result<()> {
+        if let (Some(dead_letters), true) = (&mut self.dead_letters, self.unflushed) {
+            dead_letters.flush()?;
+            self.unflushed = false;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for WithDeadLetters {
+    fn write(&mut self, msg: &Msg, lines: Vec<String>) -> Result<()> {
+        self.flush_dead_letters()?;
+        self.sink.write(msg, lines)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.flush_dead_letters()?;
+        self.sink.flush()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        if let Some(dead_letters) = &mut self.dead_letters {
+            dead_letters.close()?;
+        }
+        self.sink.close()
+    }
+}
+
+/// Topic names can't contain path separators, but a topic name given by the user could
+fn sanitize(topic: &str) -> String {
+    match topic {
+        "" => "_".to_string(),
+        t => t.replace(['/', '\\'], "_"),
+    }
+}
+
+/// UTC date of a timestamp in milliseconds, today for messages without one
+fn date(ts: i64) -> String {
+    let ts = if ts < 0 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default()
+    } else {
+        ts
+    };
+    // days to civil date, from Howard Hinnant's date algorithms
+    let z = ts.div_euclid(86_400_000) + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::error::Result;
+    use crate::parse::kafka;
+    use crate::parse::msg::{Msg, ParsedKey};
+    use crate::parse::sink::{
+        date, Compression, FileOffsets, Files, GroupCommit, Rotation, Sink, TopicMap,
+        WithDeadLetters,
+    };
+    use rdkafka::consumer::{BaseConsumer, Consumer};
+    use rdkafka::{Offset, TopicPartitionList};
+    use std::cell::RefCell;
+    use std::io::Read;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn msg(offset: i64, ts: i64) -> Msg {
+        Msg {
+            topic: "orders".to_string(),
+            partition: 1,
+            offset,
+            ts,
+            key: ParsedKey::None,
+            key_len: 0,
+            msg: vec![],
+            msg_len: 0,
+            headers: vec![],
+        }
+    }
+
+    #[test]
+    fn test_topic_map() {
+        let topics = TopicMap::new(&[("orders".to_string(), "orders-v1".to_string())], ".json");
+        assert_eq!(topics.get("orders"), "orders-v1");
+        assert_eq!(topics.get("payments"), "payments.json");
+    }
+
+    #[test]
+    fn test_date() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(1683000000000), "2023-05-02");
+        assert_eq!(date(951782400000), "2000-02-29");
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = std::env::temp_dir().join(format!("sink-{}", std::process::id()));
+        let rotation = Rotation {
+            max_bytes: Some(10),
+            max_age: None,
+        };
+        let mut sink = Files::new(&dir, Compression::Gzip, rotation);
+        let day = 1683000000000;
+        sink.write(&msg(5, day), vec!["{\"a\":1}".to_string()]).unwrap();
+        sink.write(&msg(6, day), vec!["{\"a\":2}".to_string()]).unwrap();
+        sink.write(&msg(7, day), vec!["{\"a\":3}".to_string()]).unwrap();
+        sink.write(&msg(8, day + 86_400_000), vec!["{}".to_string()]).unwrap();
+        sink.close().unwrap();
+
+        let partition = dir.join("orders").join("1");
+        let first = partition
+            .join("2023-05-02")
+            .join("00000000000000000005.jsonl.gz");
+        let mut content = String::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(&first).unwrap())
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "{\"a\":1}\n{\"a\":2}\n");
+        let offsets: FileOffsets = serde_json::from_slice(
+            &std::fs::read(first.with_extension("gz.offsets.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!((offsets.first_offset, offsets.last_offset), (5, 6));
+        assert!(partition
+            .join("2023-05-02")
+            .join("00000000000000000007.jsonl.gz")
+            .exists());
+        assert!(partition
+            .join("2023-05-03")
+            .join("00000000000000000008.jsonl.gz")
+            .exists());
+
+        // reading the topic again leaves the first run's files alone
+        let mut sink = Files::new(&dir, Compression::Gzip, Rotation::default());
+        sink.write(&msg(5, day), vec!["{}".to_string()]).unwrap();
+        sink.close().unwrap();
+        let again = partition
+            .join("2023-05-02")
+            .join("00000000000000000005-1.jsonl.gz");
+        assert!(again.exists());
+        let offsets: FileOffsets = serde_json::from_slice(
+            &std::fs::read(first.with_extension("gz.offsets.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(offsets.last_offset, 6);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Records what is done to it in a log shared with other sinks
+    #[derive(Default)]
+    struct Log {
+        name: &'static str,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Sink for Log {
+        fn write(&mut self, _msg: &Msg, lines: Vec<String>) -> Result<()> {
+            let mut log = self.log.borrow_mut();
+            log.extend(lines.iter().map(|l| format!("{} {}", self.name, l)));
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.log.borrow_mut().push(format!("{} flush", self.name));
+            Ok(())
+        }
+
+        fn close(&mut self) -> Result<()> {
+            self.log.borrow_mut().push(format!("{} close", self.name));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let (_mock, servers) = kafka::test::mock_cluster();
+        let producer = kafka::Producer::new(&servers).unwrap();
+        producer
+            .send("orders", Some(1), &ParsedKey::None, &[], b"{}")
+            .unwrap();
+        producer.flush().unwrap();
+        let consumer: BaseConsumer<_> = kafka::client_config(&servers)
+            .create_with_context(kafka::LagContext::default())
+            .unwrap();
+        let group = Arc::new(consumer);
+        let committed = || {
+            let mut tpl = TopicPartitionList::new();
+            tpl.add_partition("orders", 1);
+            let tpl = group
+                .committed_offsets(tpl, Duration::from_secs(5))
+                .unwrap();
+            tpl.find_partition("orders", 1).unwrap().offset()
+        };
+
+        let mut sink = GroupCommit::new(Box::<Log>::default(), group.clone(), 2);
+        sink.write(&msg(5, 0), vec!["{}".to_string()]).unwrap();
+        assert_eq!(committed(), Offset::Invalid);
+        sink.write(&msg(6, 0), vec!["{}".to_string()]).unwrap();
+        assert_eq!(committed(), Offset::Offset(7));
+        sink.write(&msg(7, 0), vec!["{}".to_string()]).unwrap();
+        sink.close().unwrap();
+        assert_eq!(committed(), Offset::Offset(8));
+    }
+
+    #[test]
+    fn test_dead_letters_flushed_first() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let sink = |name| {
+            Box::new(Log {
+                name,
+                log: log.clone(),
+            })
+        };
+        let mut sink = WithDeadLetters::new(sink("out"), Some(sink("dlq")));
+        sink.write(&msg(5, 0), vec!["a".to_string()]).unwrap();
+        sink.write_dead_letter(&msg(6, 0), "b".to_string()).unwrap();
+        sink.write(&msg(7, 0), vec!["c".to_string()]).unwrap();
+        sink.write(&msg(8, 0), vec!["d".to_string()]).unwrap();
+        sink.close().unwrap();
+        assert_eq!(
+            *log.borrow(),
+            [
+                "out a",
+                "dlq b",
+                "dlq flush",
+                "out c",
+                "out d",
+                "dlq close",
+                "out close"
+            ]
+        );
+    }
+}
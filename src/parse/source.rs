@@ -0,0 +1,661 @@
+use crate::parse::confluent::{latest_schema, parse_schemas, Schema};
+use crate::parse::error::*;
+use crate::parse::protobuf::ProtobufError;
+use protobuf::descriptor::FileDescriptorSet;
+use protobuf::reflect::FileDescriptor;
+use protobuf::Message;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Ids the registry didn't know aren't asked for again before this long
+const MISS_TTL: Duration = Duration::from_secs(60);
+
+/// Ids the registry failed to answer for aren't asked for again before this long
+const FAILURE_TTL: Duration = Duration::from_secs(5);
+
+/// Where schemas come from. Lookups answer None for schemas the source doesn't know and fail
+/// when the source couldn't be asked.
+pub trait SchemaSource: Send + Sync {
+    fn by_id(&self, id: i32) -> Result<Option<Schema>>;
+
+    /// Latest version of `subject` if `version` is None
+    fn by_subject(&self, subject: &str, version: Option<usize>) -> Result<Option<Schema>>;
+
+    /// Compiled schema, for sources holding descriptors rather than `.proto` text
+    fn descriptor(&self, _id: i32) -> Result<Option<FileDescriptor>> {
+        Ok(None)
+    }
+}
+
+/// Schemas known up front, such as the JSON of the registry's `/schemas` endpoint read with
+/// `read_schema_file` or a snapshot read with `read_snapshot_dir`
+impl SchemaSource for Vec<Schema> {
+    fn by_id(&self, id: i32) -> Result<Option<Schema>> {
+        Ok(self.iter().find(|s| s.id == id).cloned())
+    }
+
+    fn by_subject(&self, subject: &str, version: Option<usize>) -> Result<Option<Schema>> {
+        Ok(match version {
+            Some(version) => self
+                .iter()
+                .find(|s| s.subject == subject && s.version == version),
+            None => latest_schema(self, subject),
+        }
+        .cloned())
+    }
+}
+
+/// Schemas in the JSON format of the registry's `/schemas` endpoint
+pub fn read_schema_file(path: &Path) -> Result<Vec<Schema>> {
+    std::fs::read(path)
+        .map_err(Error::from)
+        .and_then(|data| parse_schemas(&data))
+        .with_context(|| format!("reading schemas from {}", path.display()))
+}
+
+/// Schemas of every `.json` file below `dir`, each holding a schema as served by the registry's
+/// `/subjects/{subject}/versions/{version}` endpoint or a list of them as served by `/schemas`
+pub fn read_snapshot_dir(dir: &Path) -> Result<Vec<Schema>> {
+    let mut schemas = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in
+            std::fs::read_dir(&dir).with_context(|| format!("reading {}", dir.display()))?
+        {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|e| e == "json") {
+                let data =
+                    std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+                match serde_json::from_slice::<Schema>(&data) {
+                    Ok(schema) => schemas.push(schema),
+                    Err(_) => schemas.extend(
+                        parse_schemas(&data)
+                            .with_context(|| format!("reading {}", path.display()))?,
+                    ),
+                }
+            }
+        }
+    }
+    info!(
+        dir = dir.display().to_string(),
+        schemas = schemas.len(),
+        "read snapshot"
+    );
+    Ok(schemas)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SubjectVersion {
+    subject: String,
+    version: usize,
+}
+
+/// Schemas fetched from a registry, the ids it didn't know and the ones it failed to answer for
+#[derive(Default)]
+struct RegistryCache {
+    schemas: Mutex<Vec<Schema>>,
+    misses: Mutex<HashMap<i32, Instant>>,
+    failures: Mutex<HashMap<i32, (Instant, String)>>,
+}
+
+impl RegistryCache {
+    fn find(&self, f: impl Fn(&Schema) -> bool) -> Option<Schema> {
+        self.schemas.lock().unwrap().iter().find(|s| f(s)).cloned()
+    }
+
+    fn insert(&self, schema: &Schema) {
+        let mut schemas = self.schemas.lock().unwrap();
+        if !schemas
+            .iter()
+            .any(|s| s.id == schema.id && s.subject == schema.subject)
+        {
+            schemas.push(schema.clone());
+        }
+    }
+
+    fn recently_missed(&self, id: i32) -> bool {
+        matches!(self.misses.lock().unwrap().get(&id), Some(missed) if missed.elapsed() < MISS_TTL)
+    }
+
+    fn miss(&self, id: i32) {
+        self.misses.lock().unwrap().insert(id, Instant::now());
+    }
+
+    fn recent_failure(&self, id: i32) -> Option<String> {
+        match self.failures.lock().unwrap().get(&id) {
+            Some((failed, e)) if failed.elapsed() < FAILURE_TTL => Some(e.clone()),
+            _ => None,
+        }
+    }
+
+    fn fail(&self, id: i32, e: &Error) {
+        let failure = (Instant::now(), e.to_string());
+        self.failures.lock().unwrap().insert(id, failure);
+    }
+}
+
+/// `url` of the registry, or of its `/schemas` endpoint, without trailing slash
+fn registry_url(url: &str) -> String {
+    let url = url.trim_end_matches('/');
+    url.strip_suffix("/schemas").unwrap_or(url).to_string()
+}
+
+fn subject_url(url: &str, subject: &str, version: Option<usize>) -> String {
+    let version = version.map_or("latest".to_string(), |v| v.to_string());
+    format!("{}/subjects/{}/versions/{}", url, subject, version)
+}
+
+/// Confluent schema registry. Schemas are cached once fetched, ids it doesn't know are asked for
+/// again after a minute and ids it failed to answer for after a few seconds.
+pub struct Registry {
+    url: String,
+    client: reqwest::blocking::Client,
+    cache: RegistryCache,
+}
+
+impl Registry {
+    /// `url` of the registry, or of its `/schemas` endpoint
+    pub fn new(url: &str) -> Registry {
+        Registry {
+            url: registry_url(url),
+            client: reqwest::blocking::Client::new(),
+            cache: RegistryCache::default(),
+        }
+    }
+
+    /// Fetches every schema at once, rather than one at a time when first looked up
+    pub fn preload(&self) -> Result<usize> {
+        let schemas = parse_schemas(
+            &self
+                .get(&format!("{}/schemas", self.url))?
+                .unwrap_or_default(),
+        )?;
+        let n = schemas.len();
+        *self.cache.schemas.lock().unwrap() = schemas;
+        info!(url = self.url, schemas = n, "preloaded schemas");
+        Ok(n)
+    }
+
+    /// None if the registry answered 404
+    fn get(&self, url: &str) -> Result<Option<Vec<u8>>> {
+        debug!(url = url, "fetching");
+        let fetch = || -> Result<Option<Vec<u8>>> {
+            let resp = self.client.get(url).send()?;
+            if resp.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            Ok(Some(resp.error_for_status()?.bytes()?.to_vec()))
+        };
+        fetch().with_context(|| format!("fetching {}", url))
+    }
+
+    fn fetch_subject(&self, subject: &str, version: Option<usize>) -> Result<Option<Schema>> {
+        let schema = match self.get(&subject_url(&self.url, subject, version))? {
+            Some(data) => serde_json::from_slice::<Schema>(&data)?,
+            None => return Ok(None),
+        };
+        self.cache.insert(&schema);
+        Ok(Some(schema))
+    }
+}
+
+impl SchemaSource for Registry {
+    fn by_id(&self, id: i32) -> Result<Option<Schema>> {
+        if let Some(schema) = self.cache.find(|s| s.id == id) {
+            return Ok(Some(schema));
+        }
+        if self.cache.recently_missed(id) {
+            return Ok(None);
+        }
+        if let Some(e) = self.cache.recent_failure(id) {
+            return Err(Error::RegistryFailed(e));
+        }
+        let fetch = || -> Result<Option<Schema>> {
+            let url = format!("{}/schemas/ids/{}/versions", self.url, id);
+            let versions: Vec<SubjectVersion> = match self.get(&url)? {
+                Some(data) => serde_json::from_slice(&data)?,
+                None => vec![],
+            };
+            for v in versions {
+                if let Some(schema) = self.fetch_subject(&v.subject, Some(v.version))? {
+                    return Ok(Some(schema));
+                }
+            }
+            Ok(None)
+        };
+        let result = fetch();
+        match &result {
+            Ok(None) => self.cache.miss(id),
+            Err(e) => self.cache.fail(id, e),
+            Ok(Some(_)) => {}
+        }
+        result
+    }
+
+    fn by_subject(&self, subject: &str, version: Option<usize>) -> Result<Option<Schema>> {
+        if let Some(version) = version {
+            if let Some(schema) = self
+                .cache
+                .find(|s| s.subject == subject && s.version == version)
+            {
+                return Ok(Some(schema));
+            }
+        }
+        self.fetch_subject(subject, version)
+    }
+}
+
+/// Confluent schema registry for async code, whose lookups never block: they only answer with
+/// schemas fetched by `prefetch` beforehand. `DecodeStream` prefetches the schemas of every
+/// message before decoding it.
+pub struct AsyncRegistry {
+    url: String,
+    client: reqwest::Client,
+    cache: RegistryCache,
+}
+
+impl AsyncRegistry {
+    /// `url` of the registry, or of its `/schemas` endpoint
+    pub fn new(url: &str) -> AsyncRegistry {
+        AsyncRegistry {
+            url: registry_url(url),
+            client: reqwest::Client::new(),
+            cache: RegistryCache::default(),
+        }
+    }
+
+    /// None if the registry answered 404
+    async fn get(&self, url: &str) -> Result<Option<Vec<u8>>> {
+        debug!(url = url, "fetching");
+        let fetch = async {
+            let resp = self.client.get(url).send().await?;
+            if resp.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            Ok::<_, Error>(Some(resp.error_for_status()?.bytes().await?.to_vec()))
+        };
+        fetch.await.with_context(|| format!("fetching {}", url))
+    }
+
+    async fn fetch_subject(&self, subject: &str, version: usize) -> Result<Option<Schema>> {
+        let schema = match self
+            .get(&subject_url(&self.url, subject, Some(version)))
+            .await?
+        {
+            Some(data) => serde_json::from_slice::<Schema>(&data)?,
+            None => return Ok(None),
+        };
+        self.cache.insert(&schema);
+        Ok(Some(schema))
+    }
+
+    /// Fetches schema `id` and the schemas it references, unless they were fetched already
+    pub async fn prefetch(&self, id: i32) -> Result<()> {
+        if self.cache.find(|s| s.id == id).is_some() || self.cache.recently_missed(id) {
+            return Ok(());
+        }
+        let url = format!("{}/schemas/ids/{}/versions", self.url, id);
+        let versions: Vec<SubjectVersion> = match self.get(&url).await? {
+            Some(data) => serde_json::from_slice(&data)?,
+            None => vec![],
+        };
+        let mut schema = None;
+        for v in versions {
+            schema = self.fetch_subject(&v.subject, v.version).await?;
+            if schema.is_some() {
+                break;
+            }
+        }
+        let schema = match schema {
+            Some(schema) => schema,
+            None => {
+                self.cache.miss(id);
+                return Ok(());
+            }
+        };
+        let mut pending = schema.references.unwrap_or_default();
+        while let Some(r) = pending.pop() {
+            if self
+                .cache
+                .find(|s| s.subject == r.subject && s.version == r.version)
+                .is_some()
+            {
+                continue;
+            }
+            if let Some(referenced) = self.fetch_subject(&r.subject, r.version).await? {
+                pending.extend(referenced.references.unwrap_or_default());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SchemaSource for AsyncRegistry {
+    fn by_id(&self, id: i32) -> Result<Option<Schema>> {
+        Ok(self.cache.find(|s| s.id == id))
+    }
+
+    fn by_subject(&self, subject: &str, version: Option<usize>) -> Result<Option<Schema>> {
+        let schemas = self.cache.schemas.lock().unwrap();
+        Ok(match version {
+            Some(version) => schemas
+                .iter()
+                .find(|s| s.subject == subject && s.version == version),
+            None => latest_schema(&schemas, subject),
+        }
+        .cloned())
+    }
+}
+
+/// Compiled schemas of a `FileDescriptorSet`, as written by `protoc --include_imports
+/// --descriptor_set_out`. Schema ids are given to its files, which are subjects of version 1.
+pub struct DescriptorSet {
+    files: Vec<FileDescriptor>,
+    ids: Vec<(i32, String)>,
+}
+
+impl DescriptorSet {
+    /// `ids` give schema ids to the names of files in the set
+    pub fn load(path: &Path, ids: &[(i32, String)]) -> Result<DescriptorSet> {
+        let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        let set = FileDescriptorSet::parse_from_bytes(&data)
+            .with_context(|| format!("reading descriptor set {}", path.display()))?;
+        let mut pending = set.file;
+        let mut files: Vec<FileDescriptor> = vec![];
+        // imports are built first, whatever the order of the set
+        while !pending.is_empty() {
+            let ready = pending.iter().position(|fdp| {
+                fdp.dependency
+                    .iter()
+                    .all(|d| files.iter().any(|f| f.name() == d))
+            });
+            let fdp = match ready {
+                Some(i) => pending.remove(i),
+                None => {
+                    let missing = pending[0]
+                        .dependency
+                        .iter()
+                        .find(|d| !files.iter().any(|f| f.name() == d.as_str()))
+                        .cloned()
+                        .unwrap_or_default();
+                    return Err(ProtobufError::MissingImport(missing).into());
+                }
+            };
+            files.push(FileDescriptor::new_dynamic(fdp, &files)?);
+        }
+        for (id, name) in ids {
+            if !files.iter().any(|f| f.name() == name) {
+                return Err(
+                    ProtobufError::MissingImport(format!("{} (schema {})", name, id)).into(),
+                );
+            }
+        }
+        Ok(DescriptorSet {
+            files,
+            ids: ids.to_vec(),
+        })
+    }
+
+    fn schema(id: i32, name: &str) -> Schema {
+        Schema {
+            id,
+            version: 1,
+            schema_type: "PROTOBUF".to_string(),
+            subject: name.to_string(),
+            references: None,
+            schema: String::new(),
+        }
+    }
+}
+
+impl SchemaSource for DescriptorSet {
+    fn by_id(&self, id: i32) -> Result<Option<Schema>> {
+        Ok(self
+            .ids
+            .iter()
+            .find(|(i, _)| *i == id)
+            .map(|(id, name)| DescriptorSet::schema(*id, name)))
+    }
+
+    fn by_subject(&self, subject: &str, version: Option<usize>) -> Result<Option<Schema>> {
+        if version.is_some_and(|v| v != 1) {
+            return Ok(None);
+        }
+        Ok(self
+            .ids
+            .iter()
+            .find(|(_, name)| name == subject)
+            .map(|(id, name)| DescriptorSet::schema(*id, name)))
+    }
+
+    fn descriptor(&self, id: i32) -> Result<Option<FileDescriptor>> {
+        Ok(self
+            .ids
+            .iter()
+            .find(|(i, _)| *i == id)
+            .and_then(|(_, name)| self.files.iter().find(|f| f.name() == name))
+            .cloned())
+    }
+}
+
+/// Asks its sources in order and answers with the first one that knows the schema. Sources
+/// that fail are skipped, their error is only returned if no other source knows the schema.
+pub struct Chain {
+    sources: Vec<Box<dyn SchemaSource>>,
+}
+
+impl Chain {
+    pub fn new(sources: Vec<Box<dyn SchemaSource>>) -> Chain {
+        Chain { sources }
+    }
+
+    fn first<T>(&self, f: impl Fn(&dyn SchemaSource) -> Result<Option<T>>) -> Result<Option<T>> {
+        let mut error = None;
+        for source in &self.sources {
+            match f(source.as_ref()) {
+                Ok(Some(found)) => return Ok(Some(found)),
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("schema source failed: {:?}", e);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+}
+
+impl SchemaSource for Chain {
+    fn by_id(&self, id: i32) -> Result<Option<Schema>> {
+        self.first(|s| s.by_id(id))
+    }
+
+    fn by_subject(&self, subject: &str, version: Option<usize>) -> Result<Option<Schema>> {
+        self.first(|s| s.by_subject(subject, version))
+    }
+
+    fn descriptor(&self, id: i32) -> Result<Option<FileDescriptor>> {
+        self.first(|s| s.descriptor(id))
+    }
+}
+
+/// Schemas referenced by `schema` and, recursively, by them, with the names they are imported by
+pub fn resolve_references(
+    source: &dyn SchemaSource,
+    schema: &Schema,
+) -> Result<Vec<(String, Schema)>> {
+    let mut out: Vec<(String, Schema)> = vec![];
+    let mut pending = schema.references.clone().unwrap_or_default();
+    while let Some(r) = pending.pop() {
+        if out.iter().any(|(name, _)| *name == r.name) {
+            continue;
+        }
+        let referenced = source
+            .by_subject(&r.subject, Some(r.version))?
+            .ok_or_else(|| ProtobufError::ReferenceNotFound(r.subject.clone(), r.version))?;
+        pending.extend(referenced.references.clone().unwrap_or_default());
+        out.push((r.name, referenced));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::confluent::{Reference, Schema};
+    use crate::parse::error::Error;
+    use crate::parse::source::{
+        resolve_references, AsyncRegistry, Chain, DescriptorSet, Registry, SchemaSource,
+    };
+    use protobuf::descriptor::FileDescriptorSet;
+    use protobuf::Message;
+    use warp::Filter;
+
+    fn schema(id: i32, subject: &str, version: usize, references: Vec<Reference>) -> Schema {
+        Schema {
+            id,
+            version,
+            schema_type: "PROTOBUF".to_string(),
+            subject: subject.to_string(),
+            references: Some(references),
+            schema: String::new(),
+        }
+    }
+
+    fn reference(name: &str, subject: &str, version: usize) -> Reference {
+        Reference {
+            name: name.to_string(),
+            subject: subject.to_string(),
+            version,
+        }
+    }
+
+    #[test]
+    fn test_chain_and_references() {
+        let common = vec![
+            schema(1, "common", 1, vec![]),
+            schema(2, "common", 2, vec![]),
+            schema(3, "money", 1, vec![reference("common.proto", "common", 1)]),
+        ];
+        let orders = vec![schema(
+            10,
+            "orders-value",
+            1,
+            vec![
+                reference("money.proto", "money", 1),
+                reference("common.proto", "common", 1),
+            ],
+        )];
+        let chain = Chain::new(vec![Box::new(orders), Box::new(common)]);
+        assert_eq!(chain.by_id(2).unwrap().unwrap().version, 2);
+        assert_eq!(chain.by_subject("common", None).unwrap().unwrap().id, 2);
+        assert!(chain.by_id(4).unwrap().is_none());
+
+        let orders = chain.by_id(10).unwrap().unwrap();
+        let mut resolved = resolve_references(&chain, &orders)
+            .unwrap()
+            .into_iter()
+            .map(|(name, s)| (name, s.id))
+            .collect::<Vec<_>>();
+        resolved.sort();
+        assert_eq!(
+            resolved,
+            vec![
+                ("common.proto".to_string(), 1),
+                ("money.proto".to_string(), 3)
+            ]
+        );
+
+        let missing = schema(11, "x", 1, vec![reference("y.proto", "y", 1)]);
+        assert!(resolve_references(&chain, &missing).is_err());
+    }
+
+    #[test]
+    fn test_descriptor_set() {
+        let dir = std::env::temp_dir().join(format!("descriptor-set-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("money.proto"),
+            "syntax = \"proto3\";\npackage acme;\nmessage Money { int64 cents = 1; }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("order.proto"),
+            "syntax = \"proto3\";\npackage acme;\nimport \"money.proto\";\nmessage Order { Money total = 1; }\n",
+        )
+        .unwrap();
+        let mut set = FileDescriptorSet::new();
+        set.file = protobuf_parse::Parser::new()
+            .pure()
+            .include(&dir)
+            .input(dir.join("order.proto"))
+            .parse_and_typecheck()
+            .unwrap()
+            .file_descriptors;
+        // imports last, they are built first anyway
+        set.file.reverse();
+        let path = dir.join("set.pb");
+        std::fs::write(&path, set.write_to_bytes().unwrap()).unwrap();
+
+        let source = DescriptorSet::load(&path, &[(5, "order.proto".to_string())]).unwrap();
+        assert_eq!(source.by_id(5).unwrap().unwrap().subject, "order.proto");
+        assert!(source.by_id(6).unwrap().is_none());
+        let fd = source.descriptor(5).unwrap().unwrap();
+        assert_eq!(fd.messages().next().unwrap().full_name(), "acme.Order");
+        assert!(DescriptorSet::load(&path, &[(5, "other.proto".to_string())]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_async_registry() {
+        let ids = warp::path!("schemas" / "ids" / i32 / "versions").map(|id| match id {
+            7 => warp::reply::json(&serde_json::json!([{"subject": "orders-value", "version": 1}])),
+            _ => warp::reply::json(&serde_json::json!([])),
+        });
+        let subjects = warp::path!("subjects" / String / "versions" / usize).map(
+            |subject: String, version| {
+                let s = match subject.as_str() {
+                    "orders-value" => schema(
+                        7,
+                        "orders-value",
+                        version,
+                        vec![reference("money.proto", "money", 2)],
+                    ),
+                    _ => schema(3, &subject, version, vec![]),
+                };
+                warp::reply::json(&s)
+            },
+        );
+        let (addr, server) = warp::serve(ids.or(subjects)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let registry = AsyncRegistry::new(&format!("http://{}/schemas/", addr));
+        assert!(registry.by_id(7).unwrap().is_none());
+        registry.prefetch(7).await.unwrap();
+        registry.prefetch(8).await.unwrap();
+        assert_eq!(registry.by_id(7).unwrap().unwrap().subject, "orders-value");
+        assert!(registry.by_id(8).unwrap().is_none());
+        assert_eq!(
+            registry.by_subject("money", None).unwrap().unwrap().version,
+            2
+        );
+        let orders = registry.by_id(7).unwrap().unwrap();
+        assert_eq!(resolve_references(&registry, &orders).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_registry_failure_cached() {
+        // nothing listens on the discard port
+        let registry = Registry::new("http://127.0.0.1:9");
+        assert!(!matches!(registry.by_id(7), Err(Error::RegistryFailed(_))));
+        assert!(matches!(registry.by_id(7), Err(Error::RegistryFailed(_))));
+        assert!(!matches!(registry.by_id(8), Err(Error::RegistryFailed(_))));
+    }
+}
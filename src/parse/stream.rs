@@ -0,0 +1,228 @@
+use crate::parse::confluent::peek_schema_id;
+use crate::parse::decoder::Decoder;
+use crate::parse::envelope::Envelope;
+use crate::parse::error::*;
+use crate::parse::kafka::{self, ConsumerError, KafkaOptions, Start};
+use crate::parse::msg::Msg;
+use crate::parse::source::AsyncRegistry;
+use futures::stream::FuturesOrdered;
+use futures::{Stream, StreamExt};
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone)]
+pub struct StreamOptions {
+    pub topics: Vec<String>,
+    /// all partitions if empty
+    pub partitions: Vec<i32>,
+    pub start: Start,
+    /// messages decoded at once on blocking threads, records are yielded in order anyway
+    pub concurrency: usize,
+    /// records decoded ahead of the stream's reader, the consumer waits when it is full
+    pub queue_size: usize,
+    pub kafka: KafkaOptions,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        StreamOptions {
+            topics: vec![],
+            partitions: vec![],
+            start: Start::Beginning,
+            concurrency: 4,
+            queue_size: 1024,
+            kafka: KafkaOptions::default(),
+        }
+    }
+}
+
+/// A consumed message and what it decoded to. Decoding errors are not errors of the stream,
+/// the message is there for whatever the reader does with the ones it can't decode.
+#[derive(Debug)]
+pub struct Record {
+    pub msg: Msg,
+    pub envelopes: Result<Vec<Envelope>>,
+}
+
+/// Decoded records of kafka topics, in offset order within each partition. The stream never
+/// ends unless consuming fails, which it yields as its last item. Dropping it stops the
+/// consumer.
+pub struct DecodeStream {
+    rx: mpsc::Receiver<Result<Record>>,
+    task: JoinHandle<()>,
+}
+
+impl Stream for DecodeStream {
+    type Item = Result<Record>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl Drop for DecodeStream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Consumes `options.topics` from `servers_csv` on the current tokio runtime. Schemas of the
+/// decoder's source are expected to be known, or prefetched from `registry` when given, which
+/// should then be that source or one of its sources.
+pub fn decode_kafka_stream(
+    servers_csv: &str,
+    decoder: Decoder,
+    registry: Option<Arc<AsyncRegistry>>,
+    options: StreamOptions,
+) -> DecodeStream {
+    let (tx, rx) = mpsc::channel(options.queue_size.max(1));
+    let servers = servers_csv.to_string();
+    let task = tokio::spawn(async move {
+        if let Err(e) = consume(servers, decoder, registry, options, tx.clone()).await {
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+    DecodeStream { rx, task }
+}
+
+fn join_error(e: tokio::task::JoinError) -> Error {
+    Error::IoError(e.into())
+}
+
+async fn consume(
+    servers: String,
+    decoder: Decoder,
+    registry: Option<Arc<AsyncRegistry>>,
+    options: StreamOptions,
+    tx: mpsc::Sender<Result<Record>>,
+) -> Result<()> {
+    let consumer: Arc<StreamConsumer> = Arc::new(kafka::client_config(&servers).create()?);
+    // metadata and watermarks are fetched with blocking calls
+    let assigned = {
+        let consumer = consumer.clone();
+        let options = options.clone();
+        tokio::task::spawn_blocking(move || {
+            let topics = options
+                .topics
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            let tpl = kafka::assignment(
+                consumer.as_ref(),
+                &topics,
+                &options.partitions,
+                options.start,
+                &options.kafka,
+            )?;
+            consumer.assign(&tpl)?;
+            Ok::<_, Error>(tpl.count())
+        })
+        .await
+        .map_err(join_error)??
+    };
+    info!(
+        topics = options.topics.join(","),
+        partitions = assigned,
+        "streaming from {:?}",
+        options.start
+    );
+    let concurrency = options.concurrency.max(1);
+    let mut decoding = FuturesOrdered::new();
+    let mut error_retries = options.kafka.max_error_retries;
+    let mut backoff = kafka::Backoff::new(&options.kafka);
+    loop {
+        tokio::select! {
+            _ = tx.closed() => return Ok(()),
+            Some(record) = decoding.next(), if !decoding.is_empty() => {
+                if tx.send(record).await.is_err() {
+                    return Ok(());
+                }
+            }
+            m = consumer.recv(), if decoding.len() < concurrency => match m {
+                Ok(m) => {
+                    error_retries = options.kafka.max_error_retries;
+                    backoff.reset();
+                    decoding.push_back(decode(kafka::parse(m), decoder.clone(), registry.clone()));
+                }
+                Err(e) if kafka::is_transient(&e) && error_retries > 0 => {
+                    error_retries -= 1;
+                    let delay = backoff.next_delay();
+                    warn!(
+                        retries = error_retries,
+                        delay_ms = delay.as_millis() as u64,
+                        "transient kafka error: {:?}",
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(ConsumerError::Fatal(e).into()),
+            },
+        }
+    }
+}
+
+/// Prefetches the schemas of `msg` and decodes it off the runtime's threads
+async fn decode(
+    msg: Msg,
+    mut decoder: Decoder,
+    registry: Option<Arc<AsyncRegistry>>,
+) -> Result<Record> {
+    if let Some(registry) = registry {
+        let key = msg.key.as_bytes().and_then(peek_schema_id);
+        for id in peek_schema_id(&msg.msg).into_iter().chain(key) {
+            if let Err(e) = registry.prefetch(id).await {
+                warn!(schema_id = id, "prefetching schema failed: {:?}", e);
+            }
+        }
+    }
+    tokio::task::spawn_blocking(move || {
+        let envelopes = decoder.decode_msg(&msg);
+        Record { msg, envelopes }
+    })
+    .await
+    .map_err(join_error)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::parse::confluent::Schema;
+    use crate::parse::decoder::Decoder;
+    use crate::parse::kafka::{self, Producer};
+    use crate::parse::msg::ParsedKey;
+    use crate::parse::stream::{decode_kafka_stream, StreamOptions};
+    use futures::StreamExt;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_decode_kafka_stream() {
+        let (_mock, servers) = kafka::test::mock_cluster();
+        let producer = Producer::new(&servers).unwrap();
+        for i in 0..5 {
+            let value = format!(r#"{{"n":{}}}"#, i);
+            producer
+                .send("orders", Some(0), &ParsedKey::None, &[], value.as_bytes())
+                .unwrap();
+        }
+        producer.flush().unwrap();
+
+        let decoder = Decoder::new(Arc::new(Vec::<Schema>::new()), &std::env::temp_dir(), &[]);
+        let options = StreamOptions {
+            topics: vec!["orders".to_string()],
+            concurrency: 2,
+            ..Default::default()
+        };
+        let stream = decode_kafka_stream(&servers, decoder, None, options);
+        let records = stream.take(5).collect::<Vec<_>>().await;
+        for (i, record) in records.into_iter().enumerate() {
+            let record = record.unwrap();
+            assert_eq!((record.msg.partition, record.msg.offset), (0, i as i64));
+            assert_eq!(record.envelopes.unwrap()[0].value, json!({"n": i}));
+        }
+    }
+}